@@ -0,0 +1,176 @@
+// Copyright (C) 2026 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! A module for collecting blame information in structured form.
+
+use std::ffi::OsStr;
+use std::ffi::OsString;
+use std::io;
+use std::io::ErrorKind;
+use std::iter::once;
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+use crate::blame_capture;
+use crate::BlameConfig;
+use crate::Error;
+use crate::File;
+use crate::Hunk;
+use crate::Result;
+
+
+/// A regular expression matching a line as emitted by `git blame -s`,
+/// e.g., `^1d2f3a4 12) content`. Between the commit and the line
+/// number, the file name and the original line number may be present
+/// as well, depending on the arguments provided.
+static BLAME_LINE_REGEX: Lazy<Regex> =
+  Lazy::new(|| Regex::new(r"^(\^?[0-9a-f]+)(?: .*?)?(?: +([0-9]+))? +([0-9]+)\) (.*)$").unwrap());
+
+
+/// A single line as annotated by `git blame -s`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlameLine {
+  /// The (possibly abbreviated) SHA-1 of the commit that last modified
+  /// the line, prefixed with `^` for boundary commits.
+  pub commit: String,
+  /// The number of the line in the annotated file.
+  pub line_number: usize,
+  /// The content of the line.
+  pub content: String,
+}
+
+
+/// A diff hunk along with the annotated lines of its source side.
+#[derive(Clone, Debug)]
+pub struct BlamedHunk {
+  /// The source side of the hunk.
+  pub src: File,
+  /// The destination side of the hunk.
+  pub dst: File,
+  /// The annotated lines.
+  pub lines: Vec<BlameLine>,
+}
+
+
+/// Parse the output of `git blame -s` into a list of [`BlameLine`]
+/// objects.
+fn parse(output: &str) -> Result<Vec<BlameLine>> {
+  output
+    .lines()
+    .map(|line| {
+      let captures = BLAME_LINE_REGEX.captures(line).ok_or_else(|| {
+        Error::Io(io::Error::new(
+          ErrorKind::InvalidData,
+          format!("encountered unexpected `git blame` output: {line}"),
+        ))
+      })?;
+      // The regular expression guarantees a valid number, modulo
+      // overflow.
+      let line_number = captures[3].parse().map_err(|err| {
+        Error::Io(io::Error::new(
+          ErrorKind::InvalidData,
+          format!("encountered invalid line number in `{line}`: {err}"),
+        ))
+      })?;
+
+      Ok(BlameLine {
+        commit: captures[1].to_string(),
+        line_number,
+        content: captures[4].to_string(),
+      })
+    })
+    .collect()
+}
+
+
+/// Annotate the source side of a single diff hunk.
+pub(crate) fn blame_hunk(
+  src: &File,
+  dst: &File,
+  args: &[OsString],
+  config: &BlameConfig,
+) -> Result<BlamedHunk> {
+  // `git blame` refuses to annotate an empty range of lines, as is
+  // the case for newly added files.
+  let lines = if src.count > 0 {
+    let args = once(OsStr::new("-s")).chain(args.iter().map(OsString::as_os_str));
+    let output = blame_capture(config, src, args)?;
+    parse(&String::from_utf8_lossy(&output))?
+  } else {
+    Vec::new()
+  };
+
+  Ok(BlamedHunk {
+    src: src.clone(),
+    dst: dst.clone(),
+    lines,
+  })
+}
+
+
+/// Annotate all the diff hunks, returning the annotated lines instead
+/// of printing them.
+///
+/// `args` are passed to `git blame` in addition to `-s`. They must not
+/// alter the output format in ways other than adding the file name or
+/// original line number.
+pub fn blame_collect(diffs: &[Hunk], args: &[OsString]) -> Result<Vec<BlamedHunk>> {
+  blame_collect_with_config(diffs, args, &BlameConfig::default())
+}
+
+
+/// Annotate all the diff hunks, as dictated by the provided
+/// configuration, returning the annotated lines instead of printing
+/// them.
+///
+/// Only the configuration pertaining to the invocation of `git blame`
+/// is honored, not that about how to format output.
+pub fn blame_collect_with_config(
+  diffs: &[Hunk],
+  args: &[OsString],
+  config: &BlameConfig,
+) -> Result<Vec<BlamedHunk>> {
+  let () = config.validate()?;
+  diffs
+    .iter()
+    .map(|Hunk { src, dst }| blame_hunk(src, dst, args, config))
+    .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+
+  /// Check that we can parse the output of `git blame -s`.
+  #[test]
+  fn parse_blame_lines() {
+    let output = "^90f2bf9  9) i\nf8edd900 10) j) x\n00000000 f 11 11) \n";
+    let lines = parse(output).unwrap();
+    assert_eq!(
+      lines,
+      vec![
+        BlameLine {
+          commit: "^90f2bf9".to_string(),
+          line_number: 9,
+          content: "i".to_string(),
+        },
+        BlameLine {
+          commit: "f8edd900".to_string(),
+          line_number: 10,
+          content: "j) x".to_string(),
+        },
+        BlameLine {
+          commit: "00000000".to_string(),
+          line_number: 11,
+          content: "".to_string(),
+        },
+      ]
+    );
+
+    let error = parse("foobar\n").unwrap_err();
+    assert!(error.to_string().contains("foobar"), "{error}");
+  }
+}
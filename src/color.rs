@@ -0,0 +1,151 @@
+// Copyright (C) 2026 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! A module for coloring blame annotations based on commit age.
+
+use std::io::Write as _;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use crate::is_null_commit;
+use crate::porcelain;
+use crate::Result;
+
+
+/// The number of hex digits of a commit's SHA-1 to display.
+const SHA1_DIGITS: usize = 8;
+
+/// The ANSI escape sequence for lines changed within the last week.
+const RED: &str = "\x1b[31m";
+/// The ANSI escape sequence for lines changed within the last month.
+const ORANGE: &str = "\x1b[38;5;208m";
+/// The ANSI escape sequence for lines changed within the last year.
+const YELLOW: &str = "\x1b[33m";
+/// The ANSI escape sequence for lines changed more than a year ago.
+const BLUE: &str = "\x1b[34m";
+/// The ANSI escape sequence resetting all attributes.
+const RESET: &str = "\x1b[0m";
+
+const DAY: u64 = 24 * 60 * 60;
+const WEEK: u64 = 7 * DAY;
+const MONTH: u64 = 30 * DAY;
+const YEAR: u64 = 365 * DAY;
+
+
+/// A type mapping the age of a commit to a color.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AgeColorizer {
+  /// The point in time ages are calculated relative to.
+  now: SystemTime,
+}
+
+impl AgeColorizer {
+  /// Create a new `AgeColorizer` calculating ages relative to the
+  /// current time.
+  #[inline]
+  pub fn new() -> Self {
+    Self::with_reference(SystemTime::now())
+  }
+
+  /// Create a new `AgeColorizer` calculating ages relative to `now`.
+  #[inline]
+  pub fn with_reference(now: SystemTime) -> Self {
+    Self { now }
+  }
+
+  /// Retrieve the ANSI escape sequence prefix to use for a commit
+  /// created at `time`.
+  ///
+  /// Commits from the future are treated as brand new.
+  pub fn prefix(&self, time: SystemTime) -> &'static str {
+    let age = self.now.duration_since(time).unwrap_or_default();
+    match age.as_secs() {
+      age if age < WEEK => RED,
+      age if age < MONTH => ORANGE,
+      age if age < YEAR => YELLOW,
+      _ => BLUE,
+    }
+  }
+
+  /// Wrap `token` in the color to use for a commit created at `time`.
+  pub fn colorize(&self, token: &str, time: SystemTime) -> String {
+    format!("{}{token}{RESET}", self.prefix(time))
+  }
+}
+
+impl Default for AgeColorizer {
+  #[inline]
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+
+/// Convert the output of `git blame --line-porcelain` into the format
+/// of `git blame -s`, with each commit colored by its age.
+///
+/// The all-zeros commit hash is replaced with `root_label`, unless it
+/// is empty.
+pub(crate) fn format(output: &[u8], colorizer: &AgeColorizer, root_label: &str) -> Result<Vec<u8>> {
+  let output = String::from_utf8_lossy(output);
+  let lines = porcelain::parse(&output)?;
+  let width = lines
+    .iter()
+    .map(|line| line.line_number.to_string().len())
+    .max()
+    .unwrap_or_default();
+
+  let mut formatted = Vec::new();
+  for line in lines {
+    let time = if line.author_time >= 0 {
+      UNIX_EPOCH + Duration::from_secs(line.author_time.unsigned_abs())
+    } else {
+      UNIX_EPOCH - Duration::from_secs(line.author_time.unsigned_abs())
+    };
+    let commit = if !root_label.is_empty() && is_null_commit(line.commit.as_bytes()) {
+      root_label
+    } else {
+      line.commit.get(..SHA1_DIGITS).unwrap_or(&line.commit)
+    };
+
+    writeln!(
+      formatted,
+      "{} {:>width$}) {}",
+      colorizer.colorize(commit, time),
+      line.line_number,
+      line.content,
+    )?;
+  }
+  Ok(formatted)
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+
+  /// Check that commits are colored according to their age.
+  #[test]
+  fn age_colors() {
+    let now = UNIX_EPOCH + Duration::from_secs(10 * YEAR);
+    let colorizer = AgeColorizer::with_reference(now);
+    let ago = |secs| now - Duration::from_secs(secs);
+
+    assert_eq!(colorizer.prefix(now), RED);
+    assert_eq!(colorizer.prefix(now + Duration::from_secs(DAY)), RED);
+    assert_eq!(colorizer.prefix(ago(6 * DAY)), RED);
+    assert_eq!(colorizer.prefix(ago(WEEK)), ORANGE);
+    assert_eq!(colorizer.prefix(ago(29 * DAY)), ORANGE);
+    assert_eq!(colorizer.prefix(ago(MONTH)), YELLOW);
+    assert_eq!(colorizer.prefix(ago(364 * DAY)), YELLOW);
+    assert_eq!(colorizer.prefix(ago(YEAR)), BLUE);
+    assert_eq!(colorizer.prefix(UNIX_EPOCH), BLUE);
+
+    assert_eq!(
+      colorizer.colorize("deadbeef", ago(DAY)),
+      "\x1b[31mdeadbeef\x1b[0m"
+    );
+  }
+}
@@ -0,0 +1,3383 @@
+// Copyright (C) 2022 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! A module for parsing diffs.
+
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::fmt::Result as FmtResult;
+use std::io::BufRead;
+use std::mem::replace;
+use std::mem::take;
+use std::ops::Not;
+use std::ops::Range;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use once_cell::sync::Lazy;
+
+use regex::Regex;
+
+use crate::Error;
+use crate::Result;
+
+const WS_STRING: &str = r"[ \t]*";
+/// A file name as quoted by git, if it contains special characters.
+const QUOTED_STRING: &str = r#""(?:[^"\\]|\\.)*""#;
+/// A file name that may contain spaces, but no tabs. Git terminates
+/// such names by a tab, while traditional diffs follow them up with a
+/// tab and a time stamp.
+const FILE_STRING: &str = r"[^\t]*[^ \t]";
+/// A file name on a `diff --git` line, which is ambiguous in the
+/// presence of spaces. We assume that file names are prefixed with
+/// `a/` and `b/`, respectively, in such a case.
+const GIT_SRC_STRING: &str = r"a/.+?|[^ \t]+";
+const GIT_DST_STRING: &str = r"b/.+|[^ \t]+";
+const ADDSUB_STRING: &str = r"([+\-])";
+const NUMLINE_STRING: &str = r"([0-9]+)";
+/// The indentation of the diffs between patches in `git range-diff`
+/// output.
+const RANGE_DIFF_INDENT: &str = "    ";
+
+static DIFF_DIFF_REGEX: Lazy<Regex> = Lazy::new(|| {
+  // Aside from '+' and '-' we have a "continuation" character ('\') in
+  // here which essentially just indicates a line that is being ignored.
+  // This character is used (in conjunction with the string "No newline at
+  // end of file") to indicate that a newline symbol at the end of a file
+  // is added or removed, for instance.
+  Regex::new(r"^[+\-\\ ]").unwrap()
+});
+static DIFF_GIT_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(&format!(
+    "^diff --git ({QUOTED_STRING}|{GIT_SRC_STRING}) ({QUOTED_STRING}|{GIT_DST_STRING})$"
+  ))
+  .unwrap()
+});
+static DIFF_CC_REGEX: Lazy<Regex> =
+  Lazy::new(|| Regex::new(&format!("^diff --(?:cc|combined) ({QUOTED_STRING}|.+)$")).unwrap());
+static DIFF_RENAME_FROM_REGEX: Lazy<Regex> =
+  Lazy::new(|| Regex::new("^rename from (.+)$").unwrap());
+static DIFF_META_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(concat!(
+    "^(index |new file mode|deleted file mode|old mode|new mode|",
+    "similarity index|dissimilarity index)"
+  ))
+  .unwrap()
+});
+static DIFF_BINARY_REGEX: Lazy<Regex> =
+  Lazy::new(|| Regex::new("^(Binary files |GIT binary patch)").unwrap());
+static DIFF_COMMIT_MSG_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new("^    ").unwrap());
+static DIFF_NODIFF_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"^[^+\- ]").unwrap());
+static DIFF_SRC_REGEX: Lazy<Regex> =
+  Lazy::new(|| Regex::new(&format!("^---{WS_STRING}({QUOTED_STRING}|{FILE_STRING})")).unwrap());
+static DIFF_DST_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(&format!(
+    r"^\+\+\+{WS_STRING}({QUOTED_STRING}|{FILE_STRING})"
+  ))
+  .unwrap()
+});
+static DIFF_TIMESTAMP_REGEX: Lazy<Regex> = Lazy::new(|| {
+  // POSIX `diff -u` follows file names with their modification time,
+  // e.g., `2026-10-15 10:42:23.000000000 +0200` or, in the traditional
+  // format, `Thu Oct 15 10:42:23 2026`.
+  Regex::new(concat!(
+    "[\t ]+(?:[0-9]{4}-[0-9]{2}-[0-9]{2} [0-9]{2}:[0-9]{2}:[0-9]{2}",
+    "(?:\\.[0-9]+)?(?: [+-][0-9]{4})?|",
+    "[A-Z][a-z]{2} [A-Z][a-z]{2} [ 0-9][0-9] [0-9]{2}:[0-9]{2}:[0-9]{2} [0-9]{4})$"
+  ))
+  .unwrap()
+});
+static DIFF_HEAD_REGEX: Lazy<Regex> = Lazy::new(|| {
+  // Note that in case a new file containing a single line is added the
+  // diff header might not contain the second count. The header may be
+  // followed by a hint at the function containing the hunk.
+  Regex::new(&format!(
+    "^@@ {ADDSUB_STRING}{NUMLINE_STRING}(?:,{NUMLINE_STRING})? \
+         {ADDSUB_STRING}{NUMLINE_STRING}(?:,{NUMLINE_STRING})? @@(?: (.*))?"
+  ))
+  .unwrap()
+});
+static DIFF_CC_HEAD_REGEX: Lazy<Regex> = Lazy::new(|| {
+  // A combined diff lists the ranges of all parents, followed by that
+  // of the result. The number of `@` symbols is one more than the
+  // number of parents.
+  Regex::new(&format!(
+    "^@(@@+) -{NUMLINE_STRING}(?:,{NUMLINE_STRING})? (?:-[0-9]+(?:,[0-9]+)? )*\
+         \\+{NUMLINE_STRING}(?:,{NUMLINE_STRING})? @@@+(?: (.*))?"
+  ))
+  .unwrap()
+});
+
+static RANGE_DIFF_PAIR_REGEX: Lazy<Regex> = Lazy::new(|| {
+  // A line of `git range-diff` output describing a pair of commits,
+  // e.g., `1:  606aa9b ! 1:  cfb3032 Subject`. Commits present in only
+  // one of the series are paired with dashes.
+  Regex::new(r"^ *(?:[0-9]+|-+): +([0-9a-f]+|-+) ([=!<>]) +(?:[0-9]+|-+): +([0-9a-f]+|-+) (.*)$")
+    .unwrap()
+});
+
+
+/// Remove the quotes git puts around file names containing special
+/// characters, unescaping the characters in between.
+///
+/// Names that are not quoted are returned as-is.
+fn unquote(name: &str) -> String {
+  let Some(quoted) = name
+    .strip_prefix('"')
+    .and_then(|name| name.strip_suffix('"'))
+  else {
+    return name.to_owned()
+  };
+
+  // Non-ASCII characters are escaped as octal representations of
+  // their individual UTF-8 bytes, so we have to work on bytes.
+  let mut unquoted = Vec::with_capacity(quoted.len());
+  let mut bytes = quoted.bytes().peekable();
+  while let Some(byte) = bytes.next() {
+    if byte != b'\\' {
+      let () = unquoted.push(byte);
+      continue
+    }
+
+    let unescaped = match bytes.next() {
+      Some(b'a') => b'\x07',
+      Some(b'b') => b'\x08',
+      Some(b'f') => b'\x0c',
+      Some(b'n') => b'\n',
+      Some(b'r') => b'\r',
+      Some(b't') => b'\t',
+      Some(b'v') => b'\x0b',
+      Some(digit @ b'0'..=b'7') => {
+        let mut value = digit - b'0';
+        for _ in 0..2 {
+          match bytes.peek() {
+            Some(digit @ b'0'..=b'7') => {
+              value = value.wrapping_mul(8).wrapping_add(digit - b'0');
+              let _digit = bytes.next();
+            },
+            _ => break,
+          }
+        }
+        value
+      },
+      // Quotes and backslashes stand for themselves. So does anything
+      // else we don't know about.
+      Some(byte) => byte,
+      None => b'\\',
+    };
+    let () = unquoted.push(unescaped);
+  }
+  String::from_utf8_lossy(&unquoted).into_owned()
+}
+
+
+/// An enumeration of the supported operations in a diff.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+  /// Lines are being added.
+  Add,
+  /// Lines are being removed.
+  Sub,
+}
+
+impl Op {
+  /// Retrieve the inverse operation.
+  ///
+  /// ```
+  /// # use git_blamediff::Op;
+  /// assert_eq!(Op::Add.flip(), Op::Sub);
+  /// assert_eq!(Op::Sub.flip(), Op::Add);
+  /// ```
+  #[inline]
+  pub fn flip(self) -> Self {
+    match self {
+      Self::Add => Self::Sub,
+      Self::Sub => Self::Add,
+    }
+  }
+
+  /// Retrieve an object for displaying the operation in a more
+  /// human-readable, "prose" form.
+  ///
+  /// ```
+  /// # use git_blamediff::Op;
+  /// assert_eq!(Op::Add.to_string(), "+");
+  /// assert_eq!(Op::Sub.to_string(), "-");
+  /// assert_eq!(Op::Add.display_verbose().to_string(), "→");
+  /// assert_eq!(Op::Sub.display_verbose().to_string(), "←");
+  /// ```
+  #[inline]
+  pub fn display_verbose(self) -> VerboseOp {
+    VerboseOp(self)
+  }
+}
+
+impl Display for Op {
+  fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+    let s = match self {
+      Self::Add => "+",
+      Self::Sub => "-",
+    };
+    f.write_str(s)
+  }
+}
+
+impl Not for Op {
+  type Output = Op;
+
+  /// Retrieve the inverse operation, just like [`Op::flip`].
+  ///
+  /// ```
+  /// # use git_blamediff::Op;
+  /// assert_eq!(!Op::Add, Op::Sub);
+  /// assert_eq!(!Op::Sub, Op::Add);
+  /// ```
+  #[inline]
+  fn not(self) -> Self::Output {
+    self.flip()
+  }
+}
+
+impl FromStr for Op {
+  type Err = ();
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "+" => Ok(Self::Add),
+      "-" => Ok(Self::Sub),
+      _ => Err(()),
+    }
+  }
+}
+
+
+/// A wrapper around an [`Op`] displaying it using arrows instead of
+/// the diff line-prefix characters.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VerboseOp(Op);
+
+impl Display for VerboseOp {
+  fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+    let s = match self.0 {
+      Op::Add => "→",
+      Op::Sub => "←",
+    };
+    f.write_str(s)
+  }
+}
+
+
+/// An enumeration of the flavors of diffs understood by [`Parser`].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DiffFormat {
+  /// Diffs as produced by `git diff` and friends.
+  #[default]
+  Git,
+  /// Diffs as produced by POSIX `diff -u`, which follows file names
+  /// in headers with their modification time.
+  Posix,
+}
+
+
+/// Options influencing how a [`Parser`] interprets diffs.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ParserOptions {
+  /// Whether to unconditionally remove the `a/` and `b/` prefixes
+  /// from the source and destination file names, respectively.
+  ///
+  /// By default, prefixes are only stripped for files introduced by a
+  /// `diff --git` line on which both names carry them. When set, they
+  /// are stripped from all names carrying them, which is wrong if the
+  /// diff got created with `--no-prefix` and touches files in a
+  /// top-level directory actually named `a` or `b`.
+  pub strip_ab_prefix: bool,
+  /// The format of the diffs to parse.
+  pub diff_format: DiffFormat,
+}
+
+
+/// A single line of a diff hunk.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DiffLine {
+  /// The operation the line is subject to, or `None` for a context
+  /// line.
+  pub op: Option<Op>,
+  /// The text of the line, without the leading operation symbol.
+  pub text: String,
+}
+
+
+/// An object capturing meta data about a diff.
+#[derive(Clone, Debug)]
+pub struct File {
+  /// The file the diff belongs to.
+  pub file: Arc<String>,
+  /// Whether the diff adds or removes lines.
+  pub op: Op,
+  /// The start line of the diff.
+  pub line: usize,
+  /// The number of lines in the diff.
+  pub count: usize,
+  /// The number of unchanged context lines at the start of the diff.
+  pub leading_context: usize,
+  /// The number of unchanged context lines at the end of the diff.
+  pub trailing_context: usize,
+  /// The name the file had before it got renamed, if the diff is
+  /// part of a rename.
+  pub original_file: Option<Arc<String>>,
+  /// The hint at the function containing the hunk, as provided by
+  /// `git diff` after the hunk header, if any.
+  pub func_context: Option<String>,
+  /// The lines of the hunk present on this side of the diff, i.e.,
+  /// context lines along with either the removed or the added ones.
+  pub content: Vec<DiffLine>,
+}
+
+impl File {
+  /// Retrieve the name of the file to annotate, which is the name the
+  /// file had before getting renamed, if that is known.
+  #[inline]
+  pub fn blame_file(&self) -> &str {
+    self.original_file.as_deref().unwrap_or(&self.file)
+  }
+
+  /// Determine the start line of the diff by searching for its lines in
+  /// `content`, the content of the file the diff belongs to.
+  ///
+  /// This is useful for diffs lacking line numbers, such as those
+  /// embedded in `git range-diff` output. The first match wins. If no
+  /// match is found, `false` is returned and the start line is left
+  /// unchanged.
+  pub fn locate(&mut self, content: &str) -> bool {
+    let needle = self
+      .content
+      .iter()
+      .map(|line| line.text.as_str())
+      .collect::<Vec<_>>();
+    if needle.is_empty() {
+      return false
+    }
+
+    let lines = content.lines().collect::<Vec<_>>();
+    match lines
+      .windows(needle.len())
+      .position(|window| window == needle)
+    {
+      Some(index) => {
+        self.line = index + 1;
+        true
+      },
+      None => false,
+    }
+  }
+}
+
+
+/// A single hunk of a diff, comprised of its source and destination
+/// side.
+#[derive(Clone, Debug)]
+pub struct Hunk {
+  /// The source side of the hunk, i.e., the one lines got removed
+  /// from.
+  pub src: File,
+  /// The destination side of the hunk, i.e., the one lines got added
+  /// to.
+  pub dst: File,
+}
+
+impl Hunk {
+  /// Create the reverse of the hunk, i.e., one with source and
+  /// destination side swapped and all operations flipped.
+  ///
+  /// Because the reverse of a rename is not known to `git`, the
+  /// resulting hunk carries no information about the original file.
+  pub fn swap(&self) -> Hunk {
+    fn flip(file: &File) -> File {
+      File {
+        op: file.op.flip(),
+        original_file: None,
+        content: file
+          .content
+          .iter()
+          .map(|line| DiffLine {
+            op: line.op.map(Op::flip),
+            text: line.text.clone(),
+          })
+          .collect(),
+        ..file.clone()
+      }
+    }
+
+    Hunk {
+      src: flip(&self.dst),
+      dst: flip(&self.src),
+    }
+  }
+}
+
+
+/// An enumeration of the ways in which two diffs can conflict.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConflictKind {
+  /// The diffs touch different files.
+  None,
+  /// The diffs touch the same file, but disjoint ranges of lines.
+  SameFile,
+  /// The diffs touch overlapping ranges of lines of the same file.
+  OverlappingRange,
+}
+
+
+/// Retrieve the range of source lines covered by a diff.
+///
+/// Diffs not covering any lines (i.e., pure additions) are treated as
+/// covering the line they are anchored at, so that two additions at the
+/// same location are considered overlapping.
+fn src_range(hunk: &Hunk) -> Range<usize> {
+  hunk.src.line..hunk.src.line + hunk.src.count.max(1)
+}
+
+
+/// Determine whether and how two diffs conflict, i.e., whether they
+/// modify overlapping ranges of the same file.
+pub fn conflict_kind(a: &Hunk, b: &Hunk) -> ConflictKind {
+  if a.src.file != b.src.file {
+    return ConflictKind::None
+  }
+
+  let a = src_range(a);
+  let b = src_range(b);
+  if a.start < b.end && b.start < a.end {
+    ConflictKind::OverlappingRange
+  } else {
+    ConflictKind::SameFile
+  }
+}
+
+
+/// Find all pairs of diffs that modify overlapping ranges of the same
+/// file, returning their indices.
+pub fn find_conflicts(diffs: &[Hunk]) -> Vec<(usize, usize)> {
+  let mut conflicts = Vec::new();
+  for (i, a) in diffs.iter().enumerate() {
+    for (j, b) in diffs.iter().enumerate().skip(i + 1) {
+      if conflict_kind(a, b) == ConflictKind::OverlappingRange {
+        let () = conflicts.push((i, j));
+      }
+    }
+  }
+  conflicts
+}
+
+
+/// Check whether the changes of a diff are limited to whitespace, i.e.,
+/// whether its removed and added lines are identical once all ASCII
+/// whitespace is stripped from them.
+pub fn is_whitespace_only(hunk: &Hunk) -> bool {
+  fn changed(file: &File) -> impl Iterator<Item = u8> + '_ {
+    file
+      .content
+      .iter()
+      .filter(|line| line.op.is_some())
+      .flat_map(|line| line.text.bytes())
+      .filter(|byte| !byte.is_ascii_whitespace())
+  }
+
+  changed(&hunk.src).eq(changed(&hunk.dst))
+}
+
+
+/// Filter out all diffs that merely change whitespace, as those are
+/// typically not of interest when annotating.
+///
+/// See [`is_whitespace_only`] for what constitutes such a diff.
+pub fn filter_whitespace_hunks(diffs: &[Hunk]) -> Vec<Hunk> {
+  diffs
+    .iter()
+    .filter(|diff| !is_whitespace_only(diff))
+    .cloned()
+    .collect()
+}
+
+
+/// Extract the name of a file from its (possibly quoted) form in a
+/// `---` or `+++` line.
+fn file_name(name: &str, format: DiffFormat) -> String {
+  let name = match format {
+    DiffFormat::Git => name,
+    DiffFormat::Posix => match DIFF_TIMESTAMP_REGEX.find(name) {
+      Some(timestamp) => &name[..timestamp.start()],
+      None => name,
+    },
+  };
+  unquote(name)
+}
+
+
+/// The range of lines covered by one side of a hunk, as captured from
+/// its header.
+struct HunkRange<'line> {
+  /// The operation performed on the side.
+  op: Op,
+  /// The start line.
+  start: &'line str,
+  /// The line count, if any.
+  count: Option<&'line str>,
+}
+
+
+/// Create the `Hunk` object described by a hunk header.
+///
+/// `None` is returned if a number in the hunk header is out of range.
+fn hunk(
+  src: &Arc<String>,
+  dst: &Arc<String>,
+  original: &Option<Arc<String>>,
+  src_range: HunkRange<'_>,
+  dst_range: HunkRange<'_>,
+  func_context: Option<&str>,
+) -> Option<Hunk> {
+  let func_context = func_context
+    .map(str::trim)
+    .filter(|func| !func.is_empty())
+    .map(str::to_string);
+  // Because a diff header might not contain counts if only a single
+  // line is affected, we provide the default "1" here.
+  let src_file = File {
+    file: src.clone(),
+    op: src_range.op,
+    line: src_range.start.parse().ok()?,
+    count: src_range.count.unwrap_or("1").parse().ok()?,
+    leading_context: 0,
+    trailing_context: 0,
+    original_file: original.clone(),
+    func_context: func_context.clone(),
+    content: Vec::new(),
+  };
+  let dst_file = File {
+    file: dst.clone(),
+    op: dst_range.op,
+    line: dst_range.start.parse().ok()?,
+    count: dst_range.count.unwrap_or("1").parse().ok()?,
+    leading_context: 0,
+    trailing_context: 0,
+    original_file: None,
+    func_context,
+    content: Vec::new(),
+  };
+  Some(Hunk {
+    src: src_file,
+    dst: dst_file,
+  })
+}
+
+
+/// Reduce a line of a hunk of a combined diff with `parents` columns of
+/// operation symbols to the corresponding line of a diff against the
+/// first parent.
+///
+/// Lines that were removed relative to other parents only are not
+/// part of such a diff and are turned into ignored ones, prefixed with
+/// `\`. `None` is returned if `line` is no combined diff line.
+fn reduce_combined(line: &str, parents: usize) -> Option<String> {
+  let ops = line.get(..parents)?;
+  if ops.is_empty() || !ops.bytes().all(|op| matches!(op, b' ' | b'+' | b'-')) {
+    // Lines such as `\ No newline at end of file` are carried over.
+    return line.starts_with('\\').then(|| line.to_string())
+  }
+
+  let text = &line[parents..];
+  // A `-` in any column means that the line is not present in the
+  // result, but in the parents with that very symbol. Otherwise the
+  // line is present in the result and, unless marked with a `+`, in the
+  // parents.
+  let op = match (ops.as_bytes()[0], ops.contains('-')) {
+    (b'-', _) => '-',
+    (_, true) => '\\',
+    (b'+', false) => '+',
+    (_, false) => ' ',
+  };
+  Some(format!("{op}{text}"))
+}
+
+
+/// Check whether `line` looks like the source or destination file
+/// header of a diff.
+fn is_file_header(line: &str) -> bool {
+  DIFF_SRC_REGEX.is_match(line) || DIFF_DST_REGEX.is_match(line)
+}
+
+
+/// The progress made in parsing the lines of a hunk.
+#[derive(Clone, Copy, Debug)]
+struct HunkProgress {
+  /// Whether we have seen an added or removed line.
+  changed: bool,
+  /// The number of source lines the hunk header announced but that we
+  /// have not yet seen.
+  src_left: usize,
+  /// The number of destination lines the hunk header announced but
+  /// that we have not yet seen.
+  dst_left: usize,
+}
+
+impl HunkProgress {
+  /// Create a progress tracker for the lines of the given hunk.
+  fn new(hunk: &Hunk) -> Self {
+    Self {
+      changed: false,
+      src_left: hunk.src.count,
+      dst_left: hunk.dst.count,
+    }
+  }
+
+  /// Check whether a diff line fits into the hunk, as per the counts
+  /// announced by its header.
+  fn fits(&self, line: &str) -> bool {
+    match line.as_bytes().first() {
+      Some(b' ') => self.src_left > 0 && self.dst_left > 0,
+      Some(b'-') => self.src_left > 0,
+      Some(b'+') => self.dst_left > 0,
+      _ => true,
+    }
+  }
+
+  /// Check whether we have seen all the lines the hunk header
+  /// announced.
+  fn is_complete(&self) -> bool {
+    self.src_left == 0 && self.dst_left == 0
+  }
+}
+
+
+/// An enumeration of all the states our parser can be in.
+#[derive(Clone, Debug)]
+enum State {
+  /// The state when we expect a new file to start.
+  Start,
+  /// The state after we parsed a `diff --git` line, as emitted by
+  /// `git diff`, containing hints as to the names of the source and
+  /// destination files.
+  ///
+  /// `original` is the original name of the file, if it got renamed,
+  /// here and in the following states.
+  GitDiff {
+    src_hint: Arc<String>,
+    dst_hint: Arc<String>,
+    original: Option<Arc<String>>,
+  },
+  /// The state after we parsed the source file header part. `strip`
+  /// indicates whether the `b/` prefix should be stripped from the
+  /// destination file.
+  Src {
+    src: Arc<String>,
+    strip: bool,
+    original: Option<Arc<String>>,
+  },
+  /// The state after we parsed the destination file header part.
+  Dst {
+    src: Arc<String>,
+    dst: Arc<String>,
+    original: Option<Arc<String>>,
+  },
+  /// The state after we parsed the entire header. `progress` tracks
+  /// the lines of the current hunk seen so far.
+  Hdr {
+    src: Arc<String>,
+    dst: Arc<String>,
+    original: Option<Arc<String>>,
+    progress: HunkProgress,
+  },
+  /// The state after we parsed the entire header of a hunk of a
+  /// combined diff, as produced for merge commits. Lines carry one
+  /// operation symbol for each of the `parents`.
+  CombinedHdr {
+    src: Arc<String>,
+    dst: Arc<String>,
+    original: Option<Arc<String>>,
+    progress: HunkProgress,
+    parents: usize,
+  },
+}
+
+impl State {
+  /// Create an error for `line`, which could not be parsed in the
+  /// current state.
+  fn error(&self, line: &str, line_number: usize) -> Error {
+    Error::Parse {
+      line_number,
+      content: line.to_string(),
+      state: format!("{self:?}"),
+    }
+  }
+
+  /// A helper function advancing `self` to another state.
+  fn advance(&mut self, state: State) -> Option<Result<()>> {
+    *self = state;
+    Some(Ok(()))
+  }
+
+  /// Try parsing a line containing information about the changed lines.
+  fn parse_head(
+    &mut self,
+    diffs: &mut VecDeque<Hunk>,
+    line: &str,
+    line_number: usize,
+    src: Arc<String>,
+    dst: Arc<String>,
+    original: Option<Arc<String>>,
+  ) -> Option<Result<()>> {
+    let captures = DIFF_HEAD_REGEX.captures(line)?;
+    // It is fine to unwrap captures 1-2 and 4-5 because we know they
+    // participate in the match unconditionally. Likewise, it is fine to
+    // unwrap the operations because the regex would not have matched
+    // if they were not valid.
+    let src_range = HunkRange {
+      op: captures.get(1).unwrap().as_str().parse().unwrap(),
+      start: captures.get(2).unwrap().as_str(),
+      count: captures.get(3).map(|m| m.as_str()),
+    };
+    let dst_range = HunkRange {
+      op: captures.get(4).unwrap().as_str().parse().unwrap(),
+      start: captures.get(5).unwrap().as_str(),
+      count: captures.get(6).map(|m| m.as_str()),
+    };
+    let func_context = captures.get(7).map(|m| m.as_str());
+
+    let Some(diff) = hunk(&src, &dst, &original, src_range, dst_range, func_context) else {
+      return Some(Err(self.error(line, line_number)))
+    };
+    let progress = HunkProgress::new(&diff);
+    let () = diffs.push_back(diff);
+    self.advance(Self::Hdr {
+      src,
+      dst,
+      original,
+      progress,
+    })
+  }
+
+  /// Try parsing a line containing information about the changed lines
+  /// of a combined diff.
+  ///
+  /// Such a diff is reduced to one against the first parent.
+  fn parse_cc_head(
+    &mut self,
+    diffs: &mut VecDeque<Hunk>,
+    line: &str,
+    line_number: usize,
+    src: Arc<String>,
+    dst: Arc<String>,
+    original: Option<Arc<String>>,
+  ) -> Option<Result<()>> {
+    let captures = DIFF_CC_HEAD_REGEX.captures(line)?;
+    // It is fine to unwrap captures 1-2 and 4 because we know they
+    // participate in the match unconditionally.
+    let parents = captures.get(1).unwrap().len();
+    let src_range = HunkRange {
+      op: Op::Sub,
+      start: captures.get(2).unwrap().as_str(),
+      count: captures.get(3).map(|m| m.as_str()),
+    };
+    let dst_range = HunkRange {
+      op: Op::Add,
+      start: captures.get(4).unwrap().as_str(),
+      count: captures.get(5).map(|m| m.as_str()),
+    };
+    let func_context = captures.get(6).map(|m| m.as_str());
+
+    let Some(diff) = hunk(&src, &dst, &original, src_range, dst_range, func_context) else {
+      return Some(Err(self.error(line, line_number)))
+    };
+    let progress = HunkProgress::new(&diff);
+    let () = diffs.push_back(diff);
+    self.advance(Self::CombinedHdr {
+      src,
+      dst,
+      original,
+      progress,
+      parents,
+    })
+  }
+
+  /// Try parsing a `diff --cc` or `diff --combined` line, which starts
+  /// the combined diff of a file in a merge commit.
+  fn parse_cc(&mut self, line: &str) -> Option<Result<()>> {
+    let captures = DIFF_CC_REGEX.captures(line)?;
+    // It is fine to unwrap here because we know the queried capture
+    // group participates in the match unconditionally.
+    let file = unquote(captures.get(1).unwrap().as_str());
+
+    // The file is named only once here and without the `a/` and `b/`
+    // prefixes, which the `---` and `+++` lines carry nevertheless,
+    // unless `--no-prefix` was used. We assume the default.
+    self.advance(Self::GitDiff {
+      src_hint: Arc::new(format!("a/{file}")),
+      dst_hint: Arc::new(format!("b/{file}")),
+      original: None,
+    })
+  }
+
+  /// Try parsing a `diff --git` line.
+  fn parse_git(&mut self, line: &str) -> Option<Result<()>> {
+    let captures = DIFF_GIT_REGEX.captures(line)?;
+    // It is fine to unwrap here because we know the queried capture
+    // groups participate in the match unconditionally.
+    let src_hint = captures.get(1).unwrap();
+    let dst_hint = captures.get(2).unwrap();
+
+    self.advance(Self::GitDiff {
+      src_hint: Arc::new(unquote(src_hint.as_str())),
+      dst_hint: Arc::new(unquote(dst_hint.as_str())),
+      original: None,
+    })
+  }
+
+  /// Try parsing a `rename from` line, as emitted by `git diff` for
+  /// renamed files.
+  fn parse_rename(
+    &mut self,
+    line: &str,
+    src_hint: Arc<String>,
+    dst_hint: Arc<String>,
+  ) -> Option<Result<()>> {
+    let captures = DIFF_RENAME_FROM_REGEX.captures(line)?;
+    // It is fine to unwrap here because we know the queried capture
+    // group participates in the match unconditionally.
+    let original = captures.get(1).unwrap();
+
+    self.advance(Self::GitDiff {
+      src_hint,
+      dst_hint,
+      original: Some(Arc::new(unquote(original.as_str()))),
+    })
+  }
+
+  /// Try parsing a line containing the source file.
+  ///
+  /// If `strip` is true, the `a/` prefix added by `git diff` is removed
+  /// from the file name.
+  fn parse_src(
+    &mut self,
+    line: &str,
+    strip: bool,
+    original: Option<Arc<String>>,
+    format: DiffFormat,
+  ) -> Option<Result<()>> {
+    let captures = DIFF_SRC_REGEX.captures(line)?;
+    // It is fine to unwrap here because we know the queried capture
+    // group participates in the match unconditionally.
+    let src = file_name(captures.get(1).unwrap().as_str(), format);
+    let src = if strip {
+      src.strip_prefix("a/").unwrap_or(&src)
+    } else {
+      &src
+    };
+
+    self.advance(Self::Src {
+      src: Arc::new(src.to_owned()),
+      strip,
+      original,
+    })
+  }
+
+  /// Try parsing a line containing the destination file.
+  ///
+  /// If `strip` is true, the `b/` prefix added by `git diff` is removed
+  /// from the file name.
+  fn parse_dst(
+    &mut self,
+    line: &str,
+    src: Arc<String>,
+    strip: bool,
+    original: Option<Arc<String>>,
+    format: DiffFormat,
+  ) -> Option<Result<()>> {
+    let captures = DIFF_DST_REGEX.captures(line)?;
+    // It is fine to unwrap here because we know the queried capture
+    // group participates in the match unconditionally.
+    let dst = file_name(captures.get(1).unwrap().as_str(), format);
+    let dst = if strip {
+      dst.strip_prefix("b/").unwrap_or(&dst)
+    } else {
+      &dst
+    };
+
+    self.advance(Self::Dst {
+      src,
+      dst: Arc::new(dst.to_owned()),
+      original,
+    })
+  }
+
+  /// Try matching a line that contains no actual diff.
+  fn match_no_diff(&mut self, line: &str) -> Option<Result<()>> {
+    DIFF_NODIFF_REGEX.is_match(line).then_some(Ok(()))
+  }
+
+  /// Try matching a line of a commit message, which `git show` and
+  /// `git log` indent by four spaces, preceding the actual diff.
+  fn match_commit_msg(&mut self, line: &str) -> Option<Result<()>> {
+    DIFF_COMMIT_MSG_REGEX.is_match(line).then_some(Ok(()))
+  }
+
+  /// Try matching a meta data line, such as `index ...` or
+  /// `new file mode ...`, which is of no interest to us.
+  fn match_meta(&mut self, line: &str) -> Option<Result<()>> {
+    DIFF_META_REGEX.is_match(line).then_some(Ok(()))
+  }
+
+  /// Try matching a line indicating that a binary file changed. Such
+  /// files can't be annotated, so we skip over the remainder of the
+  /// section and look for the next file.
+  fn skip_binary(&mut self, line: &str) -> Option<Result<()>> {
+    DIFF_BINARY_REGEX.is_match(line).then_some(())?;
+    self.advance(Self::Start)
+  }
+
+  /// Try matching an actual diff line, keeping track of the context
+  /// lines surrounding the changed ones.
+  fn parse_diff(
+    &mut self,
+    diffs: &mut VecDeque<Hunk>,
+    line: &str,
+    progress: HunkProgress,
+  ) -> Option<Result<()>> {
+    DIFF_DIFF_REGEX.is_match(line).then_some(())?;
+
+    // It is fine to unwrap here because we only ever end up in the
+    // `Hdr` and `CombinedHdr` states after having pushed a diff.
+    let Hunk {
+      src: src_file,
+      dst: dst_file,
+    } = diffs.back_mut().unwrap();
+    let mut progress = progress;
+    let diff_line = |op| DiffLine {
+      op,
+      text: line[1..].to_string(),
+    };
+    match line.as_bytes()[0] {
+      b' ' => {
+        if progress.changed {
+          src_file.trailing_context += 1;
+        } else {
+          src_file.leading_context += 1;
+        }
+        progress.src_left = progress.src_left.saturating_sub(1);
+        progress.dst_left = progress.dst_left.saturating_sub(1);
+        let () = src_file.content.push(diff_line(None));
+        let () = dst_file.content.push(diff_line(None));
+      },
+      b'-' => {
+        src_file.trailing_context = 0;
+        progress.changed = true;
+        progress.src_left = progress.src_left.saturating_sub(1);
+        let () = src_file.content.push(diff_line(Some(Op::Sub)));
+      },
+      b'+' => {
+        src_file.trailing_context = 0;
+        progress.changed = true;
+        progress.dst_left = progress.dst_left.saturating_sub(1);
+        let () = dst_file.content.push(diff_line(Some(Op::Add)));
+      },
+      _ => (),
+    };
+    dst_file.leading_context = src_file.leading_context;
+    dst_file.trailing_context = src_file.trailing_context;
+
+    match self {
+      Self::Hdr {
+        progress: current, ..
+      }
+      | Self::CombinedHdr {
+        progress: current, ..
+      } => *current = progress,
+      _ => (),
+    }
+    Some(Ok(()))
+  }
+
+  /// Check that the current hunk, if any, is complete, i.e., that we
+  /// have seen as many lines as its header announced, once `line` is
+  /// found not to be part of it.
+  ///
+  /// In lenient mode, a warning is printed instead of failing.
+  fn finish_hunk(&self, line: &str, line_number: usize, lenient: bool) -> Option<Result<()>> {
+    match self {
+      Self::Hdr { progress, .. } | Self::CombinedHdr { progress, .. }
+        if !progress.is_complete() =>
+      {
+        let error = self.error(line, line_number);
+        if lenient {
+          eprintln!("warning: {error}");
+          None
+        } else {
+          Some(Err(error))
+        }
+      },
+      _ => None,
+    }
+  }
+
+  /// Retrieve the context line an empty line stands for, if we are in
+  /// the middle of a hunk still expecting context lines.
+  ///
+  /// Editors and mail clients tend to strip trailing white space,
+  /// turning context lines of blank lines into empty ones. Just like
+  /// `git apply`, we accept those.
+  fn blank_context(&self) -> Option<String> {
+    match self {
+      Self::Hdr { progress, .. } if progress.fits(" ") => Some(" ".to_string()),
+      Self::CombinedHdr {
+        progress, parents, ..
+      } if progress.fits(" ") => Some(" ".repeat(*parents)),
+      _ => None,
+    }
+  }
+
+  /// Try matching a line not from an actual diff that indicates the
+  /// start of a new file.
+  fn restart(&mut self, line: &str) -> Option<Result<()>> {
+    DIFF_NODIFF_REGEX.is_match(line).then_some(())?;
+    self.advance(Self::Start)
+  }
+
+  /// Parse `line`, advancing the state accordingly.
+  ///
+  /// If `lenient` is true, mismatches between the number of lines in a
+  /// hunk and the counts announced by its header merely result in a
+  /// warning. The same is true for unexpected lines, in which case we
+  /// start over looking for the next file.
+  fn parse(
+    &mut self,
+    diffs: &mut VecDeque<Hunk>,
+    line: &str,
+    line_number: usize,
+    lenient: bool,
+    options: &ParserOptions,
+  ) -> Result<()> {
+    /// Check and evaluate the result of a parser function.
+    macro_rules! check {
+      ($result:expr) => {
+        match $result {
+          // The parser did not match. Continue with the next one.
+          None => (),
+          // The parser matched and then either continued parsing
+          // successfully or produced an error. Short circuit in both cases
+          // to bubble up the result.
+          Some(result) => return result,
+        }
+      };
+    }
+
+    let strip = options.strip_ab_prefix;
+    let format = options.diff_format;
+
+    // This clone is a mere bump of three `Arc` counts, at most.
+    match self.clone() {
+      State::Start => {
+        check!(self.parse_git(line));
+        check!(self.parse_cc(line));
+        check!(self.parse_src(line, strip, None, format));
+        check!(self.skip_binary(line));
+        check!(self.match_no_diff(line));
+        check!(self.match_commit_msg(line));
+      },
+      State::GitDiff {
+        src_hint,
+        dst_hint,
+        original,
+      } => {
+        // By default `git diff` prefixes source and destination files
+        // with `a/` and `b/`, respectively, but that is not the case
+        // when `--no-prefix` is used. We can't know for sure which one
+        // it is, as paths may well start with a directory named `a` or
+        // `b`. As a heuristic, we only strip prefixes if both file
+        // names on the `diff --git` line carry them. That is wrong only
+        // for files moved from within a top-level directory `a` to one
+        // named `b` in diffs created with `--no-prefix`. Users may ask
+        // for stripping prefixes unconditionally, though.
+        let strip = strip || (src_hint.starts_with("a/") && dst_hint.starts_with("b/"));
+        check!(self.parse_git(line));
+        check!(self.parse_cc(line));
+        check!(self.parse_rename(line, src_hint, dst_hint));
+        check!(self.parse_src(line, strip, original, format));
+        check!(self.skip_binary(line));
+        // Lines such as `index ...`, `new file mode ...`, or
+        // `rename to ...` are simply skipped over.
+        check!(self.match_no_diff(line));
+      },
+      State::Src {
+        src,
+        strip,
+        original,
+      } => {
+        check!(self.parse_dst(line, src, strip, original, format));
+        check!(self.match_meta(line));
+      },
+      State::Dst { src, dst, original } => {
+        check!(self.parse_head(
+          diffs,
+          line,
+          line_number,
+          src.clone(),
+          dst.clone(),
+          original.clone()
+        ));
+        check!(self.parse_cc_head(diffs, line, line_number, src, dst, original));
+        check!(self.match_meta(line));
+      },
+      State::Hdr {
+        src,
+        dst,
+        original,
+        progress,
+      } => {
+        // Diff lines exceeding the counts announced by the hunk header
+        // are not considered part of the hunk. In lenient mode we
+        // treat them as such nevertheless, unless they look like the
+        // start of the next file.
+        let fits = progress.fits(line);
+        let overflow = !fits && lenient && DIFF_DIFF_REGEX.is_match(line) && !is_file_header(line);
+        if overflow {
+          eprintln!("warning: {}", self.error(line, line_number));
+        }
+        if fits || overflow {
+          check!(self.parse_diff(diffs, line, progress));
+        }
+        check!(self.finish_hunk(line, line_number, lenient));
+        check!(self.parse_head(diffs, line, line_number, src, dst, original));
+        check!(self.parse_git(line));
+        check!(self.parse_cc(line));
+        check!(self.parse_src(line, strip, None, format));
+        check!(self.skip_binary(line));
+        check!(self.restart(line));
+      },
+      State::CombinedHdr {
+        src,
+        dst,
+        original,
+        progress,
+        parents,
+      } => {
+        if let Some(reduced) = reduce_combined(line, parents) {
+          let fits = progress.fits(&reduced);
+          let overflow = !fits && lenient && !is_file_header(line);
+          if overflow {
+            eprintln!("warning: {}", self.error(line, line_number));
+          }
+          if fits || overflow {
+            check!(self.parse_diff(diffs, &reduced, progress));
+          }
+        }
+        check!(self.finish_hunk(line, line_number, lenient));
+        check!(self.parse_cc_head(diffs, line, line_number, src, dst, original));
+        check!(self.parse_git(line));
+        check!(self.parse_cc(line));
+        check!(self.parse_src(line, strip, None, format));
+        check!(self.skip_binary(line));
+        check!(self.restart(line));
+      },
+    };
+
+    let error = self.error(line, line_number);
+    if lenient {
+      eprintln!("warning: {error}; skipping");
+      *self = State::Start;
+      Ok(())
+    } else {
+      Err(error)
+    }
+  }
+}
+
+
+/// A type interpreting a diff and extracting relevant information.
+pub struct Parser {
+  state: State,
+  diffs: Vec<Hunk>,
+  /// Whether to merely warn about malformed input instead of failing.
+  lenient: bool,
+  /// The number of lines consumed across all invocations of
+  /// [`Parser::parse`] since the last reset.
+  line_number: usize,
+  /// Options influencing how diffs are interpreted.
+  options: ParserOptions,
+}
+
+impl Parser {
+  /// Create a new `Parser` object in its initial state.
+  ///
+  /// The parser fails on hunks containing more or fewer lines than
+  /// announced by their headers, as can be the case for truncated or
+  /// otherwise corrupted diffs.
+  #[inline]
+  pub fn new() -> Self {
+    Self {
+      state: State::Start,
+      diffs: Vec::new(),
+      lenient: false,
+      line_number: 0,
+      options: ParserOptions::default(),
+    }
+  }
+
+  /// Create a new `Parser` object in its initial state, with space
+  /// for `hunks` diffs preallocated.
+  #[inline]
+  pub fn with_capacity(hunks: usize) -> Self {
+    Self {
+      diffs: Vec::with_capacity(hunks),
+      ..Self::new()
+    }
+  }
+
+  /// Create a new `Parser` object in its initial state, interpreting
+  /// diffs as dictated by the provided options.
+  #[inline]
+  pub fn with_options(options: ParserOptions) -> Self {
+    Self {
+      options,
+      ..Self::new()
+    }
+  }
+
+  /// Create a new `Parser` object in its initial state that prints a
+  /// warning to stderr instead of failing on malformed input.
+  ///
+  /// Hunks not matching the line counts announced by their headers are
+  /// accepted as they are. On unexpected lines, such as those added by
+  /// code review tools, the parser skips ahead to the next file,
+  /// keeping the diffs found so far.
+  #[inline]
+  pub fn new_lenient() -> Self {
+    Self {
+      lenient: true,
+      ..Self::new()
+    }
+  }
+
+  /// Create an iterator yielding diffs parsed from `reader` one at a
+  /// time.
+  ///
+  /// Contrary to [`Parser::parse`], diffs are reported as soon as they
+  /// are complete, instead of after the entire input was consumed.
+  #[inline]
+  pub fn iter<R>(reader: R) -> ParserIter<R>
+  where
+    R: BufRead,
+  {
+    ParserIter::new(reader)
+  }
+
+  /// Parse a list of lines.
+  ///
+  /// Lines are consumed until `lines` reports end-of-file, i.e., until
+  /// [`BufRead::read_line`] reads zero bytes. When reading from a pipe
+  /// that is the case only once the writing end has been closed.
+  /// The last line of the input does not have to be terminated by a
+  /// newline symbol and is parsed just like every other line.
+  pub fn parse<L>(&mut self, lines: L) -> Result<()>
+  where
+    L: BufRead,
+  {
+    let mut iter = ParserIter::new(lines);
+    iter.state = replace(&mut self.state, State::Start);
+    iter.lenient = self.lenient;
+    iter.options = self.options;
+    // Continue numbering where the previous invocation stopped, so
+    // that errors refer to the line in the input as a whole.
+    iter.line_number = self.line_number;
+    // If we are in the middle of a hunk, the last diff may still be
+    // amended with additional context lines.
+    if let State::Hdr { .. } | State::CombinedHdr { .. } = iter.state {
+      iter.pending.extend(self.diffs.pop());
+    }
+
+    let result = iter.by_ref().try_for_each(|diff| {
+      let () = self.diffs.push(diff?);
+      Ok(())
+    });
+    // On error, keep the diffs found before the offending line.
+    let () = self.diffs.extend(iter.pending);
+    self.state = iter.state;
+    self.line_number = iter.line_number;
+    result
+  }
+
+  /// Reset the parser to its initial state, discarding all found
+  /// diffs but retaining allocated memory for reuse.
+  ///
+  /// Line numbers reported in errors start over from the beginning.
+  /// Whether the parser is lenient and its options are not affected.
+  #[inline]
+  pub fn reset(&mut self) {
+    self.state = State::Start;
+    self.line_number = 0;
+    let () = self.diffs.clear();
+  }
+
+  /// Retrieve all found diffs.
+  pub fn diffs(&self) -> &[Hunk] {
+    &self.diffs
+  }
+
+  /// Retrieve all found diffs for in-place modification, e.g., for
+  /// filtering or sorting them.
+  ///
+  /// If the input parsed so far ended in the middle of a hunk, the
+  /// last diff may still be amended by further parsing. Hence, parsing
+  /// additional input after modifying the diffs leaves the parser in an
+  /// unspecified state.
+  pub fn diffs_mut(&mut self) -> &mut Vec<Hunk> {
+    &mut self.diffs
+  }
+
+  /// Consume the parser, retrieving all found diffs.
+  ///
+  /// Any hunk that the input parsed so far ended in the middle of is
+  /// reported as is.
+  pub fn into_diffs(self) -> Vec<Hunk> {
+    self.diffs
+  }
+
+  /// Fold over all found diffs on a per-file basis.
+  ///
+  /// `f` is invoked with the accumulated value, the name of the source
+  /// file, and all diffs for that file. Diffs are grouped in a single
+  /// scan, preserving their order. Hence, if diffs for a file do not
+  /// appear contiguously (which is never the case for diffs generated
+  /// by `git`), `f` will be invoked once for each contiguous run.
+  pub fn fold_files<B>(&self, init: B, mut f: impl FnMut(B, &str, &[Hunk]) -> B) -> B {
+    let mut acc = init;
+    let mut rest = self.diffs.as_slice();
+
+    while let Some(Hunk { src: first, .. }) = rest.first() {
+      let count = rest
+        .iter()
+        .take_while(|Hunk { src, .. }| src.file == first.file)
+        .count();
+      let (diffs, remainder) = rest.split_at(count);
+      acc = f(acc, &first.file, diffs);
+      rest = remainder;
+    }
+    acc
+  }
+}
+
+impl Default for Parser {
+  #[inline]
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+
+/// An enumeration of the patch series compared by `git range-diff`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum RangeDiffSide {
+  /// The old series, i.e., the one given first.
+  Old,
+  /// The new series, i.e., the one given second.
+  #[default]
+  New,
+  /// Both series.
+  Both,
+}
+
+
+/// A pair of corresponding commits of the patch series compared by
+/// `git range-diff`.
+#[derive(Clone, Debug)]
+pub struct CommitPair {
+  /// The (abbreviated) SHA-1 of the commit in the old series, if the
+  /// commit is part of it.
+  pub old: Option<String>,
+  /// The (abbreviated) SHA-1 of the commit in the new series, if the
+  /// commit is part of it.
+  pub new: Option<String>,
+  /// Whether the patches of both commits are identical.
+  pub identical: bool,
+  /// The subject of the commit.
+  pub subject: String,
+  /// The hunks of the old commit's patch that differ from the new one.
+  pub old_diffs: Vec<Hunk>,
+  /// The hunks of the new commit's patch that differ from the old one.
+  pub new_diffs: Vec<Hunk>,
+}
+
+
+/// Extract the source and destination file from the name of a section
+/// of a patch embedded in `git range-diff` output, e.g., `main.c`,
+/// `main.c: int main()`, `old.c => new.c`, or `new.c (new)`.
+///
+/// `None` is returned for sections not describing a file, such as the
+/// commit message.
+fn range_diff_files(section: &str) -> Option<(Arc<String>, Arc<String>)> {
+  let section = section
+    .split_once(": ")
+    .map_or(section, |(file, _func)| file);
+  let section = match section.rsplit_once(" (") {
+    Some((file, suffix)) if suffix.ends_with(')') => file,
+    _ => section,
+  };
+  if matches!(section, "" | "Metadata" | "Commit message" | "Notes") {
+    return None
+  }
+
+  let (src, dst) = section.split_once(" => ").unwrap_or((section, section));
+  Some((Arc::new(src.to_string()), Arc::new(dst.to_string())))
+}
+
+
+/// The patch of one of the commits of a pair reported by
+/// `git range-diff`, as reconstructed from the lines present on its
+/// side of the diff between the two patches.
+#[derive(Debug, Default)]
+struct RangeDiffPatch {
+  /// The source and destination file of the current hunk.
+  files: Option<(Arc<String>, Arc<String>)>,
+  /// The start lines of the source and destination side of the current
+  /// hunk, if known.
+  start: Option<(usize, usize)>,
+  /// The hint at the function containing the current hunk.
+  func_context: Option<String>,
+  /// The lines of the current hunk found so far.
+  lines: Vec<String>,
+  /// Whether any of the lines differs from the other patch.
+  differs: bool,
+  /// The differing hunks found so far.
+  diffs: Vec<Hunk>,
+}
+
+impl RangeDiffPatch {
+  /// Conclude the current hunk, recording it if it differs from the
+  /// other patch.
+  ///
+  /// The hunk is fed through a regular [`Parser`], with a header
+  /// synthesized from the lines it consists of.
+  fn finish_hunk(&mut self) -> Result<()> {
+    let lines = take(&mut self.lines);
+    let start = self.start.take();
+    let differs = take(&mut self.differs);
+    let Some((src, dst)) = &self.files else {
+      return Ok(())
+    };
+    if !differs || lines.is_empty() {
+      return Ok(())
+    }
+
+    let count = |ops: &[u8]| {
+      lines
+        .iter()
+        .filter(|line| ops.contains(&line.as_bytes()[0]))
+        .count()
+    };
+    let (src_line, dst_line) = start.unwrap_or((0, 0));
+    let func = self.func_context.as_deref().unwrap_or("");
+    let mut diff = format!(
+      "--- {src}\n+++ {dst}\n@@ -{src_line},{} +{dst_line},{} @@ {func}\n",
+      count(b" -"),
+      count(b" +"),
+    );
+    for line in lines {
+      let () = diff.push_str(&line);
+      let () = diff.push('\n');
+    }
+
+    let mut parser = Parser::new();
+    let () = parser.parse(diff.as_bytes())?;
+    let () = self.diffs.append(&mut parser.diffs);
+    Ok(())
+  }
+
+  /// Parse a line of the patch, without the operation symbol of the
+  /// diff between the patches. `differs` indicates whether the line is
+  /// present in this patch only.
+  fn parse(&mut self, line: &str, differs: bool) -> Result<()> {
+    // Section headers, e.g., for files, are formatted like context
+    // lines.
+    if let Some(section) = line
+      .strip_prefix(" ## ")
+      .and_then(|line| line.strip_suffix(" ##"))
+    {
+      let () = self.finish_hunk()?;
+      self.files = range_diff_files(section);
+    } else if line.starts_with("@@") {
+      let () = self.finish_hunk()?;
+      // `git range-diff` strips line numbers from hunk headers, but
+      // keeps the name of the file along with the function context.
+      if let Some(captures) = DIFF_HEAD_REGEX.captures(line) {
+        // It is fine to unwrap captures 2 and 5 because we know they
+        // participate in the match unconditionally.
+        let start = |index| captures.get(index).unwrap().as_str().parse().ok();
+        self.start = start(2).zip(start(5));
+        self.func_context = captures.get(7).map(|m| m.as_str().to_string());
+      } else if let Some(section) = line.strip_prefix("@@ ") {
+        self.files = range_diff_files(section);
+        self.func_context = section
+          .split_once(": ")
+          .map(|(_file, func)| func.to_string());
+      } else {
+        self.func_context = None;
+      }
+    } else if self.files.is_some() && DIFF_DIFF_REGEX.is_match(line) {
+      let () = self.lines.push(line.to_string());
+      self.differs |= differs;
+    } else {
+      let () = self.finish_hunk()?;
+    }
+    Ok(())
+  }
+}
+
+
+/// A type interpreting the output of `git range-diff`, extracting the
+/// pairs of commits it reports along with the hunks of their patches
+/// that differ between the two series.
+///
+/// Note that `git range-diff` strips line numbers from the patches it
+/// embeds. Such hunks are reported with start lines of zero and have to
+/// be located, e.g., using [`File::locate`], before being annotated.
+/// Patches of commits present in only one of the series are not
+/// embedded at all.
+#[derive(Debug, Default)]
+pub struct RangeDiffParser {
+  pairs: Vec<CommitPair>,
+  /// The patches of the old and new commit of the last pair.
+  patches: Option<(RangeDiffPatch, RangeDiffPatch)>,
+  /// The number of lines consumed across all invocations of
+  /// [`RangeDiffParser::parse`].
+  line_number: usize,
+}
+
+impl RangeDiffParser {
+  /// Create a new `RangeDiffParser` object in its initial state.
+  #[inline]
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Conclude the patches of the last pair of commits, if any.
+  fn finish_pair(&mut self) -> Result<()> {
+    if let Some((mut old, mut new)) = self.patches.take() {
+      let () = old.finish_hunk()?;
+      let () = new.finish_hunk()?;
+      // It is fine to unwrap here because we only ever have patches
+      // after having pushed a pair.
+      let pair = self.pairs.last_mut().unwrap();
+      pair.old_diffs = old.diffs;
+      pair.new_diffs = new.diffs;
+    }
+    Ok(())
+  }
+
+  /// Parse a line of the diff between the patches of the last pair of
+  /// commits, after its indentation.
+  fn parse_patches(&mut self, line: &str) -> Result<bool> {
+    let Some((old, new)) = &mut self.patches else {
+      return Ok(false)
+    };
+
+    match line.as_bytes().first() {
+      // A hunk header of the diff between the patches indicates a gap
+      // in both. It contains the name of the section of the patches the
+      // hunk belongs to.
+      Some(b'@') => {
+        let section = line.trim_start_matches('@').trim_start();
+        for patch in [old, new] {
+          let () = patch.finish_hunk()?;
+          patch.files = range_diff_files(section);
+          patch.func_context = section
+            .split_once(": ")
+            .map(|(_file, func)| func.to_string());
+        }
+      },
+      Some(b' ') => {
+        let () = old.parse(&line[1..], false)?;
+        let () = new.parse(&line[1..], false)?;
+      },
+      Some(b'-') => old.parse(&line[1..], true)?,
+      Some(b'+') => new.parse(&line[1..], true)?,
+      _ => return Ok(false),
+    }
+    Ok(true)
+  }
+
+  /// Parse a list of lines.
+  pub fn parse<L>(&mut self, lines: L) -> Result<()>
+  where
+    L: BufRead,
+  {
+    let commit = |sha1: &str| (!sha1.starts_with('-')).then(|| sha1.to_string());
+
+    for line in lines.lines() {
+      let line = line?;
+      self.line_number += 1;
+
+      let line = line.trim_end_matches('\r');
+      if let Some(captures) = RANGE_DIFF_PAIR_REGEX.captures(line) {
+        let () = self.finish_pair()?;
+        let () = self.pairs.push(CommitPair {
+          old: commit(&captures[1]),
+          new: commit(&captures[3]),
+          identical: &captures[2] == "=",
+          subject: captures[4].to_string(),
+          old_diffs: Vec::new(),
+          new_diffs: Vec::new(),
+        });
+        self.patches = Some(Default::default());
+      } else if let Some(body) = line.strip_prefix(RANGE_DIFF_INDENT) {
+        if !self.parse_patches(body)? {
+          return Err(Error::Parse {
+            line_number: self.line_number,
+            content: line.to_string(),
+            state: "RangeDiff".to_string(),
+          })
+        }
+      } else if !line.is_empty() {
+        return Err(Error::Parse {
+          line_number: self.line_number,
+          content: line.to_string(),
+          state: "RangeDiff".to_string(),
+        })
+      }
+    }
+    self.finish_pair()
+  }
+
+  /// Retrieve all found pairs of commits.
+  pub fn pairs(&self) -> &[CommitPair] {
+    &self.pairs
+  }
+
+  /// Retrieve the commits of the given side(s) along with the hunks of
+  /// their patches that differ between the two series, in order.
+  ///
+  /// Commits without such hunks are omitted. For
+  /// [`RangeDiffSide::Both`], the old commit of each pair precedes the
+  /// new one.
+  pub fn patches(&self, side: RangeDiffSide) -> Vec<(&str, &[Hunk])> {
+    let old = matches!(side, RangeDiffSide::Old | RangeDiffSide::Both);
+    let new = matches!(side, RangeDiffSide::New | RangeDiffSide::Both);
+
+    self
+      .pairs
+      .iter()
+      .flat_map(|pair| {
+        let old = old
+          .then_some(pair.old.as_deref().zip(Some(pair.old_diffs.as_slice())))
+          .flatten();
+        let new = new
+          .then_some(pair.new.as_deref().zip(Some(pair.new_diffs.as_slice())))
+          .flatten();
+        old.into_iter().chain(new)
+      })
+      .filter(|(_commit, diffs)| !diffs.is_empty())
+      .collect()
+  }
+}
+
+
+/// An iterator parsing diffs from a reader and yielding them one at a
+/// time, as created by [`Parser::iter`].
+pub struct ParserIter<R> {
+  reader: R,
+  state: State,
+  /// Diffs that have been parsed but not yet yielded. The last one may
+  /// still be amended with context lines, so it is only yielded once
+  /// the next one was found or the end of the input was reached.
+  pending: VecDeque<Hunk>,
+  /// The number of the last line read.
+  line_number: usize,
+  /// A buffer for the line currently being parsed.
+  line: String,
+  /// Whether we have reached end-of-file or encountered an error. In
+  /// both cases only pending diffs are yielded from here on.
+  done: bool,
+  /// Whether to merely warn about malformed input instead of failing.
+  lenient: bool,
+  /// Options influencing how diffs are interpreted.
+  options: ParserOptions,
+}
+
+impl<R> ParserIter<R>
+where
+  R: BufRead,
+{
+  fn new(reader: R) -> Self {
+    Self {
+      reader,
+      state: State::Start,
+      pending: VecDeque::new(),
+      line_number: 0,
+      line: String::new(),
+      done: false,
+      lenient: false,
+      options: ParserOptions::default(),
+    }
+  }
+
+  /// Read and parse the next line of input.
+  fn parse_line(&mut self) -> Result<()> {
+    self.line.clear();
+
+    let count = self.reader.read_line(&mut self.line)?;
+    if count == 0 {
+      // We have reached end-of-file, which concludes the last hunk.
+      self.done = true;
+      let line_number = self.line_number + 1;
+      return self
+        .state
+        .finish_hunk("", line_number, self.lenient)
+        .unwrap_or(Ok(()))
+    }
+    self.line_number += 1;
+
+    // Remove trailing new line symbols, we already expect lines. Diffs
+    // created on Windows may use CRLF line endings, so carriage returns
+    // are removed as well.
+    // Note that the very last line may lack such a symbol, in which
+    // case `read_line` provides us with the remaining bytes as-is.
+    let line = self.line.trim_end_matches(['\n', '\r']);
+    // Inside of a hunk an empty line is a context line whose leading
+    // space got stripped. Anywhere else we simply ignore empty lines
+    // and do not even hand them into the state for further
+    // consideration because they cannot change anything.
+    if line.is_empty() {
+      if let Some(context) = self.state.blank_context() {
+        let () = self.state.parse(
+          &mut self.pending,
+          &context,
+          self.line_number,
+          self.lenient,
+          &self.options,
+        )?;
+      }
+    } else {
+      let () = self.state.parse(
+        &mut self.pending,
+        line,
+        self.line_number,
+        self.lenient,
+        &self.options,
+      )?;
+    }
+    Ok(())
+  }
+}
+
+impl<R> Iterator for ParserIter<R>
+where
+  R: BufRead,
+{
+  type Item = Result<Hunk>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    loop {
+      if self.pending.len() > 1 || self.done {
+        break self.pending.pop_front().map(Ok)
+      }
+
+      if let Err(error) = self.parse_line() {
+        self.done = true;
+        break Some(Err(error))
+      }
+    }
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::io;
+  use std::io::BufReader;
+  use std::io::Read;
+  use std::ops::Deref as _;
+
+
+  /// Check that diffs can be shared with and sent to other threads.
+  #[test]
+  fn diffs_send_sync() {
+    fn check<T: Send + Sync>() {}
+
+    let () = check::<File>();
+    let () = check::<Hunk>();
+  }
+
+
+  /// Test parsing of a very simple one-line-change diff.
+  #[test]
+  fn parse_simple_diff() {
+    let diff = r#"
+--- main.c
++++ main.c
+@@ -6,6 +6,6 @@ int main(int argc, char const* argv[])
+     fprintf(stderr, "Too many arguments.\n");
+     return -1;
+   }
+-  printf("Hello world!");
++  printf("Hello world!\n");
+   return 0;
+ }"#;
+
+    let mut parser = Parser::new();
+    let () = parser.parse(diff.as_bytes()).unwrap();
+
+    let diffs = parser.diffs();
+    assert_eq!(diffs.len(), 1);
+
+    let Hunk { src, dst } = &diffs[0];
+    assert_eq!(src.file.deref(), "main.c");
+    assert_eq!(src.op, Op::Sub);
+    assert_eq!(src.line, 6);
+    assert_eq!(src.count, 6);
+    assert_eq!(
+      src.func_context.as_deref(),
+      Some("int main(int argc, char const* argv[])")
+    );
+
+    assert_eq!(dst.file.deref(), "main.c");
+    assert_eq!(dst.op, Op::Add);
+    assert_eq!(dst.line, 6);
+    assert_eq!(dst.count, 6);
+    assert_eq!(dst.func_context, src.func_context);
+  }
+
+  /// Test that we can parse a diff emitted by git if a file's trailing
+  /// newline is added.
+  #[test]
+  fn parse_diff_adding_newline_at_end_of_file() {
+    let diff = r#"
+--- main.c
++++ main.c
+@@ -8,4 +8,4 @@ int main(int argc, char const* argv[])
+   }
+   printf("Hello world!");
+   return 0;
+-}
+\\ No newline at end of file
++}"#;
+
+    let mut parser = Parser::new();
+    let () = parser.parse(diff.as_bytes()).unwrap();
+
+    let diffs = parser.diffs();
+    assert_eq!(diffs.len(), 1);
+
+    let Hunk { src, dst } = &diffs[0];
+    assert_eq!(src.file.deref(), "main.c");
+    assert_eq!(src.op, Op::Sub);
+    assert_eq!(src.line, 8);
+    assert_eq!(src.count, 4);
+
+    assert_eq!(dst.file.deref(), "main.c");
+    assert_eq!(dst.op, Op::Add);
+    assert_eq!(dst.line, 8);
+    assert_eq!(dst.count, 4);
+  }
+
+  /// Test that we can parse a diff emitted by git if a file's trailing
+  /// newline is removed.
+  #[test]
+  fn parse_diff_removing_newline_at_end_of_file() {
+    let diff = r#"
+--- main.c
++++ main.c
+@@ -8,4 +8,4 @@ int main(int argc, char const* argv[])
+   }
+   printf("Hello world!");
+   return 0;
+-}
++}
+\\ No newline at end of file"#;
+
+    let mut parser = Parser::new();
+    let () = parser.parse(diff.as_bytes()).unwrap();
+
+    let diffs = parser.diffs();
+    assert_eq!(diffs.len(), 1);
+
+    let Hunk { src, dst } = &diffs[0];
+    assert_eq!(src.file.deref(), "main.c");
+    assert_eq!(src.op, Op::Sub);
+    assert_eq!(src.line, 8);
+    assert_eq!(src.count, 4);
+
+    assert_eq!(dst.file.deref(), "main.c");
+    assert_eq!(dst.op, Op::Add);
+    assert_eq!(dst.line, 8);
+    assert_eq!(dst.count, 4);
+  }
+
+  /// Test that we can parse a diff adding a file with a single line."""
+  #[test]
+  fn parse_diff_with_added_file_with_single_line() {
+    let diff = r#"
+--- /dev/null
++++ main.c
+@@ -0,0 +1 @@
++main.c"#;
+
+    let mut parser = Parser::new();
+    let () = parser.parse(diff.as_bytes()).unwrap();
+
+    let diffs = parser.diffs();
+    assert_eq!(diffs.len(), 1);
+
+    let Hunk { src, dst } = &diffs[0];
+    assert_eq!(src.file.deref(), "/dev/null");
+    assert_eq!(src.op, Op::Sub);
+    assert_eq!(src.line, 0);
+    assert_eq!(src.count, 0);
+
+    assert_eq!(dst.file.deref(), "main.c");
+    assert_eq!(dst.op, Op::Add);
+    assert_eq!(dst.line, 1);
+    assert_eq!(dst.count, 1);
+    assert_eq!(dst.func_context, None);
+  }
+
+  /// Test that we can parse a diff removing a file with a single line.
+  #[test]
+  fn parse_diff_with_removed_file_with_single_line() {
+    let diff = r#"
+--- main.c
++++ /dev/null
+@@ -1 +0,0 @@
+-main.c"#;
+
+    let mut parser = Parser::new();
+    let () = parser.parse(diff.as_bytes()).unwrap();
+
+    let diffs = parser.diffs();
+    assert_eq!(diffs.len(), 1);
+
+    let Hunk { src, dst } = &diffs[0];
+    assert_eq!(src.file.deref(), "main.c");
+    assert_eq!(src.op, Op::Sub);
+    assert_eq!(src.line, 1);
+    assert_eq!(src.count, 1);
+
+    assert_eq!(dst.file.deref(), "/dev/null");
+    assert_eq!(dst.op, Op::Add);
+    assert_eq!(dst.line, 0);
+    assert_eq!(dst.count, 0);
+  }
+
+  /// Verify that we can parse a diff containing an empty line.
+  #[test]
+  fn parse_diff_with_empty_line() {
+    let diff = r#"
+--- main.c
++++ main.c
+@@ -1,6 +1,6 @@
+ #include <stdio.h>
+ 
+-int main(int argc, char const* argv[])
++int main(int argc, char* argv[])
+ {
+   if (argc > 1) {
+     fprintf(stderr, "Too many arguments.\n");"#;
+
+    let mut parser = Parser::new();
+    let () = parser.parse(diff.as_bytes()).unwrap();
+
+    let diffs = parser.diffs();
+    assert_eq!(diffs.len(), 1);
+
+    let Hunk { src, dst } = &diffs[0];
+    assert_eq!(src.file.deref(), "main.c");
+    assert_eq!(src.op, Op::Sub);
+    assert_eq!(src.line, 1);
+    assert_eq!(src.count, 6);
+
+    assert_eq!(dst.file.deref(), "main.c");
+    assert_eq!(dst.op, Op::Add);
+    assert_eq!(dst.line, 1);
+    assert_eq!(dst.count, 6);
+  }
+
+  /// Check that an empty line inside of a hunk is interpreted as a
+  /// context line whose trailing white space got stripped.
+  #[test]
+  fn parse_diff_with_stripped_context_line() {
+    let diff = concat!(
+      "--- main.c\n+++ main.c\n@@ -1,3 +1,3 @@\n",
+      " #include <stdio.h>\n\n-int main()\n+int main(void)\n\n",
+    );
+
+    let mut parser = Parser::new();
+    let () = parser.parse(diff.as_bytes()).unwrap();
+
+    let diffs = parser.diffs();
+    assert_eq!(diffs.len(), 1);
+    let Hunk { src, dst } = &diffs[0];
+    assert_eq!(src.line, 1);
+    assert_eq!(src.count, 3);
+    assert_eq!(src.leading_context, 2);
+    assert_eq!(src.content[1].text, "");
+    assert_eq!(dst.count, 3);
+    assert_eq!(dst.content.len(), 3);
+  }
+
+  /// Check that the last line of the input is parsed even if it is not
+  /// terminated by a newline symbol.
+  #[test]
+  fn parse_diff_without_trailing_newline() {
+    let diff = "--- main.c\n+++ main.c\n@@ -6 +6 @@\n-a\n+b";
+
+    let mut parser = Parser::new();
+    let () = parser.parse(diff.as_bytes()).unwrap();
+
+    let diffs = parser.diffs();
+    assert_eq!(diffs.len(), 1);
+
+    let Hunk { src, dst } = &diffs[0];
+    assert_eq!(src.file.deref(), "main.c");
+    assert_eq!(src.line, 6);
+    assert_eq!(src.count, 1);
+    assert_eq!(dst.line, 6);
+    assert_eq!(dst.count, 1);
+  }
+
+  /// Check that input is parsed correctly when the reader hands it out
+  /// in small chunks, with the last line lacking a trailing newline.
+  #[test]
+  fn parse_diff_in_chunks_without_trailing_newline() {
+    let diff = concat!(
+      "diff --git main.c main.c\n",
+      "--- main.c\n",
+      "+++ main.c\n",
+      "@@ -1,2 +1,2 @@\n",
+      "-a\n",
+      "+b\n",
+      " c\n",
+      "diff --git foo.c foo.c\n",
+      "--- foo.c\n",
+      "+++ foo.c\n",
+      "@@ -3 +3 @@\n",
+      "-d\n",
+      "+e",
+    );
+
+    let mut parser = Parser::new();
+    let reader = BufReader::with_capacity(3, diff.as_bytes());
+    let () = parser.parse(reader).unwrap();
+
+    let diffs = parser.diffs();
+    assert_eq!(diffs.len(), 2);
+
+    let Hunk { src, .. } = &diffs[1];
+    assert_eq!(src.file.deref(), "foo.c");
+    assert_eq!(src.line, 3);
+    assert_eq!(src.count, 1);
+  }
+
+  /// Check that we keep track of the context lines surrounding the
+  /// changed lines of a hunk.
+  #[test]
+  fn parse_diff_context_lines() {
+    let diff = r#"
+--- main.c
++++ main.c
+@@ -6,6 +6,6 @@ int main(int argc, char const* argv[])
+     fprintf(stderr, "Too many arguments.\n");
+     return -1;
+   }
+-  printf("Hello world!");
++  printf("Hello world!\n");
+   return 0;
+-}
++}
+\\ No newline at end of file
+@@ -20,2 +20,3 @@
++/* EOF */
+ 
+ "#;
+
+    let mut parser = Parser::new();
+    let () = parser.parse(diff.as_bytes()).unwrap();
+
+    let diffs = parser.diffs();
+    assert_eq!(diffs.len(), 2);
+
+    let Hunk { src, dst } = &diffs[0];
+    assert_eq!(src.leading_context, 3);
+    assert_eq!(src.trailing_context, 0);
+    assert_eq!(dst.leading_context, 3);
+    assert_eq!(dst.trailing_context, 0);
+
+    let Hunk { src, dst } = &diffs[1];
+    assert_eq!(src.leading_context, 0);
+    assert_eq!(src.trailing_context, 2);
+    assert_eq!(dst.leading_context, 0);
+    assert_eq!(dst.trailing_context, 2);
+  }
+
+  /// Check that we capture the content lines of hunks and detect
+  /// hunks only changing whitespace.
+  #[test]
+  fn parse_diff_content_lines() {
+    let diff = r#"
+--- main.c
++++ main.c
+@@ -1,3 +1,3 @@
+ int main() {
+-  return 0;
++    return  0;
+ }
+@@ -10,2 +10,2 @@
+-int foo;
++int bar;
+ int baz;
+"#;
+
+    let mut parser = Parser::new();
+    let () = parser.parse(diff.as_bytes()).unwrap();
+
+    let diffs = parser.diffs();
+    assert_eq!(diffs.len(), 2);
+
+    let line = |op, text: &str| DiffLine {
+      op,
+      text: text.to_string(),
+    };
+    let Hunk { src, dst } = &diffs[0];
+    assert_eq!(
+      src.content,
+      [
+        line(None, "int main() {"),
+        line(Some(Op::Sub), "  return 0;"),
+        line(None, "}"),
+      ]
+    );
+    assert_eq!(
+      dst.content,
+      [
+        line(None, "int main() {"),
+        line(Some(Op::Add), "    return  0;"),
+        line(None, "}"),
+      ]
+    );
+    assert!(is_whitespace_only(&diffs[0]));
+    assert!(!is_whitespace_only(&diffs[1]));
+
+    let filtered = filter_whitespace_hunks(diffs);
+    assert_eq!(filtered.len(), 1);
+    assert_eq!(filtered[0].src.line, 10);
+  }
+
+  /// Check that we can swap the sides of a hunk.
+  #[test]
+  fn swap_hunk() {
+    let diff = r#"
+--- main.c
++++ main.c
+@@ -10,2 +11,3 @@
+-int foo;
++int bar;
++int qux;
+ int baz;
+"#;
+
+    let mut parser = Parser::new();
+    let () = parser.parse(diff.as_bytes()).unwrap();
+
+    let Hunk { src, dst } = parser.diffs()[0].swap();
+    assert_eq!(src.op, Op::Sub);
+    assert_eq!(src.line, 11);
+    assert_eq!(src.count, 3);
+    assert_eq!(dst.op, Op::Add);
+    assert_eq!(dst.line, 10);
+    assert_eq!(dst.count, 2);
+    assert_eq!(
+      src.content,
+      [
+        DiffLine {
+          op: Some(Op::Sub),
+          text: "int bar;".to_string(),
+        },
+        DiffLine {
+          op: Some(Op::Sub),
+          text: "int qux;".to_string(),
+        },
+        DiffLine {
+          op: None,
+          text: "int baz;".to_string(),
+        },
+      ]
+    );
+  }
+
+  /// Check that we can parse a diff modifying a file as produced by a
+  /// plain `git diff`.
+  #[test]
+  fn parse_git_diff_modification() {
+    let diff = r#"diff --git a/src/main.c b/src/main.c
+index 4f3b2a1..9e8c7d6 100644
+--- a/src/main.c
++++ b/src/main.c
+@@ -6 +6 @@ int main(int argc, char const* argv[])
+-  printf("Hello world!");
++  printf("Hello world!\n");
+"#;
+
+    let mut parser = Parser::new();
+    let () = parser.parse(diff.as_bytes()).unwrap();
+
+    let diffs = parser.diffs();
+    assert_eq!(diffs.len(), 1);
+
+    let Hunk { src, dst } = &diffs[0];
+    assert_eq!(src.file.deref(), "src/main.c");
+    assert_eq!(src.line, 6);
+    assert_eq!(src.count, 1);
+    assert_eq!(dst.file.deref(), "src/main.c");
+    assert_eq!(dst.line, 6);
+    assert_eq!(dst.count, 1);
+  }
+
+  /// Check that we can parse a `git diff` adding lines to multiple
+  /// files.
+  #[test]
+  fn parse_git_diff_addition() {
+    let diff = r#"diff --git a/foo.c b/foo.c
+index 4f3b2a1..9e8c7d6 100644
+--- a/foo.c
++++ b/foo.c
+@@ -1,0 +2,2 @@ int foo;
++int bar;
++int baz;
+diff --git a/bar.c b/bar.c
+index 1a2b3c4..5d6e7f8 100644
+--- a/bar.c
++++ b/bar.c
+@@ -3,0 +4 @@ int bar;
++int qux;
+"#;
+
+    let mut parser = Parser::new();
+    let () = parser.parse(diff.as_bytes()).unwrap();
+
+    let diffs = parser.diffs();
+    assert_eq!(diffs.len(), 2);
+
+    let Hunk { src, dst } = &diffs[0];
+    assert_eq!(src.file.deref(), "foo.c");
+    assert_eq!(src.count, 0);
+    assert_eq!(src.func_context.as_deref(), Some("int foo;"));
+    assert_eq!(dst.file.deref(), "foo.c");
+    assert_eq!(dst.line, 2);
+    assert_eq!(dst.count, 2);
+
+    let Hunk { src, dst } = &diffs[1];
+    assert_eq!(src.file.deref(), "bar.c");
+    assert_eq!(dst.file.deref(), "bar.c");
+    assert_eq!(dst.line, 4);
+    assert_eq!(dst.count, 1);
+  }
+
+  /// Check that we can parse a `git diff` creating a new file.
+  #[test]
+  fn parse_git_diff_new_file() {
+    let diff = r#"diff --git a/main.c b/main.c
+new file mode 100644
+index 0000000..4f3b2a1
+--- /dev/null
++++ b/main.c
+@@ -0,0 +1 @@
++int main;
+"#;
+
+    let mut parser = Parser::new();
+    let () = parser.parse(diff.as_bytes()).unwrap();
+
+    let diffs = parser.diffs();
+    assert_eq!(diffs.len(), 1);
+
+    let Hunk { src, dst } = &diffs[0];
+    assert_eq!(src.file.deref(), "/dev/null");
+    assert_eq!(src.count, 0);
+    assert_eq!(dst.file.deref(), "main.c");
+    assert_eq!(dst.count, 1);
+  }
+
+  /// Check that we can parse a `git diff` deleting a file.
+  #[test]
+  fn parse_git_diff_deletion() {
+    let diff = r#"diff --git a/main.c b/main.c
+deleted file mode 100644
+index 4f3b2a1..0000000
+--- a/main.c
++++ /dev/null
+@@ -1,2 +0,0 @@
+-int main;
+-int foo;
+"#;
+
+    let mut parser = Parser::new();
+    let () = parser.parse(diff.as_bytes()).unwrap();
+
+    let diffs = parser.diffs();
+    assert_eq!(diffs.len(), 1);
+
+    let Hunk { src, dst } = &diffs[0];
+    assert_eq!(src.file.deref(), "main.c");
+    assert_eq!(src.count, 2);
+    assert_eq!(dst.file.deref(), "/dev/null");
+    assert_eq!(dst.count, 0);
+  }
+
+  /// Check that a pure rename, without content changes, does not
+  /// result in any diffs.
+  #[test]
+  fn parse_git_diff_pure_rename() {
+    let diff = r#"diff --git a/old.c b/new.c
+similarity index 100%
+rename from old.c
+rename to new.c
+"#;
+
+    let mut parser = Parser::new();
+    let () = parser.parse(diff.as_bytes()).unwrap();
+    assert!(parser.diffs().is_empty());
+  }
+
+  /// Check that the original name of a renamed file is recorded.
+  #[test]
+  fn parse_git_diff_rename() {
+    let diff = r#"diff --git a/src/old.c b/src/new.c
+similarity index 90%
+rename from src/old.c
+rename to src/new.c
+index 4f3b2a1..9e8c7d6 100644
+--- a/src/old.c
++++ b/src/new.c
+@@ -1 +1 @@
+-int main;
++int main();
+@@ -8,2 +8 @@
+-int foo;
+-int bar;
++int baz;
+diff --git a/main.c b/main.c
+index 1a2b3c4..5d6e7f8 100644
+--- a/main.c
++++ b/main.c
+@@ -1 +1 @@
+-int main;
++int main();
+"#;
+
+    let mut parser = Parser::new();
+    let () = parser.parse(diff.as_bytes()).unwrap();
+
+    let diffs = parser.diffs();
+    assert_eq!(diffs.len(), 3);
+
+    for Hunk { src, dst } in &diffs[0..2] {
+      assert_eq!(src.file.deref(), "src/old.c");
+      assert_eq!(src.original_file.as_deref().unwrap(), "src/old.c");
+      assert_eq!(src.blame_file(), "src/old.c");
+      assert_eq!(dst.file.deref(), "src/new.c");
+      assert_eq!(dst.original_file, None);
+    }
+
+    let Hunk { src, .. } = &diffs[2];
+    assert_eq!(src.original_file, None);
+    assert_eq!(src.blame_file(), "main.c");
+  }
+
+  /// Check that prefixes are not stripped from diffs created with
+  /// `--no-prefix`, even if files reside in a directory named `a`, and
+  /// that file headers without hunks are skipped over.
+  #[test]
+  fn parse_git_diff_without_prefix() {
+    let diff = r#"diff --git a/main.c a/main.c
+index 4f3b2a1..9e8c7d6 100644
+--- a/main.c
++++ a/main.c
+@@ -1 +1 @@
+-int main;
++int main();
+diff --git b/image.png b/image.png
+index 1a2b3c4..5d6e7f8 100644
+Binary files b/image.png and b/image.png differ
+diff --git main.c main.c
+index 4f3b2a1..9e8c7d6 100644
+--- main.c
++++ main.c
+@@ -1 +1 @@
+-int main;
++int main();
+"#;
+
+    let mut parser = Parser::new();
+    let () = parser.parse(diff.as_bytes()).unwrap();
+
+    let diffs = parser.diffs();
+    assert_eq!(diffs.len(), 2);
+
+    let Hunk { src, dst } = &diffs[0];
+    assert_eq!(src.file.deref(), "a/main.c");
+    assert_eq!(dst.file.deref(), "a/main.c");
+
+    let Hunk { src, dst } = &diffs[1];
+    assert_eq!(src.file.deref(), "main.c");
+    assert_eq!(dst.file.deref(), "main.c");
+  }
+
+  /// Check that `a/` and `b/` prefixes are stripped from file names
+  /// if requested.
+  #[test]
+  fn parse_diff_stripping_prefixes() {
+    let diff = r#"--- a/src/main.rs
++++ b/src/main.rs
+@@ -1 +1 @@
+-fn main() {}
++fn main() { }
+--- a/b/lib.rs
++++ b/b/lib.rs
+@@ -1 +1 @@
+-fn foo() {}
++fn foo() { }
+--- a/main.c
++++ /dev/null
+@@ -1 +0,0 @@
+-int main;
+"#;
+
+    let mut parser = Parser::new();
+    let () = parser.parse(diff.as_bytes()).unwrap();
+    let diffs = parser.diffs();
+    assert_eq!(diffs[0].src.file.deref(), "a/src/main.rs");
+    assert_eq!(diffs[0].dst.file.deref(), "b/src/main.rs");
+
+    let options = ParserOptions {
+      strip_ab_prefix: true,
+      ..Default::default()
+    };
+    let mut parser = Parser::with_options(options);
+    let () = parser.parse(diff.as_bytes()).unwrap();
+
+    let diffs = parser.diffs();
+    assert_eq!(diffs.len(), 3);
+    assert_eq!(diffs[0].src.file.deref(), "src/main.rs");
+    assert_eq!(diffs[0].dst.file.deref(), "src/main.rs");
+    // Only a single prefix is removed.
+    assert_eq!(diffs[1].src.file.deref(), "b/lib.rs");
+    assert_eq!(diffs[1].dst.file.deref(), "b/lib.rs");
+    assert_eq!(diffs[2].src.file.deref(), "main.c");
+    assert_eq!(diffs[2].dst.file.deref(), "/dev/null");
+  }
+
+  /// Check that we can parse diffs as produced by POSIX `diff -u`,
+  /// with modification times following file names.
+  #[test]
+  fn parse_posix_diff() {
+    let diff = concat!(
+      "--- main.c\t2026-10-15 10:42:23.000000000 +0200\n",
+      "+++ main.c\t2026-10-15 10:43:01.123456789 +0200\n",
+      "@@ -1 +1 @@\n",
+      "-int main;\n",
+      "+int main();\n",
+      "--- my file.c 2026-10-15 10:42:23 +0200\n",
+      "+++ my file.c 2026-10-15 10:43:01 +0200\n",
+      "@@ -1 +1 @@\n",
+      "-int foo;\n",
+      "+int foo();\n",
+      "--- foo.c Thu Oct  1 10:42:23 2026\n",
+      "+++ foo.c Thu Oct 15 10:43:01 2026\n",
+      "@@ -1 +1 @@\n",
+      "-int bar;\n",
+      "+int bar();\n",
+    );
+
+    let options = ParserOptions {
+      diff_format: DiffFormat::Posix,
+      ..Default::default()
+    };
+    let mut parser = Parser::with_options(options);
+    let () = parser.parse(diff.as_bytes()).unwrap();
+
+    let files = parser
+      .diffs()
+      .iter()
+      .map(|Hunk { src, dst }| (src.file.deref().as_str(), dst.file.deref().as_str()))
+      .collect::<Vec<_>>();
+    assert_eq!(
+      files,
+      [
+        ("main.c", "main.c"),
+        ("my file.c", "my file.c"),
+        ("foo.c", "foo.c")
+      ]
+    );
+
+    // In git format only tab-separated suffixes are ignored.
+    let mut parser = Parser::new();
+    let () = parser.parse(diff.as_bytes()).unwrap();
+    let diffs = parser.diffs();
+    assert_eq!(diffs[0].src.file.deref(), "main.c");
+    assert_eq!(
+      diffs[1].src.file.deref(),
+      "my file.c 2026-10-15 10:42:23 +0200"
+    );
+  }
+
+  /// Check that meta data lines in file headers are skipped over,
+  /// irrespective of where in the header they appear.
+  #[test]
+  fn parse_git_diff_meta_data() {
+    let metas = [
+      "index 8aef42..1f3c00 100644",
+      "new file mode 100644",
+      "deleted file mode 100644",
+      "old mode 100755",
+      "new mode 100644",
+      "similarity index 95%",
+      "dissimilarity index 60%",
+    ];
+
+    let check = |diff: &str| {
+      let mut parser = Parser::new();
+      let () = parser.parse(diff.as_bytes()).unwrap();
+
+      let diffs = parser.diffs();
+      assert_eq!(diffs.len(), 1, "{diff}");
+
+      let Hunk { src, dst } = &diffs[0];
+      assert_eq!(src.file.deref(), "main.c");
+      assert_eq!(src.line, 6);
+      assert_eq!(src.count, 1);
+      assert_eq!(dst.file.deref(), "main.c");
+    };
+
+    for meta in metas {
+      let () = check(&format!(
+        "diff --git a/main.c b/main.c\n{meta}\n--- a/main.c\n+++ b/main.c\n@@ -6 +6 @@\n-a\n+b\n"
+      ));
+      let () = check(&format!(
+        "diff --git a/main.c b/main.c\n--- a/main.c\n{meta}\n+++ b/main.c\n@@ -6 +6 @@\n-a\n+b\n"
+      ));
+      let () = check(&format!(
+        "diff --git a/main.c b/main.c\n--- a/main.c\n+++ b/main.c\n{meta}\n@@ -6 +6 @@\n-a\n+b\n"
+      ));
+    }
+
+    let metas = metas.join("\n");
+    let () = check(&format!(
+      concat!(
+        "diff --git a/main.c b/main.c\n{metas}\n",
+        "--- a/main.c\n{metas}\n",
+        "+++ b/main.c\n{metas}\n",
+        "@@ -6 +6 @@\n-a\n+b\n",
+      ),
+      metas = metas,
+    ));
+  }
+
+  /// Check that binary sections in diffs are skipped over.
+  #[test]
+  fn parse_diff_with_binary_files() {
+    let diff = r#"diff --git a/img.png b/img.png
+index 6cc1f0a..fb51185 100644
+GIT binary patch
+literal 300
+zcmV+{0n`3z&`QUVNyvxrp;yYJKXDo}$&X=trKkbk_3xb`)+x_d^iHIhV-ajLHhH?F
+yX2zG<Z-!N3xIPaRV;gcnh!;Pf;bw*<Q~Q*(>2E8CGr<V*a8hB*Z&#S?L!%GtESIbR
+
+literal 300
+zcmV+{0n`2mj$yR$Mg_4@4*U&|j@T
+
+diff --git a/main.c b/main.c
+index 4f3b2a1..9e8c7d6 100644
+--- a/main.c
++++ b/main.c
+@@ -1 +1 @@
+-int main;
++int main();
+diff --git a/logo.png b/logo.png
+index 1a2b3c4..5d6e7f8 100644
+Binary files a/logo.png and b/logo.png differ
+--- foo.c
++++ foo.c
+@@ -3 +3 @@
+-int foo;
++int bar;
+Binary files old/icon.png and new/icon.png differ
+"#;
+
+    let mut parser = Parser::new();
+    let () = parser.parse(diff.as_bytes()).unwrap();
+
+    let diffs = parser.diffs();
+    assert_eq!(diffs.len(), 2);
+
+    let Hunk { src, dst } = &diffs[0];
+    assert_eq!(src.file.deref(), "main.c");
+    assert_eq!(dst.file.deref(), "main.c");
+
+    let Hunk { src, dst } = &diffs[1];
+    assert_eq!(src.file.deref(), "foo.c");
+    assert_eq!(src.line, 3);
+    assert_eq!(dst.file.deref(), "foo.c");
+  }
+
+  /// Check that we can parse a combined diff, as produced by
+  /// `git show` for merge commits.
+  #[test]
+  fn parse_combined_diff() {
+    let diff = r#"commit f670df822552439414af2a7d8b84fa2bbc90deaf
+Merge: f231cdd d02e2e9
+Author: nobody <nobody@example.com>
+Date:   Thu Oct 15 11:53:54 2026 +0000
+
+    Merge branch 'side'
+
+diff --cc main.c
+index 03f1858,cd85c7d..d00ddb0
+--- a/main.c
++++ b/main.c
+@@@ -1,5 -1,5 +1,5 @@@ int main()
+  1
+- main
+ -side
+++merged
+  3
+ +4
+--5
+++five
+"#;
+
+    let mut parser = Parser::new();
+    let () = parser.parse(diff.as_bytes()).unwrap();
+
+    let diffs = parser.diffs();
+    assert_eq!(diffs.len(), 1);
+
+    let Hunk { src, dst } = &diffs[0];
+    assert_eq!(src.file.deref(), "main.c");
+    assert_eq!(src.op, Op::Sub);
+    assert_eq!(src.line, 1);
+    assert_eq!(src.count, 5);
+    assert_eq!(src.func_context.as_deref(), Some("int main()"));
+    assert_eq!(dst.file.deref(), "main.c");
+    assert_eq!(dst.op, Op::Add);
+    assert_eq!(dst.line, 1);
+    assert_eq!(dst.count, 5);
+
+    // The diff is reduced to one against the first parent.
+    let text = |file: &File| {
+      file
+        .content
+        .iter()
+        .map(|line| line.text.clone())
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(text(src), ["1", "main", "3", "4", "5"]);
+    assert_eq!(text(dst), ["1", "merged", "3", "4", "five"]);
+    assert_eq!(src.leading_context, 1);
+    assert_eq!(src.trailing_context, 0);
+  }
+
+  /// Check that we can parse diffs with CRLF line endings.
+  #[test]
+  fn parse_diff_with_crlf_line_endings() {
+    let diff = concat!(
+      "diff --git a/main.c b/main.c\r\n",
+      "index 4f3b2a1..9e8c7d6 100644\r\n",
+      "--- a/main.c\r\n",
+      "+++ b/main.c\r\n",
+      "@@ -1,2 +1,2 @@\r\n",
+      "-int main;\r\n",
+      "+int main();\r\n",
+      " int foo;\r\n",
+      "diff --git a/my file.c b/my file.c\r\n",
+      "rename from old file.c\r\n",
+      "rename to my file.c\r\n",
+      "--- a/old file.c\t\r\n",
+      "+++ b/my file.c\t\r\n",
+      "@@ -3 +3 @@\r\n",
+      "-int bar;\r\n",
+      "+int baz;\r\n",
+    );
+
+    let mut parser = Parser::new();
+    let () = parser.parse(diff.as_bytes()).unwrap();
+
+    let diffs = parser.diffs();
+    assert_eq!(diffs.len(), 2);
+
+    let Hunk { src, dst } = &diffs[0];
+    assert_eq!(src.file.deref(), "main.c");
+    assert_eq!(src.count, 2);
+    assert_eq!(src.trailing_context, 1);
+    assert_eq!(dst.file.deref(), "main.c");
+
+    let Hunk { src, dst } = &diffs[1];
+    assert_eq!(src.file.deref(), "old file.c");
+    assert_eq!(src.blame_file(), "old file.c");
+    assert_eq!(src.line, 3);
+    assert_eq!(dst.file.deref(), "my file.c");
+  }
+
+  /// Check that file names quoted by git are unquoted correctly.
+  #[test]
+  fn unquoting() {
+    assert_eq!(unquote("main.c"), "main.c");
+    assert_eq!(unquote("my component.rs"), "my component.rs");
+    assert_eq!(unquote(r#""my component.rs""#), "my component.rs");
+    assert_eq!(unquote(r#""a/q\"x.c""#), r#"a/q"x.c"#);
+    assert_eq!(unquote(r#""a/b\\s.c""#), r"a/b\s.c");
+    assert_eq!(unquote(r#""new\nline\ttab.c""#), "new\nline\ttab.c");
+    assert_eq!(unquote(r#""caf\303\251.c""#), "café.c");
+    assert_eq!(unquote(r#""""#), "");
+    // A lone quote is not a quoted name.
+    assert_eq!(unquote(r#"""#), r#"""#);
+  }
+
+  /// Check that we can parse diffs of files with names containing
+  /// spaces or other special characters.
+  #[test]
+  fn parse_git_diff_special_file_names() {
+    let diff = concat!(
+      "diff --git a/my component.rs b/my component.rs\n",
+      "index 4f3b2a1..9e8c7d6 100644\n",
+      "--- a/my component.rs\t\n",
+      "+++ b/my component.rs\t\n",
+      "@@ -1 +1 @@\n",
+      "-int main;\n",
+      "+int main();\n",
+      "diff --git \"a/q\\\"x.c\" \"b/q\\\"x.c\"\n",
+      "--- \"a/q\\\"x.c\"\n",
+      "+++ \"b/q\\\"x.c\"\n",
+      "@@ -1 +1 @@\n",
+      "-int main;\n",
+      "+int main();\n",
+      "diff --git \"a/back\\\\slash.c\" \"b/back\\\\slash.c\"\n",
+      "--- \"a/back\\\\slash.c\"\n",
+      "+++ \"b/back\\\\slash.c\"\n",
+      "@@ -1 +1 @@\n",
+      "-int main;\n",
+      "+int main();\n",
+      "diff --git \"a/path/with spaces/\\\"f\\\\o\\to\\\".c\" ",
+      "\"b/path/with spaces/\\\"f\\\\o\\to\\\".c\"\n",
+      "--- \"a/path/with spaces/\\\"f\\\\o\\to\\\".c\"\n",
+      "+++ \"b/path/with spaces/\\\"f\\\\o\\to\\\".c\"\n",
+      "@@ -1 +1 @@\n",
+      "-int main;\n",
+      "+int main();\n",
+    );
+
+    let mut parser = Parser::new();
+    let () = parser.parse(diff.as_bytes()).unwrap();
+
+    let diffs = parser.diffs();
+    let names = diffs
+      .iter()
+      .map(|Hunk { src, dst }| {
+        assert_eq!(src.file, dst.file);
+        src.file.deref().clone()
+      })
+      .collect::<Vec<_>>();
+    assert_eq!(
+      names,
+      [
+        "my component.rs",
+        r#"q"x.c"#,
+        r"back\slash.c",
+        "path/with spaces/\"f\\o\to\".c",
+      ]
+    );
+  }
+
+  /// Check that truncated hunks are detected.
+  #[test]
+  fn parse_truncated_diff() {
+    let check = |diff: &str, expected_line: usize, expected_content: &str| {
+      let mut parser = Parser::new();
+      let error = parser.parse(diff.as_bytes()).unwrap_err();
+      match error {
+        Error::Parse {
+          line_number,
+          content,
+          state,
+        } => {
+          assert_eq!(line_number, expected_line, "{diff}");
+          assert_eq!(content, expected_content, "{diff}");
+          assert!(state.starts_with("Hdr"), "{state}");
+        },
+        error => panic!("unexpected error: {error}"),
+      }
+
+      let mut parser = Parser::new_lenient();
+      let () = parser.parse(diff.as_bytes()).unwrap();
+      assert!(!parser.diffs().is_empty());
+    };
+
+    // Truncated at the end of the input.
+    let diff = "--- main.c\n+++ main.c\n@@ -1,3 +1,3 @@\n a\n-b\n+c\n";
+    let () = check(diff, 7, "");
+
+    // Truncated before the next hunk.
+    let diff = "--- main.c\n+++ main.c\n@@ -1,2 +1,3 @@\n a\n+b\n@@ -8 +9 @@\n-d\n+e\n";
+    let () = check(diff, 6, "@@ -8 +9 @@");
+
+    // Truncated before the next file.
+    let diff = concat!(
+      "diff --git a/main.c b/main.c\n",
+      "--- a/main.c\n",
+      "+++ b/main.c\n",
+      "@@ -1,2 +1,2 @@\n",
+      "-a\n",
+      "+b\n",
+      "diff --git a/foo.c b/foo.c\n",
+    );
+    let () = check(diff, 7, "diff --git a/foo.c b/foo.c");
+  }
+
+  /// Check that hunks containing more lines than announced by their
+  /// headers are detected.
+  #[test]
+  fn parse_diff_with_off_by_one_count() {
+    let diff = "--- main.c\n+++ main.c\n@@ -1,2 +1,2 @@\n a\n-b\n+c\n d\n";
+
+    let mut parser = Parser::new();
+    let error = parser.parse(diff.as_bytes()).unwrap_err();
+    match error {
+      Error::Parse {
+        line_number,
+        content,
+        ..
+      } => {
+        assert_eq!(line_number, 7);
+        assert_eq!(content, " d");
+      },
+      error => panic!("unexpected error: {error}"),
+    }
+
+    let mut parser = Parser::new_lenient();
+    let () = parser.parse(diff.as_bytes()).unwrap();
+
+    let diffs = parser.diffs();
+    assert_eq!(diffs.len(), 1);
+    let Hunk { src, .. } = &diffs[0];
+    assert_eq!(src.count, 2);
+    assert_eq!(src.leading_context, 1);
+    assert_eq!(src.trailing_context, 1);
+  }
+
+  /// Check that removed lines looking like file headers are treated as
+  /// part of the hunk, as long as the hunk header announced them.
+  #[test]
+  fn parse_diff_with_header_like_lines() {
+    let diff = concat!(
+      "--- main.lua\n",
+      "+++ main.lua\n",
+      "@@ -1,2 +1 @@\n",
+      "--- a comment\n",
+      " x = 1\n",
+      "--- foo.lua\n",
+      "+++ foo.lua\n",
+      "@@ -4 +4 @@\n",
+      "-y = 1\n",
+      "+y = 2\n",
+    );
+
+    let mut parser = Parser::new();
+    let () = parser.parse(diff.as_bytes()).unwrap();
+
+    let diffs = parser.diffs();
+    assert_eq!(diffs.len(), 2);
+
+    let Hunk { src, .. } = &diffs[0];
+    assert_eq!(src.file.deref(), "main.lua");
+    assert_eq!(src.trailing_context, 1);
+
+    let Hunk { src, dst } = &diffs[1];
+    assert_eq!(src.file.deref(), "foo.lua");
+    assert_eq!(src.line, 4);
+    assert_eq!(dst.file.deref(), "foo.lua");
+  }
+
+  /// Check that a reset parser behaves just like a new one.
+  #[test]
+  fn reset_parser() {
+    let first = "--- main.c\n+++ main.c\n@@ -1,2 +1,2 @@\n a\n-b\n+c\n";
+    let second = "--- foo.c\n+++ foo.c\n@@ -4 +4 @@\n-d\n+e\n";
+
+    let mut parser = Parser::with_capacity(16);
+    let () = parser.parse(first.as_bytes()).unwrap();
+    assert_eq!(parser.diffs().len(), 1);
+
+    let () = parser.reset();
+    assert!(parser.diffs().is_empty());
+    assert!(parser.diffs.capacity() >= 16);
+
+    let () = parser.parse(second.as_bytes()).unwrap();
+    let diffs = parser.diffs();
+    assert_eq!(diffs.len(), 1);
+
+    let Hunk { src, dst } = &diffs[0];
+    assert_eq!(src.file.deref(), "foo.c");
+    assert_eq!(src.line, 4);
+    assert_eq!(src.count, 1);
+    assert_eq!(dst.file.deref(), "foo.c");
+
+    // A parser stuck in the middle of a file header is reset as well,
+    // lest the second diff fail to parse.
+    let () = parser.reset();
+    let () = parser.parse("--- main.c\n".as_bytes()).unwrap();
+    let () = parser.reset();
+    let () = parser.parse(second.as_bytes()).unwrap();
+    assert_eq!(parser.diffs().len(), 1);
+    assert_eq!(parser.diffs()[0].src.file.deref(), "foo.c");
+  }
+
+  /// Check that parse errors report the offending line.
+  #[test]
+  fn parse_error() {
+    let diff = "--- main.c\n+++ main.c\n\nfoobar\n";
+
+    let mut parser = Parser::new();
+    let error = parser.parse(diff.as_bytes()).unwrap_err();
+    match error {
+      Error::Parse {
+        line_number,
+        content,
+        state,
+      } => {
+        assert_eq!(line_number, 4);
+        assert_eq!(content, "foobar");
+        assert!(state.starts_with("Dst"), "{state}");
+      },
+      error => panic!("unexpected error: {error}"),
+    }
+  }
+
+  /// Check that line numbers reported in parse errors refer to the
+  /// input as a whole when it is parsed in chunks.
+  #[test]
+  fn parse_error_in_chunks() {
+    let first = r#"--- main.c
++++ main.c
+@@ -1,2 +1,2 @@
+-int main() {
++int main(void) {
+ }
+"#;
+    let second = r#"--- foo.c
+foobar
+"#;
+
+    let mut parser = Parser::new();
+    let () = parser.parse(first.as_bytes()).unwrap();
+    let error = parser.parse(second.as_bytes()).unwrap_err();
+    match error {
+      Error::Parse {
+        line_number,
+        content,
+        ..
+      } => {
+        assert_eq!(line_number, 8);
+        assert_eq!(content, "foobar");
+      },
+      error => panic!("unexpected error: {error}"),
+    }
+
+    // After a reset numbering starts over.
+    let () = parser.reset();
+    let error = parser.parse(second.as_bytes()).unwrap_err();
+    assert!(
+      matches!(error, Error::Parse { line_number: 2, .. }),
+      "{error}"
+    );
+  }
+
+  /// Check that a lenient parser skips over unexpected lines and
+  /// continues with the next file.
+  #[test]
+  fn parse_lenient_recovery() {
+    let diff = r#"--- main.c
++++ main.c
+Reviewed-by: nobody
+@@ -1 +1 @@
+-int main() {
++int main(void) {
+--- foo.c
++++ foo.c
+@@ -3,2 +3 @@
+-int foo;
+ int bar;
+"#;
+
+    let mut parser = Parser::new();
+    let error = parser.parse(diff.as_bytes()).unwrap_err();
+    assert!(
+      matches!(error, Error::Parse { line_number: 3, .. }),
+      "{error}"
+    );
+
+    let mut parser = Parser::new_lenient();
+    let () = parser.parse(diff.as_bytes()).unwrap();
+
+    let diffs = parser.diffs();
+    assert_eq!(diffs.len(), 1);
+    let Hunk { src, dst } = &diffs[0];
+    assert_eq!(src.file.deref(), "foo.c");
+    assert_eq!(src.line, 3);
+    assert_eq!(src.count, 2);
+    assert_eq!(src.leading_context, 0);
+    assert_eq!(src.trailing_context, 1);
+    assert_eq!(dst.file.deref(), "foo.c");
+    assert_eq!(dst.line, 3);
+    assert_eq!(dst.count, 1);
+  }
+
+  /// Check that a lenient parser does not mistake the header of the
+  /// file following a complete hunk for lines of that hunk.
+  #[test]
+  fn parse_lenient_after_complete_hunk() {
+    let diff = r#"--- m.c
++++ m.c
+@@ -2 +2 @@
+-b
++c
+--- u.c
++++ u.c
+@@ -1 +1 @@
+-x
++z
+"#;
+
+    let mut parser = Parser::new_lenient();
+    let () = parser.parse(diff.as_bytes()).unwrap();
+
+    let diffs = parser.diffs();
+    assert_eq!(diffs.len(), 2);
+    let Hunk { src, dst } = &diffs[0];
+    assert_eq!(src.file.deref(), "m.c");
+    assert_eq!(src.line, 2);
+    assert_eq!(src.content.len(), 1);
+    assert_eq!(dst.content.len(), 1);
+    let Hunk { src, dst } = &diffs[1];
+    assert_eq!(src.file.deref(), "u.c");
+    assert_eq!(src.line, 1);
+    assert_eq!(dst.file.deref(), "u.c");
+  }
+
+  /// Check that we can parse the output of `git range-diff`.
+  #[test]
+  fn parse_range_diff() {
+    let diff = concat!(
+      " 1:  606aa9b ! 1:  cfb3032 change\n",
+      "    @@ main.c: int f()\n",
+      "        d;\n",
+      "        e;\n",
+      "     -  f;\n",
+      "    -+  F;\n",
+      "    ++  FF;\n",
+      "        g;\n",
+      "      }\n",
+      "     \n",
+      "    - ## r.c => s.c ##\n",
+      "    + ## r.c => t.c ##\n",
+      " 2:  1f2e3d4 =  2:  5a6b7c8 identical\n",
+      " 3:  9d8c7b6 <  -:  ------- dropped\n",
+      " -:  ------- >  3:  2274afa added\n",
+      "10:  0a1b2c3 ! 11:  3c4d5e6 numbered\n",
+      "    @@ main.c\n",
+      "     @@ -3,3 +3,3 @@ int f()\n",
+      "        b;\n",
+      "     -  c;\n",
+      "    -+  C;\n",
+      "    ++  CC;\n",
+      "        d;\n",
+    );
+
+    let mut parser = RangeDiffParser::new();
+    let () = parser.parse(diff.as_bytes()).unwrap();
+
+    let pairs = parser.pairs();
+    assert_eq!(pairs.len(), 5);
+    assert_eq!(pairs[0].old.as_deref(), Some("606aa9b"));
+    assert_eq!(pairs[0].new.as_deref(), Some("cfb3032"));
+    assert!(!pairs[0].identical);
+    assert_eq!(pairs[0].subject, "change");
+    assert!(pairs[1].identical);
+    assert_eq!(pairs[2].new, None);
+    assert_eq!(pairs[3].old, None);
+    assert_eq!(pairs[4].subject, "numbered");
+
+    assert_eq!(pairs[0].old_diffs.len(), 1);
+    assert_eq!(pairs[0].new_diffs.len(), 1);
+    let Hunk { src, dst } = &pairs[0].new_diffs[0];
+    assert_eq!(src.file.deref(), "main.c");
+    assert_eq!(src.line, 0);
+    assert_eq!(src.count, 5);
+    assert_eq!(src.func_context.as_deref(), Some("int f()"));
+    assert_eq!(dst.count, 5);
+    assert_eq!(dst.content[2].text, "  FF;");
+    let Hunk { dst, .. } = &pairs[0].old_diffs[0];
+    assert_eq!(dst.content[2].text, "  F;");
+
+    // Line numbers are retained if present.
+    let Hunk { src, dst } = &pairs[4].new_diffs[0];
+    assert_eq!(src.line, 3);
+    assert_eq!(src.count, 3);
+    assert_eq!(dst.line, 3);
+    assert_eq!(dst.content[1].text, "  CC;");
+
+    let commits = |side| {
+      parser
+        .patches(side)
+        .into_iter()
+        .map(|(commit, _diffs)| commit)
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(commits(RangeDiffSide::New), ["cfb3032", "3c4d5e6"]);
+    assert_eq!(commits(RangeDiffSide::Old), ["606aa9b", "0a1b2c3"]);
+    assert_eq!(
+      commits(RangeDiffSide::Both),
+      ["606aa9b", "cfb3032", "0a1b2c3", "3c4d5e6"]
+    );
+
+    let error = RangeDiffParser::new()
+      .parse("--- main.c\n".as_bytes())
+      .unwrap_err();
+    assert!(
+      matches!(error, Error::Parse { line_number: 1, .. }),
+      "{error}"
+    );
+  }
+
+  /// Check that diffs lacking line numbers can be located in the
+  /// content of the file they belong to.
+  #[test]
+  fn locate_diff() {
+    let diff = "--- main.c\n+++ main.c\n@@ -0,2 +0,2 @@\n c;\n-d;\n+e;\n";
+    let mut parser = Parser::new();
+    let () = parser.parse(diff.as_bytes()).unwrap();
+    let Hunk { mut src, mut dst } = parser.into_diffs().remove(0);
+
+    assert!(src.locate("a;\nb;\nc;\nd;\nc;\nd;\n"));
+    assert_eq!(src.line, 3);
+    assert!(!dst.locate("a;\nb;\nc;\nd;\n"));
+    assert_eq!(dst.line, 0);
+    assert!(dst.locate("c;\ne;\n"));
+    assert_eq!(dst.line, 1);
+  }
+
+  /// Check that found diffs can be modified and taken out of the
+  /// parser.
+  #[test]
+  fn modify_and_take_diffs() {
+    let diff = r#"--- main.c
++++ main.c
+@@ -1 +1 @@
+-int a;
++int b;
+--- foo.c
++++ foo.c
+@@ -3 +3 @@
+-int c;
++int d;
+--- bar.c
++++ bar.c
+@@ -5 +5 @@
+-int e;
++int f;
+"#;
+
+    let mut parser = Parser::new();
+    let () = parser.parse(diff.as_bytes()).unwrap();
+
+    let files = |diffs: &[Hunk]| {
+      diffs
+        .iter()
+        .map(|Hunk { src, .. }| src.file.to_string())
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(files(parser.diffs()), ["main.c", "foo.c", "bar.c"]);
+
+    let () = parser.diffs_mut().retain(|Hunk { src, .. }| src.line > 1);
+    let () = parser
+      .diffs_mut()
+      .sort_by(|a, b| a.src.file.cmp(&b.src.file));
+    assert_eq!(files(parser.diffs()), ["bar.c", "foo.c"]);
+
+    let diffs = parser.into_diffs();
+    assert_eq!(files(&diffs), ["bar.c", "foo.c"]);
+    assert_eq!(diffs[0].src.line, 5);
+    assert_eq!(diffs[1].dst.line, 3);
+  }
+
+  /// Check that `ParserIter` yields diffs as soon as they are
+  /// complete.
+  #[test]
+  fn iterate_diffs() {
+    /// A reader failing every read.
+    struct Failing;
+
+    impl Read for Failing {
+      fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::other("broken"))
+      }
+    }
+
+    let diff = r#"--- main.c
++++ main.c
+@@ -1,3 +1,3 @@
+ int a;
+-int b;
++int c;
+ int d;
+@@ -10 +10 @@
+-int x;
++int y;
+"#;
+
+    let reader = BufReader::new(diff.as_bytes().chain(Failing));
+    let mut iter = Parser::iter(reader);
+
+    let Hunk { src, dst } = iter.next().unwrap().unwrap();
+    assert_eq!(src.line, 1);
+    assert_eq!(src.count, 3);
+    assert_eq!(src.leading_context, 1);
+    assert_eq!(src.trailing_context, 1);
+    assert_eq!(dst.line, 1);
+
+    // The second diff may still be amended with context lines and so
+    // it is reported only after the error.
+    assert!(iter.next().unwrap().is_err());
+    let Hunk { src, .. } = iter.next().unwrap().unwrap();
+    assert_eq!(src.line, 10);
+    assert!(iter.next().is_none());
+  }
+
+  /// Create a diff covering `count` source lines of `file`, starting at
+  /// `line`.
+  fn diff(file: &str, line: usize, count: usize) -> Hunk {
+    let file = Arc::new(file.to_string());
+    let src = File {
+      file: file.clone(),
+      op: Op::Sub,
+      line,
+      count,
+      leading_context: 0,
+      trailing_context: 0,
+      original_file: None,
+      func_context: None,
+      content: Vec::new(),
+    };
+    let dst = File {
+      file,
+      op: Op::Add,
+      line,
+      count,
+      leading_context: 0,
+      trailing_context: 0,
+      original_file: None,
+      func_context: None,
+      content: Vec::new(),
+    };
+    Hunk { src, dst }
+  }
+
+  /// Check that we classify conflicts between diffs correctly.
+  #[test]
+  fn diff_conflict_kinds() {
+    let a = diff("main.c", 6, 6);
+    let b = diff("foo.c", 6, 6);
+    assert_eq!(conflict_kind(&a, &b), ConflictKind::None);
+
+    let b = diff("main.c", 12, 3);
+    assert_eq!(conflict_kind(&a, &b), ConflictKind::SameFile);
+    assert_eq!(conflict_kind(&b, &a), ConflictKind::SameFile);
+
+    let b = diff("main.c", 11, 3);
+    assert_eq!(conflict_kind(&a, &b), ConflictKind::OverlappingRange);
+    assert_eq!(conflict_kind(&b, &a), ConflictKind::OverlappingRange);
+
+    let a = diff("main.c", 4, 0);
+    let b = diff("main.c", 4, 0);
+    assert_eq!(conflict_kind(&a, &b), ConflictKind::OverlappingRange);
+  }
+
+  /// Check that we find all pairs of conflicting diffs.
+  #[test]
+  fn find_diff_conflicts() {
+    let diffs = [
+      diff("main.c", 1, 5),
+      diff("foo.c", 1, 5),
+      diff("main.c", 10, 5),
+      diff("main.c", 4, 2),
+      diff("foo.c", 20, 1),
+      diff("main.c", 12, 1),
+    ];
+    assert_eq!(find_conflicts(&diffs), [(0, 3), (2, 5)]);
+    assert_eq!(find_conflicts(&diffs[..3]), []);
+  }
+
+  /// Check that we can fold over diffs on a per-file basis.
+  #[test]
+  fn fold_diffs_by_file() {
+    let diff = r#"
+diff --git main.c main.c
+--- main.c
++++ main.c
+@@ -1 +1 @@
+-a
++b
+@@ -5 +5 @@
+-c
++d
+diff --git foo.c foo.c
+--- foo.c
++++ foo.c
+@@ -1 +1 @@
+-a
++b
+@@ -3 +3 @@
+-c
++d
+@@ -9 +9 @@
+-e
++f
+diff --git bar.c bar.c
+--- bar.c
++++ bar.c
+@@ -1 +1 @@
+-a
++b
+"#;
+
+    let mut parser = Parser::new();
+    let () = parser.parse(diff.as_bytes()).unwrap();
+    assert_eq!(parser.diffs().len(), 6);
+
+    let max = parser.fold_files(0, |max, _file, diffs| max.max(diffs.len()));
+    assert_eq!(max, 3);
+
+    let files = parser.fold_files(Vec::new(), |mut files, file, diffs| {
+      let () = files.push((file.to_string(), diffs.len()));
+      files
+    });
+    assert_eq!(
+      files,
+      [
+        ("main.c".to_string(), 2),
+        ("foo.c".to_string(), 3),
+        ("bar.c".to_string(), 1)
+      ]
+    );
+
+    let parser = Parser::new();
+    assert_eq!(parser.fold_files(0, |count, _, _| count + 1), 0);
+  }
+}
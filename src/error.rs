@@ -0,0 +1,154 @@
+// Copyright (C) 2026 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! A module providing the error type used throughout the crate.
+
+use std::error::Error as StdError;
+use std::ffi::OsString;
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::fmt::Result as FmtResult;
+use std::io;
+use std::result;
+
+
+/// A result type using our [`Error`] as error type.
+pub type Result<T, E = Error> = result::Result<T, E>;
+
+
+/// An enumeration of the errors that can occur while annotating diffs.
+#[derive(Debug)]
+pub enum Error {
+  /// A line of a diff could not be parsed.
+  Parse {
+    /// The (one-based) number of the offending line in the input.
+    line_number: usize,
+    /// The content of the offending line.
+    content: String,
+    /// A description of the state the parser was in.
+    state: String,
+  },
+  /// A process exited with a failure.
+  Process {
+    /// The program that failed.
+    program: OsString,
+    /// The error output of the program.
+    stderr: String,
+  },
+  /// A `git blame` process did not finish within the configured
+  /// timeout and got killed.
+  ProcessTimeout {
+    /// The file being annotated.
+    file: String,
+    /// The first line being annotated.
+    line: usize,
+    /// The number of lines being annotated.
+    count: usize,
+  },
+  /// An I/O error occurred.
+  Io(io::Error),
+}
+
+impl Display for Error {
+  fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+    match self {
+      Self::Parse {
+        line_number,
+        content,
+        state,
+      } => write!(
+        f,
+        r#"encountered unexpected line {line_number}: "{content}" (state: {state})"#
+      ),
+      Self::Process { program, stderr } => {
+        write!(f, "process `{}` failed", program.to_string_lossy())?;
+        if !stderr.is_empty() {
+          write!(f, ": {stderr}")?;
+        }
+        Ok(())
+      },
+      Self::ProcessTimeout { file, line, count } => write!(
+        f,
+        "annotating {count} line(s) of {file} starting at line {line} timed out"
+      ),
+      Self::Io(error) => Display::fmt(error, f),
+    }
+  }
+}
+
+impl StdError for Error {
+  fn source(&self) -> Option<&(dyn StdError + 'static)> {
+    match self {
+      Self::Parse { .. } | Self::Process { .. } | Self::ProcessTimeout { .. } => None,
+      Self::Io(error) => error.source(),
+    }
+  }
+}
+
+impl From<io::Error> for Error {
+  #[inline]
+  fn from(error: io::Error) -> Self {
+    Self::Io(error)
+  }
+}
+
+impl From<Error> for io::Error {
+  fn from(error: Error) -> Self {
+    match error {
+      Error::Io(error) => error,
+      error @ Error::Parse { .. } => io::Error::new(io::ErrorKind::InvalidData, error),
+      error @ Error::Process { .. } => io::Error::other(error),
+      error @ Error::ProcessTimeout { .. } => io::Error::new(io::ErrorKind::TimedOut, error),
+    }
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+
+  /// Check that errors are formatted as expected.
+  #[test]
+  fn error_display() {
+    let error = Error::Parse {
+      line_number: 3,
+      content: "foobar".to_string(),
+      state: "Start".to_string(),
+    };
+    assert_eq!(
+      error.to_string(),
+      r#"encountered unexpected line 3: "foobar" (state: Start)"#
+    );
+
+    let error = Error::Process {
+      program: OsString::from("git"),
+      stderr: "fatal: not a git repository".to_string(),
+    };
+    assert_eq!(
+      error.to_string(),
+      "process `git` failed: fatal: not a git repository"
+    );
+
+    let error = Error::Process {
+      program: OsString::from("git"),
+      stderr: String::new(),
+    };
+    assert_eq!(error.to_string(), "process `git` failed");
+
+    let error = Error::ProcessTimeout {
+      file: "main.c".to_string(),
+      line: 4,
+      count: 2,
+    };
+    assert_eq!(
+      error.to_string(),
+      "annotating 2 line(s) of main.c starting at line 4 timed out"
+    );
+
+    let error = Error::from(io::Error::new(io::ErrorKind::NotFound, "not found"));
+    assert_eq!(error.to_string(), "not found");
+    assert_eq!(io::Error::from(error).kind(), io::ErrorKind::NotFound);
+  }
+}
@@ -0,0 +1,130 @@
+// Copyright (C) 2026 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! A module for interactively selecting the diff hunks to annotate.
+
+use std::ffi::OsStr;
+use std::ffi::OsString;
+use std::io::BufRead;
+use std::io::Write;
+use std::iter::once;
+
+use crate::blame_capture;
+use crate::BlameConfig;
+use crate::Hunk;
+use crate::Result;
+
+
+/// An enumeration of the possible answers to the question of whether
+/// to annotate a hunk.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Answer {
+  /// Annotate the hunk.
+  Yes,
+  /// Skip the hunk.
+  No,
+  /// Skip the hunk and all remaining ones.
+  Quit,
+  /// Annotate the hunk and all remaining ones.
+  All,
+}
+
+
+/// Prompt the user about a hunk until a valid answer is provided.
+fn prompt<R, W>(input: &mut R, output: &mut W) -> Result<Answer>
+where
+  R: BufRead,
+  W: Write,
+{
+  let mut line = String::new();
+
+  loop {
+    write!(output, "Annotate this hunk [y]es/[n]o/[q]uit/[a]ll? ")?;
+    let () = output.flush()?;
+
+    line.clear();
+    if input.read_line(&mut line)? == 0 {
+      // There is no more input to be had. Treat that as the user
+      // quitting.
+      writeln!(output)?;
+      break Ok(Answer::Quit)
+    }
+
+    match line.trim() {
+      "y" | "Y" => break Ok(Answer::Yes),
+      "n" | "N" => break Ok(Answer::No),
+      "q" | "Q" => break Ok(Answer::Quit),
+      "a" | "A" => break Ok(Answer::All),
+      _ => (),
+    }
+  }
+}
+
+
+/// Interactively annotate diff hunks.
+///
+/// For each hunk the user is prompted via `input` and `output` whether
+/// to annotate it. Annotations are written to `output` as soon as the
+/// user agreed. Hunks without any lines to annotate, as is the case for
+/// newly added files, are not offered.
+pub fn blame_interactive<R, W>(
+  diffs: &[Hunk],
+  args: &[OsString],
+  config: &BlameConfig,
+  mut input: R,
+  mut output: W,
+) -> Result<()>
+where
+  R: BufRead,
+  W: Write,
+{
+  let mut all = false;
+
+  // `git blame` refuses to annotate an empty range of lines, so there
+  // is no point in asking about such hunks.
+  let diffs = diffs.iter().filter(|Hunk { src, .. }| src.count > 0);
+  for Hunk { src, dst } in diffs {
+    writeln!(output, "--- {}", src.file)?;
+    writeln!(output, "+++ {}", dst.file)?;
+
+    if !all {
+      writeln!(
+        output,
+        "@@ {}{},{} {}{},{} @@",
+        src.op, src.line, src.count, dst.op, dst.line, dst.count
+      )?;
+
+      match prompt(&mut input, &mut output)? {
+        Answer::Yes => (),
+        Answer::No => continue,
+        Answer::Quit => break,
+        Answer::All => all = true,
+      }
+    }
+
+    let args = once(OsStr::new("-s")).chain(args.iter().map(OsString::as_os_str));
+    let blame = blame_capture(config, src, args)?;
+    let () = output.write_all(&blame)?;
+  }
+  Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+
+  /// Check that user answers are interpreted correctly.
+  #[test]
+  fn prompt_answers() {
+    let answer = |input: &[u8]| prompt(&mut &input[..], &mut Vec::new()).unwrap();
+
+    assert_eq!(answer(b"y\n"), Answer::Yes);
+    assert_eq!(answer(b"N\n"), Answer::No);
+    assert_eq!(answer(b"q\n"), Answer::Quit);
+    assert_eq!(answer(b" a \n"), Answer::All);
+    assert_eq!(answer(b"x\nyes\n\nn\n"), Answer::No);
+    assert_eq!(answer(b""), Answer::Quit);
+  }
+}
@@ -0,0 +1,141 @@
+// Copyright (C) 2026 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! A module for emitting blame information in JSON form.
+
+use std::borrow::Borrow;
+use std::ffi::OsStr;
+use std::fmt::Write as _;
+use std::io::Write;
+
+use crate::blame_capture;
+use crate::porcelain;
+use crate::BlameConfig;
+use crate::Hunk;
+use crate::OutputFormat;
+use crate::Result;
+
+
+/// Encode a string as a JSON string literal, including the surrounding
+/// quotes.
+fn string(s: &str) -> String {
+  let mut encoded = String::with_capacity(s.len() + 2);
+  encoded.push('"');
+  for c in s.chars() {
+    match c {
+      '"' => encoded.push_str(r#"\""#),
+      '\\' => encoded.push_str(r"\\"),
+      '\n' => encoded.push_str(r"\n"),
+      '\r' => encoded.push_str(r"\r"),
+      '\t' => encoded.push_str(r"\t"),
+      c if c < ' ' => {
+        // Writing to a `String` is infallible.
+        let _result = write!(encoded, r"\u{:04x}", u32::from(c));
+      },
+      c => encoded.push(c),
+    }
+  }
+  encoded.push('"');
+  encoded
+}
+
+
+/// Annotate all the diff hunks and write the result to `writer` as a
+/// JSON array.
+///
+/// Each hunk is represented by an object of the form:
+/// ```json
+/// {"src_file":"file.c","dst_file":"file.c","lines":[{"commit":"deadbeef","line_no":6,"content":"..."}]}
+/// ```
+///
+/// Hunks are written one at a time as they are annotated, each on a
+/// line of its own.
+pub fn blame_to_json<W>(diffs: &[Hunk], config: &BlameConfig, writer: &mut W) -> Result<()>
+where
+  W: Write,
+{
+  let args = [OutputFormat::Json.blame_arg()];
+  write_json(diffs.iter().map(Ok), args, config, writer)
+}
+
+
+/// Annotate the diff hunks produced by `diffs`, invoking `git blame`
+/// with `args`, and write the result to `writer` as a JSON array.
+///
+/// `args` have to include `--line-porcelain`.
+pub(crate) fn write_json<W, D, P, A, S>(
+  diffs: D,
+  args: A,
+  config: &BlameConfig,
+  writer: &mut W,
+) -> Result<()>
+where
+  W: Write,
+  D: IntoIterator<Item = Result<P>>,
+  P: Borrow<Hunk>,
+  A: IntoIterator<Item = S> + Clone,
+  S: AsRef<OsStr>,
+{
+  write!(writer, "[")?;
+
+  for (idx, diff) in diffs.into_iter().enumerate() {
+    let diff = diff?;
+    let Hunk { src, dst } = diff.borrow();
+    // `git blame` refuses to annotate an empty range of lines, as is the
+    // case for newly added files, so such hunks get no lines.
+    let lines = if src.count > 0 {
+      let output = blame_capture(config, src, args.clone())?;
+      let output = String::from_utf8_lossy(&output);
+      porcelain::parse(&output)?
+    } else {
+      Vec::new()
+    };
+
+    if idx > 0 {
+      write!(writer, ",")?;
+    }
+    write!(
+      writer,
+      r#"{}{{"src_file":{},"dst_file":{},"lines":["#,
+      '\n',
+      string(&src.file),
+      string(&dst.file),
+    )?;
+    for (idx, line) in lines.iter().enumerate() {
+      if idx > 0 {
+        write!(writer, ",")?;
+      }
+      write!(
+        writer,
+        r#"{{"commit":{},"line_no":{},"content":{}}}"#,
+        string(&line.commit),
+        line.line_number,
+        string(&line.content),
+      )?;
+    }
+    write!(writer, "]}}")?;
+    let () = writer.flush()?;
+  }
+
+  writeln!(writer, "\n]")?;
+  Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+
+  /// Check that strings are encoded properly.
+  #[test]
+  fn string_encoding() {
+    assert_eq!(string("main.c"), r#""main.c""#);
+    assert_eq!(
+      string(r#"printf("%s\n", s);"#),
+      r#""printf(\"%s\\n\", s);""#
+    );
+    assert_eq!(string("\tbell\u{7}"), r#""\tbell\u0007""#);
+    assert_eq!(string("ünïcödé"), r#""ünïcödé""#);
+  }
+}
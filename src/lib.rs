@@ -1,95 +1,1547 @@
 // Copyright (C) 2022 Daniel Mueller <deso@posteo.net>
 // SPDX-License-Identifier: GPL-3.0-or-later
 
-use std::env::Args;
+use std::borrow::Borrow;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::env::var_os;
 use std::ffi::OsStr;
+use std::ffi::OsString;
+use std::io;
 use std::io::stdout;
-use std::io::BufRead as _;
-use std::io::BufReader;
-use std::io::Error;
 use std::io::ErrorKind;
-use std::io::Result;
-use std::io::Write as _;
-use std::ops::Deref as _;
+use std::io::Read;
+use std::io::Write;
+use std::iter::once;
+use std::mem::take;
+use std::ops::Range;
+use std::path::Path;
+use std::path::PathBuf;
+use std::path::MAIN_SEPARATOR;
 use std::process::Child;
-use std::process::ChildStdout;
 use std::process::Command;
+use std::process::Output;
 use std::process::Stdio;
+use std::slice;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::channel;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
 
-use diff_parse::File;
+mod collect;
+#[cfg(feature = "color")]
+mod color;
+mod diff;
+mod error;
+#[cfg(feature = "interactive")]
+mod interactive;
+#[cfg(feature = "json")]
+mod json;
+mod markdown;
+mod output;
+mod porcelain;
+mod progress;
+mod repo;
+mod summary;
+#[cfg(feature = "xml")]
+mod xml;
+
+pub use collect::blame_collect;
+pub use collect::blame_collect_with_config;
+pub use collect::BlameLine;
+pub use collect::BlamedHunk;
+#[cfg(feature = "color")]
+pub use color::AgeColorizer;
+pub use diff::conflict_kind;
+pub use diff::filter_whitespace_hunks;
+pub use diff::find_conflicts;
+pub use diff::is_whitespace_only;
+pub use diff::CommitPair;
+pub use diff::ConflictKind;
+pub use diff::DiffFormat;
+pub use diff::DiffLine;
+pub use diff::File;
+pub use diff::Hunk;
+pub use diff::Op;
+pub use diff::Parser;
+pub use diff::ParserIter;
+pub use diff::ParserOptions;
+pub use diff::RangeDiffParser;
+pub use diff::RangeDiffSide;
+pub use diff::VerboseOp;
+pub use error::Error;
+pub use error::Result;
+#[cfg(feature = "interactive")]
+pub use interactive::blame_interactive;
+#[cfg(feature = "json")]
+pub use json::blame_to_json;
+pub use markdown::blame_to_markdown;
+pub use output::OutputFormat;
+pub use repo::RepoContext;
+pub use summary::blame_summary;
+pub use summary::blame_summary_with_config;
+pub use summary::BlameSummary;
+#[cfg(feature = "xml")]
+pub use xml::blame_to_xml;
 
 
 /// The path to the `git` binary used by default.
 pub const GIT: &str = "/usr/bin/git";
+/// The environment variable that can be used to override the path to
+/// the `git` binary to use.
+pub const GIT_ENV: &str = "GIT_BLAMEDIFF_GIT";
+/// The environment variable that can be used to set a default timeout,
+/// in seconds, for each `git blame` invocation.
+pub const TIMEOUT_ENV: &str = "GIT_BLAMEDIFF_TIMEOUT_SECS";
+/// The minimum version of `git` supporting the `--ignore-rev` option
+/// of `git blame`.
+const IGNORE_REV_GIT_VERSION: (u32, u32, u32) = (2, 23, 0);
+/// The indentation used for annotated context lines.
+const CONTEXT_INDENT: &str = "    ";
+/// The line separating the annotations of the source and destination
+/// sides of a diff when annotating both.
+const TARGET_SEPARATOR: &str = "--";
+
+
+/// An enumeration of the sides of a diff that can be annotated.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum BlameTarget {
+  /// Annotate the source side of a diff, i.e., the lines as they were
+  /// before the change, as of `HEAD` or the configured revision.
+  #[default]
+  Source,
+  /// Annotate the destination side of a diff, i.e., the lines as they
+  /// are after the change, as present in the working tree.
+  Destination,
+  /// Annotate both sides of a diff.
+  Both,
+}
+
+
+/// A piece of a [`FormatTemplate`].
+#[derive(Clone, Debug, PartialEq)]
+enum Piece {
+  /// Literal text.
+  Literal(String),
+  /// The `{src_file}` placeholder.
+  SrcFile,
+  /// The `{dst_file}` placeholder.
+  DstFile,
+  /// The `{blame}` placeholder.
+  Blame,
+  /// The `{func}` placeholder.
+  Func,
+}
+
+
+/// A template describing the output produced for each diff hunk.
+///
+/// The template may contain the placeholders `{src_file}`,
+/// `{dst_file}`, and `{blame}`, which are replaced with the source file,
+/// the destination file, and the annotated lines of a hunk,
+/// respectively. `{func}` is replaced with the hint at the function
+/// containing the hunk, if `git diff` provided one, and with nothing
+/// otherwise. Literal braces can be included as `{{` and `}}`. The
+/// escape sequences `\n`, `\t`, and `\\` are supported as well.
+///
+/// The default template is `--- {src_file}\n+++ {dst_file}\n{blame}`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FormatTemplate {
+  pieces: Vec<Piece>,
+}
+
+impl FormatTemplate {
+  /// Parse a template from a string, checking it for validity.
+  pub fn parse(s: &str) -> Result<Self> {
+    let invalid = |reason: String| {
+      Error::Io(io::Error::new(
+        ErrorKind::InvalidInput,
+        format!("invalid format template `{s}`: {reason}"),
+      ))
+    };
+
+    let mut pieces = Vec::new();
+    let mut literal = String::new();
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+      let piece = match c {
+        '{' if chars.as_str().starts_with('{') => {
+          let _brace = chars.next();
+          literal.push('{');
+          continue
+        },
+        '}' if chars.as_str().starts_with('}') => {
+          let _brace = chars.next();
+          literal.push('}');
+          continue
+        },
+        '{' => {
+          let rest = chars.as_str();
+          let (name, rest) = rest
+            .split_once('}')
+            .ok_or_else(|| invalid("unterminated placeholder".to_string()))?;
+          chars = rest.chars();
+
+          match name {
+            "src_file" => Piece::SrcFile,
+            "dst_file" => Piece::DstFile,
+            "blame" => Piece::Blame,
+            "func" => Piece::Func,
+            _ => return Err(invalid(format!("unrecognized placeholder `{{{name}}}`"))),
+          }
+        },
+        '}' => return Err(invalid("unmatched `}`".to_string())),
+        '\\' => {
+          match chars.next() {
+            Some('n') => literal.push('\n'),
+            Some('t') => literal.push('\t'),
+            Some('\\') => literal.push('\\'),
+            Some(c) => {
+              literal.push('\\');
+              literal.push(c);
+            },
+            None => literal.push('\\'),
+          }
+          continue
+        },
+        c => {
+          literal.push(c);
+          continue
+        },
+      };
+
+      if !literal.is_empty() {
+        let () = pieces.push(Piece::Literal(take(&mut literal)));
+      }
+      let () = pieces.push(piece);
+    }
+
+    if !literal.is_empty() {
+      let () = pieces.push(Piece::Literal(literal));
+    }
+    Ok(Self { pieces })
+  }
+
+  /// Write the output for a single diff, formatted according to the
+  /// template, with `blame` being invoked to write the annotated lines.
+  pub fn render<W, F>(&self, src: &File, dst: &File, writer: &mut W, mut blame: F) -> Result<()>
+  where
+    W: Write,
+    F: FnMut(&mut W) -> Result<()>,
+  {
+    for piece in &self.pieces {
+      match piece {
+        Piece::Literal(literal) => write!(writer, "{literal}")?,
+        Piece::SrcFile => write!(writer, "{}", src.file)?,
+        Piece::DstFile => write!(writer, "{}", dst.file)?,
+        Piece::Blame => blame(writer)?,
+        Piece::Func => write!(writer, "{}", src.func_context.as_deref().unwrap_or(""))?,
+      }
+    }
+    Ok(())
+  }
+}
+
+impl Default for FormatTemplate {
+  fn default() -> Self {
+    Self {
+      pieces: vec![
+        Piece::Literal("--- ".to_string()),
+        Piece::SrcFile,
+        Piece::Literal("\n+++ ".to_string()),
+        Piece::DstFile,
+        Piece::Literal("\n".to_string()),
+        Piece::Blame,
+      ],
+    }
+  }
+}
+
+
+/// Configuration options for the annotation of diffs.
+#[derive(Clone, Debug)]
+pub struct BlameConfig {
+  /// The path to the `git` binary to use.
+  ///
+  /// Defaults to the value of the `GIT_BLAMEDIFF_GIT` environment
+  /// variable, if set, and [`GIT`] otherwise.
+  pub git_path: PathBuf,
+  /// The directory in which to invoke `git`. If `None`, the current
+  /// working directory is used.
+  pub directory: Option<PathBuf>,
+  /// Whether to annotate the context lines of a diff separately from
+  /// the changed lines, setting them apart visually.
+  pub annotate_context: bool,
+  /// Whether to treat root commits as normal commits instead of as
+  /// boundaries (i.e., pass `--root` to `git blame`).
+  pub blame_root: bool,
+  /// Cached meta data about the repository being worked on, if
+  /// already detected.
+  pub repo: Option<RepoContext>,
+  /// The maximum number of `git` processes to run concurrently.
+  pub jobs: usize,
+  /// The side of each diff to annotate.
+  pub target: BlameTarget,
+  /// The template describing the output produced for each diff.
+  pub template: FormatTemplate,
+  /// The revision range in which to look for the last commit still
+  /// containing each removed line, instead of annotating lines with
+  /// the commit that last changed them (see `git blame --reverse`).
+  ///
+  /// Only the source side of diffs is annotated in this mode and pure
+  /// additions are skipped.
+  pub reverse: Option<String>,
+  /// The revision as of which to annotate the source side of diffs.
+  /// `HEAD` is used if `None`.
+  ///
+  /// The revision is passed to `git` verbatim, so any expression it
+  /// understands, e.g., `HEAD~3`, can be used.
+  pub revision: Option<String>,
+  /// The number of lines above and below each hunk to annotate in
+  /// addition to those covered by the diff itself.
+  pub context_lines: usize,
+  /// Revisions to ignore when assigning blame (see `git blame
+  /// --ignore-rev`).
+  ///
+  /// All revisions are checked for existence before any annotation
+  /// happens.
+  pub ignore_revs: Vec<String>,
+  /// Whether the diffs describe staged changes, i.e., ones between
+  /// `HEAD` and the index, as produced by `git diff --staged`.
+  ///
+  /// If set, the destination side of diffs is annotated as present in
+  /// the index instead of the working tree, and diffs of newly added
+  /// files are skipped.
+  pub staged: bool,
+  /// The format in which to emit annotations.
+  ///
+  /// Only [`blame_with_config`] and functions based on it honor this
+  /// setting.
+  pub format: OutputFormat,
+  /// The time after which to give up on a single `git blame`
+  /// invocation, killing the process. If `None`, we wait indefinitely.
+  ///
+  /// Defaults to the number of seconds stored in the
+  /// `GIT_BLAMEDIFF_TIMEOUT_SECS` environment variable, if set.
+  pub timeout: Option<Duration>,
+  /// Environment variables to set for `git` processes.
+  pub env_vars: HashMap<OsString, OsString>,
+  /// Whether to clear the environment of `git` processes before
+  /// setting [`env_vars`][Self::env_vars], instead of having them
+  /// inherit the environment of the current process.
+  pub clear_env: bool,
+  /// The label to print instead of the all-zeros commit hash that
+  /// `git blame` reports for lines not attributed to any commit, such
+  /// as ones not committed yet. If empty, the hash is printed as is.
+  ///
+  /// Only plain output is affected. Defaults to `<root>`.
+  pub root_label: String,
+  /// Whether to report the progress made in annotating diffs on
+  /// stderr, as a single line overwritten in place.
+  ///
+  /// Only [`blame_with_config`] honors this setting.
+  pub progress: bool,
+  /// The object to use for coloring annotations by commit age, if
+  /// any.
+  #[cfg(feature = "color")]
+  pub colorizer: Option<AgeColorizer>,
+}
+
+impl BlameConfig {
+  /// Retrieve the revision as of which to annotate the source side of
+  /// diffs.
+  fn revision(&self) -> &str {
+    self.revision.as_deref().unwrap_or("HEAD")
+  }
+
+  /// Check the configuration for validity, making sure that all
+  /// revisions to ignore exist and, if the repository's meta data are
+  /// known, that the `git` in use supports ignoring them.
+  pub fn validate(&self) -> Result<()> {
+    if let Some(repo) = &self.repo {
+      if !self.ignore_revs.is_empty() && repo.git_version < IGNORE_REV_GIT_VERSION {
+        let (major, minor, patch) = repo.git_version;
+        let (min_major, min_minor, min_patch) = IGNORE_REV_GIT_VERSION;
+        return Err(Error::Io(io::Error::new(
+          ErrorKind::Unsupported,
+          format!(
+            "ignoring revisions requires git {min_major}.{min_minor}.{min_patch} or newer, \
+             found {major}.{minor}.{patch}"
+          ),
+        )))
+      }
+    }
+
+    for rev in &self.ignore_revs {
+      let mut command = self.git_command();
+      command
+        .args(["rev-parse", "--verify", "--quiet"])
+        .arg(rev)
+        .stdin(Stdio::null())
+        .stderr(Stdio::piped());
+
+      match capture(command) {
+        Ok(_output) => (),
+        Err(Error::Process { .. }) => {
+          return Err(Error::Io(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("unknown revision '{rev}'"),
+          )))
+        },
+        Err(error) => return Err(error),
+      }
+    }
+    Ok(())
+  }
+
+  /// Create a `git` [`Command`] as dictated by the configuration.
+  pub fn git_command(&self) -> Command {
+    let mut command = Command::new(&self.git_path);
+    if let Some(directory) = &self.directory {
+      command.current_dir(directory);
+    }
+    if self.clear_env {
+      command.env_clear();
+    }
+    command.envs(&self.env_vars);
+    command
+  }
+}
+
+impl Default for BlameConfig {
+  fn default() -> Self {
+    Self {
+      git_path: var_os(GIT_ENV)
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(GIT)),
+      directory: None,
+      annotate_context: false,
+      blame_root: false,
+      repo: None,
+      jobs: 1,
+      target: BlameTarget::default(),
+      template: FormatTemplate::default(),
+      reverse: None,
+      revision: None,
+      context_lines: 0,
+      ignore_revs: Vec::new(),
+      staged: false,
+      format: OutputFormat::default(),
+      timeout: var_os(TIMEOUT_ENV)
+        .and_then(|secs| secs.to_str()?.parse().ok())
+        .map(Duration::from_secs),
+      env_vars: HashMap::new(),
+      clear_env: false,
+      root_label: "<root>".to_string(),
+      progress: false,
+      #[cfg(feature = "color")]
+      colorizer: None,
+    }
+  }
+}
 
 
 /// Wait for a child process to finish and map failures to an
 /// appropriate error.
-pub fn await_child<S>(program: S, child: Child) -> Result<Option<ChildStdout>>
+///
+/// Output the process writes to its standard output or error streams,
+/// if captured, is read concurrently to prevent it from blocking on a
+/// full pipe. Captured standard output is returned, while the complete
+/// error output is included in the error reported on failure.
+pub fn await_child<S>(program: S, child: Child) -> Result<Vec<u8>>
+where
+  S: AsRef<OsStr>,
+{
+  let output = child.wait_with_output()?;
+  check_output(program.as_ref(), output)
+}
+
+
+/// Map the output of a process that exited with a failure to an
+/// appropriate error, returning its standard output otherwise.
+fn check_output(program: &OsStr, output: Output) -> Result<Vec<u8>> {
+  if !output.status.success() {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    return Err(Error::Process {
+      program: program.to_os_string(),
+      stderr: stderr.trim_end().to_string(),
+    })
+  }
+  Ok(output.stdout)
+}
+
+
+/// Read everything from a pipe of a child process, if captured.
+fn read_pipe<R>(pipe: Option<R>) -> io::Result<Vec<u8>>
+where
+  R: Read,
+{
+  let mut data = Vec::new();
+  if let Some(mut pipe) = pipe {
+    let _count = pipe.read_to_end(&mut data)?;
+  }
+  Ok(data)
+}
+
+
+/// Wait for a child process to finish, just like [`await_child`], but
+/// kill it should it not have exited after `timeout`, if provided.
+///
+/// `None` is returned if the process got killed.
+fn await_child_timeout<S>(
+  program: S,
+  mut child: Child,
+  timeout: Option<Duration>,
+) -> Result<Option<Vec<u8>>>
+where
+  S: AsRef<OsStr>,
+{
+  let Some(timeout) = timeout else {
+    return await_child(program, child).map(Some)
+  };
+
+  let stdout = child.stdout.take();
+  let stderr = child.stderr.take();
+  let child = Mutex::new(child);
+  let timed_out = AtomicBool::new(false);
+  let (sender, receiver) = channel::<()>();
+
+  let output = thread::scope(|scope| {
+    let child = &child;
+    let timed_out = &timed_out;
+
+    let _watcher = scope.spawn(move || {
+      // Sleep for the duration of the timeout, but wake up early once
+      // the process exited and the sender got dropped.
+      if receiver.recv_timeout(timeout) == Err(RecvTimeoutError::Timeout) {
+        // The lock can't be poisoned, as nobody panics while holding it.
+        let mut child = child.lock().unwrap();
+        if let Ok(None) = child.try_wait() {
+          let _result = child.kill();
+          let () = timed_out.store(true, Ordering::Relaxed);
+        }
+      }
+    });
+
+    let result = (|| -> io::Result<Output> {
+      let reader = scope.spawn(move || read_pipe(stdout));
+      let stderr = read_pipe(stderr)?;
+      // Reading does not panic.
+      let stdout = reader.join().unwrap()?;
+      // Both pipes got closed, so the process is about to exit, if it
+      // has not done so already.
+      let status = child.lock().unwrap().wait()?;
+      Ok(Output {
+        status,
+        stdout,
+        stderr,
+      })
+    })();
+    drop(sender);
+    result
+  })?;
+
+  if timed_out.into_inner() {
+    return Ok(None)
+  }
+  check_output(program.as_ref(), output).map(Some)
+}
+
+
+/// Convert a path into the form expected by `git`, which uses forward
+/// slashes as separators on all platforms.
+fn to_git_path(path: &Path) -> String {
+  let path = path.to_string_lossy();
+  if MAIN_SEPARATOR != '/' {
+    path.replace(MAIN_SEPARATOR, "/")
+  } else {
+    path.into_owned()
+  }
+}
+
+
+/// Create a `git blame` [`Command`] annotating the provided ranges of
+/// lines of `file` as of `revision`, or as present in the working tree
+/// if `None`.
+fn blame_command<A, S>(
+  config: &BlameConfig,
+  file: &str,
+  revision: Option<&str>,
+  ranges: &[Range<usize>],
+  args: A,
+) -> Command
 where
+  A: IntoIterator<Item = S>,
   S: AsRef<OsStr>,
 {
-  let mut child = child;
+  let mut command = config.git_command();
+  command.arg("--no-pager").arg("blame");
+  if config.blame_root {
+    command.arg("--root");
+  }
+  for rev in &config.ignore_revs {
+    command.arg("--ignore-rev").arg(rev);
+  }
+  command
+    .args(
+      ranges
+        .iter()
+        .map(|range| format!("-L{},+{}", range.start, range.len())),
+    )
+    .args(args)
+    .arg("--")
+    .arg(to_git_path(Path::new(file)))
+    .args(revision)
+    .stdin(Stdio::null())
+    .stderr(Stdio::piped());
+  command
+}
+
+
+/// Run a `git` command, capturing and returning its output.
+fn capture(mut command: Command) -> Result<Vec<u8>> {
+  let child = command.stdout(Stdio::piped()).spawn()?;
+  await_child(command.get_program(), child)
+}
 
-  let status = child.wait()?;
-  if !status.success() {
-    let error = format!("process `{}` failed", program.as_ref().to_string_lossy());
 
-    if let Some(stderr) = child.stderr {
-      let mut stderr = BufReader::new(stderr);
-      let mut line = String::new();
+/// Run a `git blame` command annotating the provided ranges of lines
+/// of `file`, capturing and returning its output.
+///
+/// If provided, `input` is passed to the command on its standard
+/// input. The command is killed if it does not finish within the
+/// configured timeout.
+fn capture_blame(
+  config: &BlameConfig,
+  mut command: Command,
+  file: &str,
+  ranges: &[Range<usize>],
+  input: Option<&[u8]>,
+) -> Result<Vec<u8>> {
+  if input.is_some() {
+    command.stdin(Stdio::piped());
+  }
+  let mut child = command.stdout(Stdio::piped()).spawn()?;
+  let stdin = child.stdin.take();
 
-      // Let's try to include the first line of the error output in our
-      // error, to at least give the user something.
-      if stderr.read_line(&mut line).is_ok() {
-        let line = line.trim();
-        return Err(Error::new(ErrorKind::Other, format!("{error}: {line}")))
+  let output = thread::scope(|scope| -> Result<_> {
+    // Input has to be provided concurrently to reading the output, lest
+    // we deadlock once a pipe is full.
+    let writer = stdin
+      .zip(input)
+      .map(|(mut stdin, input)| scope.spawn(move || stdin.write_all(input)));
+    let output = await_child_timeout(command.get_program(), child, config.timeout)?;
+    if let Some(writer) = writer {
+      // Writing the input does not panic. A killed process may not
+      // have consumed all of it, though.
+      let result = writer.join().unwrap();
+      if output.is_some() {
+        let () = result?;
       }
     }
-    return Err(Error::new(ErrorKind::Other, error))
-  }
-  Ok(child.stdout)
-}
-
-
-/// Invoke git to annotate all the diff hunks.
-// TODO: For some reason `ArgsOs` is not `Clone`, which is why we pass
-//       in a function that recreates such an object every time.
-pub fn blame<A>(diffs: &[(File, File)], args: A) -> Result<()>
-where
-  A: Fn() -> Args,
-{
-  let out = stdout();
-  let mut out = out.lock();
-
-  for (src, dst) in diffs {
-    // Start off by printing some information on the file we are
-    // currently annotating.
-    // TODO: We should print the file header only once.
-    writeln!(out, "--- {}", src.file)?;
-    writeln!(out, "+++ {}", dst.file)?;
-    // Make sure stdout is flushed properly before invoking a git command
-    // to be sure our output arrives before that of git.
-    let () = out.flush()?;
-
-    // Invoke git with the appropriate options to annotate the lines of
-    // the diff.
-    // TODO: Make the arguments here more configurable. In fact, we
-    //       should not hard-code any of them here.
-    let child = Command::new(GIT)
-      .arg("--no-pager")
-      .arg("blame")
-      .arg("-s")
-      .arg(format!("-L{},+{}", src.line, src.count))
-      .args(args().skip(1))
-      .arg("--")
-      .arg(src.file.deref())
-      .arg("HEAD")
-      .stdin(Stdio::null())
-      .stdout(Stdio::inherit())
-      .stderr(Stdio::piped())
-      .spawn()?;
-    let _ = await_child(GIT, child)?;
+    Ok(output)
+  })?;
+
+  output.ok_or_else(|| {
+    let line = ranges.iter().map(|range| range.start).min().unwrap_or(0);
+    let end = ranges.iter().map(|range| range.end).max().unwrap_or(0);
+    Error::ProcessTimeout {
+      file: file.to_string(),
+      line,
+      count: end.saturating_sub(line),
+    }
+  })
+}
+
+
+/// Retrieve the content of `file` as staged in the index.
+fn index_contents(config: &BlameConfig, file: &str) -> Result<Vec<u8>> {
+  let mut command = config.git_command();
+  command
+    .arg("--no-pager")
+    .arg("cat-file")
+    .arg("blob")
+    // Paths are relative to the current working directory.
+    .arg(format!(":./{}", to_git_path(Path::new(file))))
+    .stdin(Stdio::null())
+    .stderr(Stdio::piped());
+  capture(command)
+}
+
+
+/// Invoke `git blame` on the provided ranges of lines of `file` as
+/// part of annotating a diff, capturing and returning its output.
+///
+/// If `contents` is provided, it is used as the content of `file` to
+/// annotate. If configured, plain output is colored based on commit
+/// age.
+fn annotate(
+  config: &BlameConfig,
+  file: &str,
+  revision: Option<&str>,
+  ranges: &[Range<usize>],
+  args: &[OsString],
+  contents: Option<&[u8]>,
+) -> Result<Vec<u8>> {
+  let capture = |command| capture_blame(config, command, file, ranges, contents);
+  let args = args.iter().map(OsString::as_os_str).chain(
+    contents
+      .map(|_contents| [OsStr::new("--contents"), OsStr::new("-")])
+      .into_iter()
+      .flatten(),
+  );
+
+  #[cfg(feature = "color")]
+  if let (OutputFormat::Plain, Some(colorizer)) = (config.format, &config.colorizer) {
+    let args = args.chain(once(OsStr::new("--line-porcelain")));
+    let output = capture(blame_command(config, file, revision, ranges, args))?;
+    return color::format(&output, colorizer, &config.root_label)
   }
+
+  let output = capture(blame_command(config, file, revision, ranges, args))?;
+  if config.format == OutputFormat::Plain && !config.root_label.is_empty() {
+    Ok(label_null_commits(&output, &config.root_label))
+  } else {
+    Ok(output)
+  }
+}
+
+
+/// Check whether `commit` is the all-zeros hash `git blame` reports for
+/// lines not attributed to any commit, in full or abbreviated form.
+fn is_null_commit(commit: &[u8]) -> bool {
+  !commit.is_empty() && commit.iter().all(|byte| *byte == b'0')
+}
+
+
+/// Replace the all-zeros commit hash at the start of lines of plain
+/// `git blame` output with `label`.
+fn label_null_commits(output: &[u8], label: &str) -> Vec<u8> {
+  let mut labeled = Vec::with_capacity(output.len());
+  for line in output.split_inclusive(|byte| *byte == b'\n') {
+    let end = line
+      .iter()
+      .position(|byte| *byte == b' ')
+      .unwrap_or(line.len());
+    let (commit, rest) = line.split_at(end);
+    if is_null_commit(commit) {
+      let () = labeled.extend_from_slice(label.as_bytes());
+      let () = labeled.extend_from_slice(rest);
+    } else {
+      let () = labeled.extend_from_slice(line);
+    }
+  }
+  labeled
+}
+
+
+/// Invoke `git blame` on the lines covered by `file`, capturing and
+/// returning its output.
+fn blame_capture<A, S>(config: &BlameConfig, file: &File, args: A) -> Result<Vec<u8>>
+where
+  A: IntoIterator<Item = S>,
+  S: AsRef<OsStr>,
+{
+  let range = file.line..file.line + file.count;
+  let ranges = slice::from_ref(&range);
+  let path = file.blame_file();
+  let command = blame_command(config, path, Some(config.revision()), ranges, args);
+  capture_blame(config, command, path, ranges, None)
+}
+
+
+/// Annotate the lines in `line_range` of `file`, tracing their history
+/// across renames of the file.
+///
+/// Prior to the annotated lines, which are attributed to the name of
+/// the file they originated from, all renames that `file` underwent are
+/// reported.
+pub fn blame_with_history<W>(
+  file: &str,
+  line_range: Range<usize>,
+  config: &BlameConfig,
+  mut writer: W,
+) -> Result<()>
+where
+  W: Write,
+{
+  let mut command = config.git_command();
+  command
+    .arg("--no-pager")
+    .arg("log")
+    .arg("--follow")
+    .arg("--diff-filter=R")
+    .arg("--name-status")
+    .arg("--format=%h")
+    .arg(config.revision())
+    .arg("--")
+    .arg(to_git_path(Path::new(file)))
+    .stdin(Stdio::null())
+    .stderr(Stdio::piped());
+  let output = capture(command)?;
+  let output = String::from_utf8_lossy(&output);
+
+  // For each commit we get a line containing the abbreviated SHA-1
+  // followed by one with the name status, e.g., `R100\told\tnew`.
+  let mut commit = "";
+  for line in output.lines() {
+    match line.split('\t').collect::<Vec<_>>().as_slice() {
+      [status, old, new] if status.starts_with('R') => {
+        writeln!(writer, "# renamed {old} -> {new} in {commit}")?;
+      },
+      [sha1] if !sha1.is_empty() => commit = sha1,
+      _ => (),
+    }
+  }
+
+  let ranges = slice::from_ref(&line_range);
+  let command = blame_command(
+    config,
+    file,
+    Some(config.revision()),
+    ranges,
+    ["-s", "--show-name"],
+  );
+  let output = capture_blame(config, command, file, ranges, None)?;
+  let () = writer.write_all(&output)?;
   Ok(())
 }
+
+
+/// Assemble the arguments to pass to `git blame` for producing output
+/// in the given format from the provided user supplied ones.
+fn blame_args(args: &[OsString], format: OutputFormat) -> Vec<OsString> {
+  once(OsString::from(format.blame_arg()))
+    .chain(args.iter().cloned())
+    .collect()
+}
+
+
+/// Adjust the arguments to pass to `git blame` for finding the last
+/// commit in `range` still containing each annotated line.
+///
+/// Arguments that `git blame` refuses to combine with `--reverse` are
+/// filtered out.
+fn reverse_args(args: &[OsString], range: &str) -> Vec<OsString> {
+  let mut reversed = Vec::with_capacity(args.len() + 2);
+  let mut args = args.iter();
+
+  while let Some(arg) = args.next() {
+    if arg == "--contents" {
+      // Skip over the option's value as well.
+      let _value = args.next();
+    } else if arg != "--reverse" && !arg.to_string_lossy().starts_with("--contents=") {
+      let () = reversed.push(arg.clone());
+    }
+  }
+
+  reversed.push(OsString::from("--reverse"));
+  reversed.push(OsString::from(range));
+  reversed
+}
+
+
+/// Invoke git to annotate all the diff hunks, printing the result to
+/// stdout.
+///
+/// `diffs` may be the result of [`Parser::iter`], in which case
+/// annotation starts before all input has been parsed, or a list of
+/// already parsed diffs, e.g., `parser.diffs().iter().map(Ok)`.
+///
+/// `args` are passed to `git blame` in addition to the ones required
+/// for producing output in the given `format`.
+pub fn blame<D, P>(diffs: D, args: &[OsString], format: OutputFormat) -> Result<()>
+where
+  D: IntoIterator<Item = Result<P>>,
+  P: Borrow<Hunk>,
+{
+  let mut stdout = stdout().lock();
+  if format != OutputFormat::Plain {
+    return blame_to_writer(diffs, args, format, &mut stdout)
+  }
+
+  let args = blame_args(args, format);
+  let template = FormatTemplate::default();
+  blame_incremental(diffs, &args, |src, dst, blame| {
+    let () = write_formatted(src, dst, blame, &template, &mut stdout)?;
+    let () = stdout.flush()?;
+    Ok(())
+  })
+}
+
+
+/// Invoke git to annotate the source side of all the diff hunks,
+/// handing the output for each to `callback` as soon as it has been
+/// captured.
+///
+/// See [`blame`] for details on `diffs`. `callback` is invoked with the
+/// source and destination side of each hunk along with the annotated
+/// lines, as they appear in plain output. `args` are passed to `git
+/// blame` verbatim. Hunks without any lines on the source side, i.e.,
+/// pure additions, are reported with empty output. If `callback`
+/// returns an error, no further hunks are annotated and the error is
+/// returned.
+pub fn blame_incremental<D, P, F>(diffs: D, args: &[OsString], mut callback: F) -> Result<()>
+where
+  D: IntoIterator<Item = Result<P>>,
+  P: Borrow<Hunk>,
+  F: FnMut(&File, &File, &[u8]) -> Result<()>,
+{
+  let config = BlameConfig::default();
+  for diff in diffs {
+    let diff = diff?;
+    let Hunk { src, dst } = diff.borrow();
+    let mut output = Vec::new();
+    let () = blame_side(src, Op::Sub, args, &config, &mut output)?;
+    let () = callback(src, dst, &output)?;
+  }
+  Ok(())
+}
+
+
+/// Invoke git to annotate all the diff hunks, writing the result to
+/// `writer`.
+///
+/// See [`blame`] for details on `diffs`.
+pub fn blame_to_writer<W, D, P>(
+  diffs: D,
+  args: &[OsString],
+  format: OutputFormat,
+  writer: &mut W,
+) -> Result<()>
+where
+  W: Write,
+  D: IntoIterator<Item = Result<P>>,
+  P: Borrow<Hunk>,
+{
+  let config = BlameConfig {
+    format,
+    ..Default::default()
+  };
+  let args = blame_args(args, format);
+
+  #[cfg(feature = "json")]
+  if format == OutputFormat::Json {
+    return json::write_json(diffs, &args, &config, writer)
+  }
+
+  for diff in diffs {
+    let diff = diff?;
+    let Hunk { src, dst } = diff.borrow();
+    let () = blame_diff(src, dst, &args, &config, writer)?;
+  }
+  Ok(())
+}
+
+
+/// Invoke git to annotate the provided side of all the diff hunks,
+/// printing the result to stdout.
+pub fn blame_with_target(diffs: &[Hunk], args: &[OsString], target: BlameTarget) -> Result<()> {
+  let config = BlameConfig {
+    target,
+    ..Default::default()
+  };
+  blame_with_config(diffs, args, &config, &mut stdout().lock())
+}
+
+
+/// Invoke git to annotate all the diff hunks, writing the result to
+/// `writer` formatted according to `template`.
+pub fn blame_formatted<W>(
+  diffs: &[Hunk],
+  args: &[OsString],
+  writer: &mut W,
+  template: &FormatTemplate,
+) -> Result<()>
+where
+  W: Write,
+{
+  let config = BlameConfig {
+    template: template.clone(),
+    ..Default::default()
+  };
+  blame_with_config(diffs, args, &config, writer)
+}
+
+
+/// Invoke git to annotate all the diff hunks, using up to `jobs`
+/// concurrent `git` processes, and print the result to stdout.
+///
+/// The output is identical to that of [`blame`], irrespective of the
+/// order in which individual invocations finish.
+pub fn blame_parallel(diffs: &[Hunk], args: &[OsString], jobs: usize) -> Result<()> {
+  let config = BlameConfig {
+    jobs,
+    ..Default::default()
+  };
+  blame_with_config(diffs, args, &config, &mut stdout().lock())
+}
+
+
+/// Invoke git to find the last commit in `range` still containing each
+/// line removed by the diff hunks, printing the result to stdout.
+///
+/// `range` may be any revision range understood by `git`, e.g.,
+/// `v1.0..HEAD`. `args` are passed to `git blame` in addition to the
+/// default ones. Pure additions are skipped, as they did not remove
+/// any lines.
+pub fn blame_reverse(diffs: &[Hunk], args: &[OsString], range: &str) -> Result<()> {
+  let config = BlameConfig {
+    reverse: Some(range.to_string()),
+    ..Default::default()
+  };
+  blame_with_config(diffs, args, &config, &mut stdout().lock())
+}
+
+
+/// Invoke git to annotate all the diff hunks, printing the result to
+/// stdout, using a single `git blame` invocation for each run of
+/// consecutive hunks belonging to the same file.
+///
+/// The output matches that of [`blame`], except that `git blame` aligns
+/// columns across all lines it annotates in one go. `args` are passed
+/// to `git blame` in addition to the default ones.
+pub fn blame_batched(diffs: &[Hunk], args: &[OsString]) -> Result<()> {
+  blame_batched_with_config(diffs, args, &BlameConfig::default(), &mut stdout().lock())
+}
+
+
+/// Invoke git to annotate all the diff hunks, as dictated by the
+/// provided configuration, writing the result to `writer` and using a
+/// single `git blame` invocation for each run of consecutive hunks
+/// belonging to the same file.
+///
+/// Only the source side of diffs is annotated, in plain format, and
+/// context lines are not annotated separately, irrespective of the
+/// configuration. Hunks
+/// without any lines on the source side, i.e., pure additions, are
+/// skipped over.
+pub fn blame_batched_with_config<W>(
+  diffs: &[Hunk],
+  args: &[OsString],
+  config: &BlameConfig,
+  writer: &mut W,
+) -> Result<()>
+where
+  W: Write,
+{
+  let () = config.validate()?;
+  let args = blame_args(args, OutputFormat::Plain);
+  let args = match &config.reverse {
+    Some(range) => reverse_args(&args, range),
+    None => args,
+  };
+  let revision = config.reverse.is_none().then(|| config.revision());
+
+  for run in diffs.chunk_by(|a, b| a.src.blame_file() == b.src.blame_file()) {
+    let ranges = run
+      .iter()
+      .map(|Hunk { src, .. }| src.line..src.line + src.count)
+      .filter(|range| !range.is_empty())
+      .collect::<Vec<_>>();
+    let output = if !ranges.is_empty() {
+      annotate(
+        config,
+        run[0].src.blame_file(),
+        revision,
+        &ranges,
+        &args,
+        None,
+      )?
+    } else {
+      Vec::new()
+    };
+
+    // Hunks never overlap, so `git blame` reports exactly one line for
+    // each line of each range, in order.
+    let mut lines = output.split_inclusive(|byte| *byte == b'\n');
+    for Hunk { src, dst } in run {
+      let blame = lines.by_ref().take(src.count).collect::<Vec<_>>().concat();
+      let () = write_formatted(src, dst, &blame, &config.template, writer)?;
+    }
+  }
+  Ok(())
+}
+
+
+/// Invoke git to annotate all the diff hunks, printing the result to
+/// stdout, while reusing the annotations of hunks covering the same
+/// lines of the same file as a previous one.
+///
+/// `args` are passed to `git blame` in addition to the default ones.
+pub fn blame_cached(diffs: &[Hunk], args: &[OsString]) -> Result<()> {
+  blame_cached_with_config(diffs, args, &BlameConfig::default(), &mut stdout().lock())
+}
+
+
+/// Invoke git to annotate all the diff hunks, as dictated by the
+/// provided configuration, writing the result to `writer` and reusing
+/// the annotations of hunks covering the same lines of the same file
+/// as a previous one.
+///
+/// The same restrictions as for [`blame_batched_with_config`] apply.
+pub fn blame_cached_with_config<W>(
+  diffs: &[Hunk],
+  args: &[OsString],
+  config: &BlameConfig,
+  writer: &mut W,
+) -> Result<()>
+where
+  W: Write,
+{
+  let () = config.validate()?;
+  let args = blame_args(args, OutputFormat::Plain);
+  let args = match &config.reverse {
+    Some(range) => reverse_args(&args, range),
+    None => args,
+  };
+  let revision = config.reverse.is_none().then(|| config.revision());
+  let mut cache = HashMap::<(String, usize, usize), Vec<u8>>::new();
+
+  for Hunk { src, dst } in diffs {
+    let key = (src.blame_file().to_string(), src.line, src.count);
+    let blame = match cache.entry(key) {
+      Entry::Occupied(entry) => entry.into_mut(),
+      Entry::Vacant(entry) => {
+        let range = src.line..src.line + src.count;
+        let output = if !range.is_empty() {
+          annotate(config, src.blame_file(), revision, &[range], &args, None)?
+        } else {
+          Vec::new()
+        };
+        entry.insert(output)
+      },
+    };
+    let () = write_formatted(src, dst, blame, &config.template, writer)?;
+  }
+  Ok(())
+}
+
+
+/// Write the output for a single diff, formatted according to
+/// `template`, with `blame` being the annotated lines.
+fn write_formatted<W>(
+  src: &File,
+  dst: &File,
+  blame: &[u8],
+  template: &FormatTemplate,
+  writer: &mut W,
+) -> Result<()>
+where
+  W: Write,
+{
+  template.render(src, dst, writer, |writer| {
+    writer.write_all(blame)?;
+    Ok(())
+  })
+}
+
+
+/// Invoke git to annotate all the diff hunks, as dictated by the
+/// provided configuration, writing the result to `writer`.
+///
+/// The configuration is validated before any annotation happens.
+pub fn blame_with_config<W>(
+  diffs: &[Hunk],
+  args: &[OsString],
+  config: &BlameConfig,
+  writer: &mut W,
+) -> Result<()>
+where
+  W: Write,
+{
+  let () = config.validate()?;
+  let args = blame_args(args, config.format);
+  let args = match &config.reverse {
+    Some(range) => reverse_args(&args, range),
+    None => args,
+  };
+
+  // The progress line is cleared once we are done, irrespective of
+  // whether we succeeded.
+  let progress = config
+    .progress
+    .then(|| progress::Progress::new(diffs.len()));
+  let report = |count, diff: &Hunk| {
+    if let Some(progress) = &progress {
+      let () = progress.report(count, diff);
+    }
+  };
+
+  #[cfg(feature = "json")]
+  if config.format == OutputFormat::Json {
+    let diffs = diffs.iter().enumerate().map(|(idx, diff)| {
+      let () = report(idx + 1, diff);
+      Ok(diff)
+    });
+    return json::write_json(diffs, &args, config, writer)
+  }
+
+  if config.jobs > 1 && diffs.len() > 1 {
+    blame_concurrently(diffs, &args, config, progress.as_ref(), writer)
+  } else {
+    for (idx, diff) in diffs.iter().enumerate() {
+      let () = report(idx + 1, diff);
+      let () = blame_diff(&diff.src, &diff.dst, &args, config, writer)?;
+    }
+    Ok(())
+  }
+}
+
+
+/// Annotate all the diff hunks using up to `config.jobs` threads, each
+/// running one `git` process at a time, and write the results to
+/// `writer` in the original order.
+///
+/// If provided, `progress` is updated whenever annotating a diff
+/// finished.
+fn blame_concurrently<W>(
+  diffs: &[Hunk],
+  args: &[OsString],
+  config: &BlameConfig,
+  progress: Option<&progress::Progress>,
+  writer: &mut W,
+) -> Result<()>
+where
+  W: Write,
+{
+  let next = AtomicUsize::new(0);
+  let done = AtomicUsize::new(0);
+  let failed = AtomicBool::new(false);
+  let (sender, receiver) = channel();
+
+  let () = thread::scope(|scope| {
+    for _ in 0..config.jobs.min(diffs.len()) {
+      let sender = sender.clone();
+      let next = &next;
+      let done = &done;
+      let failed = &failed;
+
+      scope.spawn(move || {
+        // Once a single invocation failed we stop picking up new work,
+        // but all already running processes are still awaited.
+        while !failed.load(Ordering::Relaxed) {
+          let idx = next.fetch_add(1, Ordering::Relaxed);
+          let Some(diff) = diffs.get(idx) else { break };
+
+          let mut output = Vec::new();
+          let result = blame_diff(&diff.src, &diff.dst, args, config, &mut output).map(|()| output);
+          if let Some(progress) = progress {
+            let () = progress.report(done.fetch_add(1, Ordering::Relaxed) + 1, diff);
+          }
+          if result.is_err() {
+            let () = failed.store(true, Ordering::Relaxed);
+          }
+          // The receiver outlives all threads, so sending can't fail.
+          let _result = sender.send((idx, result));
+        }
+      });
+    }
+  });
+  drop(sender);
+
+  // Work is handed out in order, so all diffs preceding a failed one
+  // are guaranteed to have been annotated.
+  let mut results = receiver.into_iter().collect::<Vec<_>>();
+  let () = results.sort_unstable_by_key(|(idx, _result)| *idx);
+  for (_idx, result) in results {
+    let () = writer.write_all(&result?)?;
+  }
+  Ok(())
+}
+
+
+/// Annotate a single diff, writing the result to `writer`.
+fn blame_diff<W>(
+  src: &File,
+  dst: &File,
+  args: &[OsString],
+  config: &BlameConfig,
+  writer: &mut W,
+) -> Result<()>
+where
+  W: Write,
+{
+  // Newly added files have no history to annotate as part of staged
+  // changes.
+  if config.staged && src.file.as_str() == "/dev/null" {
+    return Ok(())
+  }
+
+  // When annotating in reverse only removed lines are of interest.
+  let target = if config.reverse.is_some() {
+    if src.count == 0 {
+      return Ok(())
+    }
+    BlameTarget::Source
+  } else {
+    config.target
+  };
+
+  // TODO: We should print the file header only once.
+  config
+    .template
+    .render(src, dst, writer, |writer| match target {
+      BlameTarget::Source => blame_side(src, Op::Sub, args, config, writer),
+      BlameTarget::Destination => blame_side(dst, Op::Add, args, config, writer),
+      BlameTarget::Both => {
+        let () = blame_side(src, Op::Sub, args, config, writer)?;
+        writeln!(writer, "{TARGET_SEPARATOR}")?;
+        blame_side(dst, Op::Add, args, config, writer)
+      },
+    })
+}
+
+
+/// Determine the range of lines to annotate for `file`, expanded by
+/// `context` lines in both directions.
+///
+/// The start of the range is clamped to the first line, cutting off
+/// the leading context, but the end is left as is: `git blame` copes
+/// with ranges extending past the end of a file.
+fn expand_range(file: &File, context: usize) -> Range<usize> {
+  if context == 0 {
+    return file.line..file.line + file.count
+  }
+  let start = file.line.saturating_sub(context).max(1);
+  start..file.line + file.count + context
+}
+
+
+/// Annotate one side of a diff, writing the result to `writer`.
+///
+/// The source side (`op` is [`Op::Sub`]) is annotated as of the
+/// configured revision, unless annotating in reverse, while the
+/// destination side (`op` is [`Op::Add`]) is annotated as present in
+/// the working tree or, for staged changes, the index.
+fn blame_side<W>(
+  file: &File,
+  op: Op,
+  args: &[OsString],
+  config: &BlameConfig,
+  writer: &mut W,
+) -> Result<()>
+where
+  W: Write,
+{
+  if file.op != op {
+    return Err(Error::Io(io::Error::new(
+      ErrorKind::InvalidData,
+      format!(
+        "encountered unexpected operation `{}` for {}",
+        file.op, file.file
+      ),
+    )))
+  }
+
+  let (path, revision) = match op {
+    // When annotating in reverse, the revision range is part of the
+    // arguments already.
+    Op::Sub => (
+      file.blame_file(),
+      config.reverse.is_none().then(|| config.revision()),
+    ),
+    Op::Add => (file.file.as_str(), None),
+  };
+
+  let range = expand_range(file, config.context_lines);
+  // `git blame` refuses to annotate an empty range of lines, so sides
+  // without any, e.g., that of a newly added or a deleted file, are
+  // skipped over.
+  if file.file.as_str() == "/dev/null" || range.is_empty() {
+    return Ok(())
+  }
+
+  // When annotating staged changes, the destination side refers to
+  // the content of the index, which we provide to `git blame`
+  // explicitly.
+  let contents = if op == Op::Add && config.staged {
+    Some(index_contents(config, path)?)
+  } else {
+    None
+  };
+  let contents = contents.as_deref();
+
+  let end = file.line + file.count;
+  // Additionally requested context lines count as context just like
+  // those contained in the diff.
+  let (above, below) = if config.annotate_context {
+    let above = range.start..(file.line + file.leading_context).max(range.start);
+    let below = end.saturating_sub(file.trailing_context).max(above.end)..range.end;
+    (above, below)
+  } else {
+    (range.start..range.start, range.end..range.end)
+  };
+
+  // `git blame` refuses ranges starting past the end of the file,
+  // which lines below the hunk that are not part of the diff may well
+  // do. In such a case we start the range at the last line of the hunk
+  // instead, which is known to exist, and skip it in the output.
+  let anchored = file.trailing_context == 0 && file.count > 0 && !below.is_empty();
+  let (below_range, skip) = if anchored {
+    (below.start - 1..below.end, below.start > above.end)
+  } else {
+    (below.clone(), false)
+  };
+
+  // Context lines are annotated separately from the changed ones,
+  // with a single invocation covering those above and below.
+  let context = [above.clone(), below_range]
+    .into_iter()
+    .filter(|range| !range.is_empty())
+    .collect::<Vec<_>>();
+  let context = if !context.is_empty() {
+    annotate(config, path, revision, &context, args, contents)?
+  } else {
+    Vec::new()
+  };
+  let context = String::from_utf8_lossy(&context);
+  let mut context = context.lines();
+
+  for line in context.by_ref().take(above.len()) {
+    writeln!(writer, "{CONTEXT_INDENT}{line}")?;
+  }
+  // Make sure the writer is flushed properly before invoking a git
+  // command, so that our output is not held back for its duration.
+  let () = writer.flush()?;
+
+  // Invoke git with the appropriate options to annotate the lines of
+  // the diff.
+  // Note that when annotating context lines separately the remaining
+  // range of changed lines may well be empty, e.g., for a pure
+  // addition.
+  let changed = above.end..below.start;
+  if !config.annotate_context || !changed.is_empty() {
+    let output = annotate(config, path, revision, &[changed], args, contents)?;
+    let () = writer.write_all(&output)?;
+  }
+
+  if skip {
+    let _last = context.next();
+  }
+  for line in context {
+    writeln!(writer, "{CONTEXT_INDENT}{line}")?;
+  }
+  Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::sync::Arc;
+
+
+  /// Check that ignoring revisions is rejected for `git` versions not
+  /// supporting it.
+  #[test]
+  fn ignore_revs_git_version() {
+    let config = BlameConfig {
+      repo: Some(RepoContext {
+        git_version: (2, 22, 5),
+        repo_root: PathBuf::from("/"),
+        is_inside_work_tree: true,
+      }),
+      ignore_revs: vec!["HEAD".to_string()],
+      ..Default::default()
+    };
+    let error = config.validate().unwrap_err();
+    assert!(
+      error.to_string().contains("requires git 2.23.0 or newer"),
+      "{error}"
+    );
+  }
+
+  /// Check that format templates are parsed correctly.
+  #[test]
+  fn format_template_parsing() {
+    let template = FormatTemplate::parse(r"--- {src_file}\n+++ {dst_file}\n{blame}").unwrap();
+    assert_eq!(template, FormatTemplate::default());
+
+    let template = FormatTemplate::parse("{blame}").unwrap();
+    assert_eq!(template.pieces, [Piece::Blame]);
+
+    let template = FormatTemplate::parse(r"{{{src_file}}}\t\\\x").unwrap();
+    assert_eq!(
+      template.pieces,
+      [
+        Piece::Literal("{".to_string()),
+        Piece::SrcFile,
+        Piece::Literal("}\t\\\\x".to_string()),
+      ]
+    );
+
+    let template = FormatTemplate::parse("{func}").unwrap();
+    assert_eq!(template.pieces, [Piece::Func]);
+
+    assert!(FormatTemplate::parse("{function}").is_err());
+    assert!(FormatTemplate::parse("{blame").is_err());
+    assert!(FormatTemplate::parse("blame}").is_err());
+  }
+
+  /// Check that paths are converted to use forward slashes.
+  #[test]
+  fn git_path_conversion() {
+    assert_eq!(to_git_path(Path::new("main.c")), "main.c");
+    assert_eq!(to_git_path(Path::new("src/main.c")), "src/main.c");
+    assert_eq!(
+      to_git_path(&Path::new("src").join("bin").join("main.c")),
+      "src/bin/main.c"
+    );
+  }
+
+  /// Check that backslashes are treated as separators on Windows.
+  #[cfg(target_os = "windows")]
+  #[test]
+  fn git_path_conversion_windows() {
+    assert_eq!(to_git_path(Path::new(r"src\main.c")), "src/main.c");
+    assert_eq!(to_git_path(Path::new(r"C:\src\main.c")), "C:/src/main.c");
+  }
+
+  /// Check that backslashes are preserved on Unix systems, where they
+  /// are valid file name characters.
+  #[cfg(not(target_os = "windows"))]
+  #[test]
+  fn git_path_conversion_unix() {
+    assert_eq!(to_git_path(Path::new(r"src\main.c")), r"src\main.c");
+  }
+
+  /// Check that line ranges are expanded by the configured number of
+  /// context lines as expected.
+  #[test]
+  fn range_expansion() {
+    fn line_args(line: usize, count: usize, context: usize) -> Vec<OsString> {
+      let file = File {
+        file: Arc::new("main.c".to_string()),
+        op: Op::Sub,
+        line,
+        count,
+        leading_context: 0,
+        trailing_context: 0,
+        original_file: None,
+        func_context: None,
+        content: Vec::new(),
+      };
+      let config = BlameConfig::default();
+      let range = expand_range(&file, context);
+      let command = blame_command(&config, &file.file, None, &[range], Vec::<OsString>::new());
+      command
+        .get_args()
+        .filter(|arg| arg.to_string_lossy().starts_with("-L"))
+        .map(OsStr::to_os_string)
+        .collect()
+    }
+
+    assert_eq!(line_args(6, 6, 0), ["-L6,+6"]);
+    assert_eq!(line_args(6, 6, 2), ["-L4,+10"]);
+    assert_eq!(line_args(2, 1, 3), ["-L1,+5"]);
+    assert_eq!(line_args(1, 4, 3), ["-L1,+7"]);
+  }
+}
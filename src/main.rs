@@ -1,20 +1,762 @@
 // Copyright (C) 2022 Daniel Mueller <deso@posteo.net>
 // SPDX-License-Identifier: GPL-3.0-or-later
 
-use std::env::args;
+use std::env::args_os;
+use std::env::current_dir;
+use std::env::var_os;
+use std::ffi::OsStr;
+use std::ffi::OsString;
+use std::fs::read;
+use std::fs::File;
+use std::io::stderr;
 use std::io::stdin;
-use std::io::Result;
+use std::io::stdout;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::IsTerminal as _;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Child;
+use std::process::Command;
+use std::process::ExitCode;
+use std::process::Stdio;
+use std::str::FromStr;
 
-use diff_parse::Parser;
-use git_blamediff::blame;
+use git_blamediff::await_child;
+#[cfg(feature = "interactive")]
+use git_blamediff::blame_interactive;
+use git_blamediff::blame_summary_with_config;
+#[cfg(feature = "json")]
+use git_blamediff::blame_to_json;
+use git_blamediff::blame_to_markdown;
+use git_blamediff::blame_with_config;
+use git_blamediff::blame_with_history;
+use git_blamediff::filter_whitespace_hunks;
+#[cfg(feature = "color")]
+use git_blamediff::AgeColorizer;
+use git_blamediff::BlameConfig;
+use git_blamediff::BlameTarget;
+use git_blamediff::FormatTemplate;
+use git_blamediff::Hunk;
+use git_blamediff::OutputFormat;
+use git_blamediff::Parser;
+use git_blamediff::RangeDiffParser;
+use git_blamediff::RangeDiffSide;
+use git_blamediff::RepoContext;
+use git_blamediff::Result;
+
+
+/// The prefix of the option selecting the output format.
+const FORMAT_ARG: &str = "--format=";
+/// The prefix of the option controlling the coloring of annotations.
+#[cfg(feature = "color")]
+const COLOR_ARG: &str = "--color=";
+/// The option requesting separate annotation of context lines.
+const ANNOTATE_CONTEXT_ARG: &str = "--annotate-context";
+/// The prefix of the option selecting the side of diffs to annotate.
+const BLAME_TARGET_ARG: &str = "--blame-target=";
+/// The option requesting that root commits not be treated as
+/// boundaries.
+const BLAME_ROOT_ARG: &str = "--blame-root";
+/// The prefix of the option providing the template to format the
+/// output for each diff with.
+const TEMPLATE_ARG: &str = "--template=";
+/// The option requesting that the history of lines be traced across
+/// file renames.
+const FOLLOW_HISTORY_ARG: &str = "--follow-history";
+/// The prefix of the option providing the revision range in which to
+/// look for the last commit still containing each removed line.
+const REVERSE_ARG: &str = "--reverse=";
+/// The prefix of the option providing the revision as of which to
+/// annotate lines.
+const AT_ARG: &str = "--at=";
+/// The prefix of the option providing the commit preceding the changes
+/// described by the diff, e.g., `HEAD~1` for `git diff HEAD~1 HEAD`.
+/// It is a synonym for `--at=`.
+const BASE_COMMIT_ARG: &str = "--base-commit=";
+/// The option requesting that a summary of the commits and authors
+/// involved be printed after the annotations.
+const SUMMARY_ARG: &str = "--summary";
+/// The option requesting that only said summary be printed.
+const SUMMARY_ONLY_ARG: &str = "--summary-only";
+/// The prefix of the option providing the number of additional lines
+/// above and below each hunk to annotate.
+const CONTEXT_ARG: &str = "--context=";
+/// The prefix of the option providing a revision to ignore when
+/// assigning blame. May be provided multiple times.
+const IGNORE_REV_ARG: &str = "--ignore-rev=";
+/// The option requesting that output not be paged.
+const NO_PAGER_ARG: &str = "--no-pager";
+/// The environment variable that can be set to a non-empty value to
+/// disable paging of output.
+const NO_PAGER_ENV: &str = "GIT_BLAMEDIFF_NO_PAGER";
+/// The pager to use if none is configured.
+const DEFAULT_PAGER: &str = "less -FRX";
+/// The option requesting that hunks merely changing whitespace be
+/// skipped.
+const IGNORE_WHITESPACE_HUNKS_ARG: &str = "--ignore-whitespace-hunks";
+/// The prefix of the option providing the label to print instead of
+/// the all-zeros commit hash.
+const ROOT_LABEL_ARG: &str = "--root-label=";
+/// The option requesting that progress be reported on stderr.
+const PROGRESS_ARG: &str = "--progress";
+/// The number of diffs above which progress is reported by default.
+const PROGRESS_THRESHOLD: usize = 10;
+/// The option requesting that malformed input be skipped over with a
+/// warning instead of causing a failure.
+const LENIENT_ARG: &str = "--lenient";
+/// The option indicating that the input is the output of
+/// `git range-diff`.
+const RANGE_DIFF_ARG: &str = "--range-diff";
+/// The prefix of the option selecting the side of a range-diff whose
+/// commits to annotate.
+const RANGE_DIFF_SIDE_ARG: &str = "--range-diff-side=";
+/// The option indicating that the diff describes staged changes.
+const STAGED_ARG: &str = "--staged";
+/// The option requesting that the user be prompted about each hunk
+/// before annotating it.
+#[cfg(feature = "interactive")]
+const INTERACTIVE_ADD_ARG: &str = "--interactive-add";
+/// `git blame` options expecting their value as a separate argument.
+const BLAME_VALUE_ARGS: [&str; 5] = [
+  "-L",
+  "-S",
+  "--contents",
+  "--ignore-rev",
+  "--ignore-revs-file",
+];
+/// The path to the terminal device to read user input from. Standard
+/// input is used up by the diff already.
+#[cfg(all(feature = "interactive", not(windows)))]
+const TERMINAL: &str = "/dev/tty";
+#[cfg(all(feature = "interactive", windows))]
+const TERMINAL: &str = "CONIN$";
+
+
+/// An enumeration of the supported output formats.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+  /// The output of `git blame`, as is.
+  Plain,
+  /// The output of `git blame --porcelain`.
+  Porcelain,
+  /// A series of Markdown code blocks.
+  Markdown,
+  /// A JSON array of annotated hunks.
+  #[cfg(feature = "json")]
+  Json,
+}
+
+impl FromStr for Format {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "plain" => Ok(Self::Plain),
+      "porcelain" => Ok(Self::Porcelain),
+      "markdown" => Ok(Self::Markdown),
+      #[cfg(feature = "json")]
+      "json" => Ok(Self::Json),
+      _ => Err(Error::new(
+        ErrorKind::InvalidInput,
+        format!("unsupported output format: {s}"),
+      )),
+    }
+  }
+}
+
+
+/// An enumeration of the possible choices for coloring annotations.
+#[cfg(feature = "color")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ColorMode {
+  /// Color annotations only if stdout is a terminal.
+  Auto,
+  /// Always color annotations.
+  Always,
+  /// Never color annotations.
+  Never,
+}
+
+#[cfg(feature = "color")]
+impl ColorMode {
+  /// Check whether annotations should be colored.
+  fn enabled(self) -> bool {
+    match self {
+      Self::Auto => stdout().is_terminal(),
+      Self::Always => true,
+      Self::Never => false,
+    }
+  }
+}
+
+#[cfg(feature = "color")]
+impl FromStr for ColorMode {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "auto" => Ok(Self::Auto),
+      "always" => Ok(Self::Always),
+      "never" => Ok(Self::Never),
+      _ => Err(Error::new(
+        ErrorKind::InvalidInput,
+        format!("unsupported color mode: {s}"),
+      )),
+    }
+  }
+}
+
+
+/// Parse the value of the option selecting the side of diffs to
+/// annotate.
+fn parse_target(s: &str) -> Result<BlameTarget, Error> {
+  match s {
+    "src" => Ok(BlameTarget::Source),
+    "dst" => Ok(BlameTarget::Destination),
+    "both" => Ok(BlameTarget::Both),
+    _ => Err(Error::new(
+      ErrorKind::InvalidInput,
+      format!("unsupported blame target: {s}"),
+    )),
+  }
+}
+
+
+/// Parse the value of the option selecting the side of a range-diff
+/// whose commits to annotate.
+fn parse_range_diff_side(s: &str) -> Result<RangeDiffSide, Error> {
+  match s {
+    "new" => Ok(RangeDiffSide::New),
+    "old" => Ok(RangeDiffSide::Old),
+    "both" => Ok(RangeDiffSide::Both),
+    _ => Err(Error::new(
+      ErrorKind::InvalidInput,
+      format!("unsupported range-diff side: {s}"),
+    )),
+  }
+}
+
+
+/// Parse the value of the option providing the number of additional
+/// context lines to annotate.
+fn parse_context(s: &str) -> Result<usize, Error> {
+  usize::from_str(s).map_err(|err| {
+    Error::new(
+      ErrorKind::InvalidInput,
+      format!("invalid number of context lines: {s}: {err}"),
+    )
+  })
+}
+
+
+/// Retrieve the arguments provided to the program that may contain
+/// options, i.e., those preceding a `--` separator.
+///
+/// Arguments that are not valid Unicode, such as some paths, can't be
+/// options of ours and are skipped.
+fn option_args() -> impl Iterator<Item = String> {
+  args_os()
+    .skip(1)
+    .take_while(|arg| arg != "--")
+    .filter_map(|arg| arg.into_string().ok())
+}
+
+
+/// Separate the arguments to pass through to `git blame` from the
+/// paths of files to read diffs from.
+///
+/// Arguments following a `--` separator are always treated as paths.
+/// Preceding ones are treated as such if they don't look like an
+/// option or an option's value and refer to an existing file.
+fn split_args() -> (Vec<OsString>, Vec<PathBuf>) {
+  let mut blame_args = Vec::new();
+  let mut paths = Vec::new();
+  let mut args = args_os().skip(1);
+
+  while let Some(arg) = args.next() {
+    if arg == "--" {
+      let () = paths.extend(args.map(PathBuf::from));
+      break
+    }
+
+    if arg.to_str().is_some_and(is_own_arg) {
+      continue
+    }
+
+    if BLAME_VALUE_ARGS.iter().any(|value_arg| arg == *value_arg) {
+      let () = blame_args.push(arg);
+      let () = blame_args.extend(args.next());
+    } else if !arg.to_string_lossy().starts_with('-') && Path::new(&arg).is_file() {
+      let () = paths.push(PathBuf::from(arg));
+    } else {
+      let () = blame_args.push(arg);
+    }
+  }
+  (blame_args, paths)
+}
+
+
+/// Check whether `arg` is an option interpreted by the program itself,
+/// as opposed to one to be passed through to `git blame`.
+fn is_own_arg(arg: &str) -> bool {
+  #[cfg(feature = "color")]
+  if arg.starts_with(COLOR_ARG) {
+    return true
+  }
+  #[cfg(feature = "interactive")]
+  if arg == INTERACTIVE_ADD_ARG {
+    return true
+  }
+
+  arg.starts_with(FORMAT_ARG)
+    || arg == ANNOTATE_CONTEXT_ARG
+    || arg == BLAME_ROOT_ARG
+    || arg.starts_with(BLAME_TARGET_ARG)
+    || arg == FOLLOW_HISTORY_ARG
+    || arg.starts_with(TEMPLATE_ARG)
+    || arg.starts_with(REVERSE_ARG)
+    || arg.starts_with(AT_ARG)
+    || arg.starts_with(BASE_COMMIT_ARG)
+    || arg == SUMMARY_ARG
+    || arg == SUMMARY_ONLY_ARG
+    || arg.starts_with(CONTEXT_ARG)
+    || arg == STAGED_ARG
+    || arg == IGNORE_WHITESPACE_HUNKS_ARG
+    || arg == LENIENT_ARG
+    || arg == RANGE_DIFF_ARG
+    || arg.starts_with(RANGE_DIFF_SIDE_ARG)
+    || arg == PROGRESS_ARG
+    || arg.starts_with(ROOT_LABEL_ARG)
+    || arg == NO_PAGER_ARG
+    || arg.starts_with(IGNORE_REV_ARG)
+}
+
+
+/// Determine the pager to use, given the values of the `GIT_PAGER` and
+/// `PAGER` environment variables.
+fn pager_command(git_pager: Option<OsString>, pager: Option<OsString>) -> Option<OsString> {
+  let pager = git_pager
+    .or(pager)
+    .unwrap_or_else(|| OsString::from(DEFAULT_PAGER));
+  // Just as with `git`, an empty pager or `cat` disable paging.
+  (!pager.is_empty() && pager != "cat").then_some(pager)
+}
+
+
+/// Determine whether to report progress, given whether stderr is a
+/// terminal, whether progress was explicitly requested, and the number
+/// of diffs to annotate.
+///
+/// Progress is only ever reported to a terminal. Unless explicitly
+/// requested, it is only reported for more than
+/// [`PROGRESS_THRESHOLD`] diffs.
+fn report_progress(stderr_tty: bool, requested: bool, count: usize) -> bool {
+  stderr_tty && (requested || count > PROGRESS_THRESHOLD)
+}
+
+
+/// Spawn the configured pager, if output should be paged.
+fn spawn_pager() -> Result<Option<Child>> {
+  let paging = stdout().is_terminal()
+    && !option_args().any(|arg| arg == NO_PAGER_ARG)
+    && var_os(NO_PAGER_ENV).is_none_or(|value| value.is_empty());
+  if !paging {
+    return Ok(None)
+  }
+
+  let Some(pager) = pager_command(var_os("GIT_PAGER"), var_os("PAGER")) else {
+    return Ok(None)
+  };
+  let pager = pager.to_string_lossy();
+  let mut pager = pager.split_whitespace();
+  // It is fine to unwrap here because we know that the pager command
+  // is not empty.
+  let program = pager.next().unwrap();
+
+  let result = Command::new(program)
+    .args(pager)
+    .stdin(Stdio::piped())
+    .spawn();
+  match result {
+    Ok(child) => Ok(Some(child)),
+    // Without a pager we just write to stdout directly.
+    Err(error) if error.kind() == ErrorKind::NotFound => Ok(None),
+    Err(error) => Err(error.into()),
+  }
+}
+
+
+/// Parse diffs from `paths` or, if none are provided, from stdin.
+fn parse_input<F>(paths: &[PathBuf], mut parse: F) -> Result<()>
+where
+  F: FnMut(&mut dyn BufRead) -> Result<()>,
+{
+  if paths.is_empty() {
+    let () = parse(&mut stdin().lock())?;
+  } else {
+    for path in paths {
+      let () = parse(&mut BufReader::new(File::open(path)?))?;
+    }
+  }
+  Ok(())
+}
+
+
+/// Run `git` with the provided arguments, capturing its output.
+fn git_output<A, S>(config: &BlameConfig, args: A) -> Result<Vec<u8>>
+where
+  A: IntoIterator<Item = S>,
+  S: AsRef<OsStr>,
+{
+  let mut command = config.git_command();
+  let child = command
+    .args(args)
+    .stdin(Stdio::null())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()?;
+  await_child(command.get_program(), child)
+}
+
+
+/// Check whether `revision` exists in the repository.
+fn revision_exists(config: &BlameConfig, revision: &str) -> Result<bool> {
+  match git_output(config, ["rev-parse", "--verify", "--quiet", revision]) {
+    Ok(_output) => Ok(true),
+    Err(git_blamediff::Error::Process { .. }) => Ok(false),
+    Err(error) => Err(error),
+  }
+}
+
+
+/// Retrieve the content of `file` as of `revision`, if it exists.
+fn file_contents(config: &BlameConfig, revision: &str, file: &str) -> Result<Option<String>> {
+  // Paths are relative to the current working directory.
+  let object = format!("{revision}:./{file}");
+  match git_output(config, ["cat-file", "blob", &object]) {
+    Ok(content) => Ok(Some(String::from_utf8_lossy(&content).into_owned())),
+    Err(git_blamediff::Error::Process { .. }) => Ok(None),
+    Err(error) => Err(error),
+  }
+}
+
+
+/// Determine the start lines of the sides of `diff` to annotate as per
+/// `config`, unless known already.
+///
+/// `None` is returned if `diff` could not be located.
+fn locate_diff(diff: &Hunk, config: &BlameConfig) -> Result<Option<Hunk>> {
+  let Hunk { mut src, mut dst } = diff.clone();
+  let (locate_src, locate_dst) = match config.target {
+    BlameTarget::Source => (true, false),
+    BlameTarget::Destination => (false, true),
+    BlameTarget::Both => (true, true),
+  };
+
+  if locate_src && src.line == 0 && src.count > 0 {
+    let revision = config.revision.as_deref().unwrap_or("HEAD");
+    let content = file_contents(config, revision, src.blame_file())?;
+    if !content.is_some_and(|content| src.locate(&content)) {
+      return Ok(None)
+    }
+  }
+
+  if locate_dst && dst.line == 0 && dst.count > 0 {
+    // The destination side is annotated as present in the working
+    // tree.
+    let content = read(&*dst.file)
+      .ok()
+      .map(|content| String::from_utf8_lossy(&content).into_owned());
+    if !content.is_some_and(|content| dst.locate(&content)) {
+      return Ok(None)
+    }
+  }
+  Ok(Some(Hunk { src, dst }))
+}
 
 
 /// Parse the diff from stdin and invoke git blame on each hunk.
-fn main() -> Result<()> {
-  let mut parser = Parser::new();
-  parser.parse(stdin().lock())?;
+fn run() -> Result<()> {
+  let format = option_args()
+    .filter_map(|arg| arg.strip_prefix(FORMAT_ARG).map(Format::from_str))
+    .last()
+    .transpose()?
+    .unwrap_or(Format::Plain);
+  let target = option_args()
+    .filter_map(|arg| arg.strip_prefix(BLAME_TARGET_ARG).map(parse_target))
+    .last()
+    .transpose()?
+    .unwrap_or_default();
+  let template = option_args()
+    .filter_map(|arg| arg.strip_prefix(TEMPLATE_ARG).map(FormatTemplate::parse))
+    .last()
+    .transpose()?
+    .unwrap_or_default();
+  let reverse = option_args()
+    .filter_map(|arg| arg.strip_prefix(REVERSE_ARG).map(str::to_string))
+    .last();
+  let revision = option_args()
+    .filter_map(|arg| {
+      arg
+        .strip_prefix(AT_ARG)
+        .or_else(|| arg.strip_prefix(BASE_COMMIT_ARG))
+        .map(str::to_string)
+    })
+    .last();
+  let context_lines = option_args()
+    .filter_map(|arg| arg.strip_prefix(CONTEXT_ARG).map(parse_context))
+    .last()
+    .transpose()?
+    .unwrap_or_default();
+  let root_label = option_args()
+    .filter_map(|arg| arg.strip_prefix(ROOT_LABEL_ARG).map(str::to_string))
+    .last();
+  #[cfg(feature = "color")]
+  let color = option_args()
+    .filter_map(|arg| arg.strip_prefix(COLOR_ARG).map(ColorMode::from_str))
+    .last()
+    .transpose()?
+    .unwrap_or(ColorMode::Auto);
+  let config = BlameConfig {
+    annotate_context: option_args().any(|arg| arg == ANNOTATE_CONTEXT_ARG),
+    blame_root: option_args().any(|arg| arg == BLAME_ROOT_ARG),
+    target,
+    template,
+    reverse,
+    revision,
+    context_lines,
+    ignore_revs: option_args()
+      .filter_map(|arg| arg.strip_prefix(IGNORE_REV_ARG).map(str::to_string))
+      .collect(),
+    staged: option_args().any(|arg| arg == STAGED_ARG),
+    format: match format {
+      Format::Porcelain => OutputFormat::Porcelain,
+      _ => OutputFormat::Plain,
+    },
+    #[cfg(feature = "color")]
+    colorizer: color.enabled().then(AgeColorizer::new),
+    ..Default::default()
+  };
+  let config = match root_label {
+    Some(root_label) => BlameConfig {
+      root_label,
+      ..config
+    },
+    None => config,
+  };
+  // Meta data about the repository are detected once up front. Failure
+  // to do so is not fatal here, as `git` reports a more helpful error
+  // once actually invoked.
+  let repo = RepoContext::detect(&config.git_path, &current_dir()?).ok();
+  let config = BlameConfig { repo, ..config };
+  let follow_history = option_args().any(|arg| arg == FOLLOW_HISTORY_ARG);
+  let summary = option_args().any(|arg| arg == SUMMARY_ARG);
+  let summary_only = option_args().any(|arg| arg == SUMMARY_ONLY_ARG);
+  // All arguments not interpreted by us are passed through to
+  // `git blame`.
+  let (blame_args, paths) = split_args();
+
+  let new_parser = || {
+    if option_args().any(|arg| arg == LENIENT_ARG) {
+      Parser::new_lenient()
+    } else {
+      Parser::new()
+    }
+  };
+
+  // Each section consists of a set of diffs along with the
+  // configuration to annotate them with.
+  let mut sections = Vec::new();
+  if option_args().any(|arg| arg == RANGE_DIFF_ARG) {
+    #[cfg(feature = "json")]
+    if format == Format::Json {
+      return Err(
+        Error::new(
+          ErrorKind::InvalidInput,
+          "range-diffs cannot be annotated in JSON format",
+        )
+        .into(),
+      )
+    }
+
+    let side = option_args()
+      .filter_map(|arg| {
+        arg
+          .strip_prefix(RANGE_DIFF_SIDE_ARG)
+          .map(parse_range_diff_side)
+      })
+      .last()
+      .transpose()?
+      .unwrap_or_default();
+    let mut parser = RangeDiffParser::new();
+    let () = parse_input(&paths, |input| parser.parse(input))?;
+
+    for (commit, diffs) in parser.patches(side) {
+      // The patches embedded in a range-diff apply to the parents of
+      // the commits. Commits may not be available locally, though,
+      // e.g., if the range-diff was received by mail, and root commits
+      // lack a parent. We fall back to the configured revision then.
+      let parent = format!("{commit}^");
+      let config = if revision_exists(&config, &parent)? {
+        BlameConfig {
+          revision: Some(parent),
+          ..config.clone()
+        }
+      } else {
+        config.clone()
+      };
+
+      let mut located = Vec::with_capacity(diffs.len());
+      for diff in diffs {
+        match locate_diff(diff, &config)? {
+          Some(diff) => located.push(diff),
+          None => eprintln!(
+            "warning: failed to locate diff of {} from {commit}; skipping",
+            diff.src.file
+          ),
+        }
+      }
+      let () = sections.push((config, located));
+    }
+  } else {
+    let mut parser = new_parser();
+    let () = parse_input(&paths, |input| parser.parse(input))?;
+    let () = sections.push((config, parser.into_diffs()));
+  }
+
+  if option_args().any(|arg| arg == IGNORE_WHITESPACE_HUNKS_ARG) {
+    for (_, diffs) in &mut sections {
+      *diffs = filter_whitespace_hunks(diffs);
+    }
+  }
+
+  let count = sections.iter().map(|(_, diffs)| diffs.len()).sum::<usize>();
+  let progress = report_progress(
+    stderr().is_terminal(),
+    option_args().any(|arg| arg == PROGRESS_ARG),
+    count,
+  );
+  for (config, _) in &mut sections {
+    config.progress = progress;
+  }
+
+  // Fail early on an invalid configuration, before producing any
+  // output.
+  let () = sections
+    .iter()
+    .try_for_each(|(config, _)| config.validate())?;
+
+  #[cfg(feature = "interactive")]
+  let interactive = option_args().any(|arg| arg == INTERACTIVE_ADD_ARG);
+  #[cfg(not(feature = "interactive"))]
+  let interactive = false;
+
+  let emit = |out: &mut Box<dyn Write>, diffs: &[Hunk], config: &BlameConfig| -> Result<()> {
+    if !summary_only {
+      let () = match format {
+        #[cfg(feature = "interactive")]
+        Format::Plain if interactive => {
+          let input = BufReader::new(File::open(TERMINAL)?);
+          blame_interactive(diffs, &blame_args, config, input, &mut *out)
+        },
+        Format::Plain if follow_history => {
+          for Hunk { src, dst } in diffs.iter() {
+            let () = config.template.render(src, dst, out, |out| {
+              let range = src.line..src.line + src.count;
+              blame_with_history(src.blame_file(), range, config, out)
+            })?;
+          }
+          Ok(())
+        },
+        Format::Plain | Format::Porcelain => blame_with_config(diffs, &blame_args, config, out),
+        Format::Markdown => blame_to_markdown(diffs, config, out),
+        #[cfg(feature = "json")]
+        Format::Json => blame_to_json(diffs, config, out),
+      }?;
+    }
+
+    if summary || summary_only {
+      let summary = blame_summary_with_config(diffs, &blame_args, config)?;
+      writeln!(out, "{summary}")?;
+    }
+    let () = out.flush()?;
+    Ok(())
+  };
+
+  // Interactive mode prompts the user on the terminal, so paging does
+  // not mix well with it.
+  let mut pager = if !interactive { spawn_pager()? } else { None };
+  let mut out: Box<dyn Write> = match &mut pager {
+    // It is fine to unwrap here because we know that we captured the
+    // pager's stdin.
+    Some(pager) => Box::new(BufWriter::new(pager.stdin.take().unwrap())),
+    None => Box::new(stdout().lock()),
+  };
+  let result = sections
+    .iter()
+    .try_for_each(|(config, diffs)| emit(&mut out, diffs, config));
+
+  // Close the pipe to signal the end of output to the pager and wait
+  // for the user to quit it. Should the user quit early, writing fails
+  // with a broken pipe error, which we treat as success.
+  drop(out);
+  if let Some(mut pager) = pager {
+    let _status = pager.wait()?;
+  }
+  result
+}
+
+
+fn main() -> ExitCode {
+  match run() {
+    Ok(()) => ExitCode::SUCCESS,
+    // Our output got closed early, e.g., because it is piped into
+    // `head`. That is not an error from the user's perspective.
+    Err(git_blamediff::Error::Io(error)) if error.kind() == ErrorKind::BrokenPipe => {
+      ExitCode::SUCCESS
+    },
+    Err(error) => {
+      eprintln!("{error}");
+      ExitCode::FAILURE
+    },
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+
+  /// Check that the pager to use is determined correctly.
+  #[test]
+  fn pager_selection() {
+    let pager = |git_pager: Option<&str>, pager: Option<&str>| {
+      pager_command(git_pager.map(OsString::from), pager.map(OsString::from))
+    };
+
+    assert_eq!(pager(None, None), Some(OsString::from(DEFAULT_PAGER)));
+    assert_eq!(pager(None, Some("more")), Some(OsString::from("more")));
+    assert_eq!(
+      pager(Some("less -R"), Some("more")),
+      Some(OsString::from("less -R"))
+    );
+    assert_eq!(pager(Some(""), Some("more")), None);
+    assert_eq!(pager(None, Some("cat")), None);
+  }
 
-  // TODO: We may want to catch BrokenPipe errors here and exit
-  //       gracefully.
-  blame(parser.diffs(), args)
+  /// Check that we decide correctly whether to report progress.
+  #[test]
+  fn progress_reporting() {
+    assert!(!report_progress(false, true, 100));
+    assert!(!report_progress(false, false, 100));
+    assert!(report_progress(true, true, 1));
+    assert!(!report_progress(true, false, PROGRESS_THRESHOLD));
+    assert!(report_progress(true, false, PROGRESS_THRESHOLD + 1));
+  }
 }
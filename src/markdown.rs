@@ -0,0 +1,108 @@
+// Copyright (C) 2026 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! A module for emitting blame information as Markdown.
+
+use std::io::Write;
+use std::ops::Deref as _;
+
+use crate::blame_capture;
+use crate::porcelain;
+use crate::BlameConfig;
+use crate::File;
+use crate::Hunk;
+use crate::Result;
+
+
+/// Format the range of lines covered by `file`, e.g., `L6-11`.
+fn range(file: &File) -> String {
+  match file.count {
+    0 | 1 => format!("L{}", file.line),
+    count => format!("L{}-{}", file.line, file.line + count - 1),
+  }
+}
+
+
+/// Determine the fence to use for a code block containing the provided
+/// lines, making sure that it is longer than any run of backticks in
+/// them.
+fn fence<'s, L>(lines: L) -> String
+where
+  L: IntoIterator<Item = &'s str>,
+{
+  let longest = lines
+    .into_iter()
+    .flat_map(|line| line.split(|c| c != '`'))
+    .map(str::len)
+    .max()
+    .unwrap_or_default();
+  "`".repeat(longest.max(2) + 1)
+}
+
+
+/// Annotate all the diff hunks and write the result to `writer` as a
+/// series of fenced Markdown code blocks, one per hunk.
+///
+/// Each block has the form:
+/// ````text
+/// ```blame
+/// # src: file.c L6-11
+/// # dst: file.c L6-11
+/// <commit> <author> <date> <line>) <content>
+/// ```
+/// ````
+pub fn blame_to_markdown<W>(diffs: &[Hunk], config: &BlameConfig, writer: &mut W) -> Result<()>
+where
+  W: Write,
+{
+  // `git blame` refuses to annotate an empty range of lines, as is the
+  // case for newly added files, so such diffs are skipped.
+  let diffs = diffs.iter().filter(|Hunk { src, .. }| src.count > 0);
+  for (idx, Hunk { src, dst }) in diffs.enumerate() {
+    let output = blame_capture(config, src, ["--line-porcelain"])?;
+    let output = String::from_utf8_lossy(&output);
+    let lines = porcelain::parse(&output)?;
+    let width = lines
+      .iter()
+      .map(|line| line.line_number.to_string().len())
+      .max()
+      .unwrap_or_default();
+
+    let fence = fence(lines.iter().map(|line| line.content.as_str()));
+
+    if idx > 0 {
+      writeln!(writer)?;
+    }
+    writeln!(writer, "{fence}blame")?;
+    writeln!(writer, "# src: {} {}", src.file.deref(), range(src))?;
+    writeln!(writer, "# dst: {} {}", dst.file.deref(), range(dst))?;
+    for line in lines {
+      writeln!(
+        writer,
+        "{} {} {} {:>width$}) {}",
+        line.commit,
+        line.author,
+        line.author_date(),
+        line.line_number,
+        line.content,
+      )?;
+    }
+    writeln!(writer, "{fence}")?;
+  }
+  Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+
+  /// Check that code block fences are long enough.
+  #[test]
+  fn code_block_fence() {
+    assert_eq!(fence([]), "```");
+    assert_eq!(fence(["let s = `foo`;"]), "```");
+    assert_eq!(fence(["```rust", "``"]), "````");
+  }
+}
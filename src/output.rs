@@ -0,0 +1,35 @@
+// Copyright (C) 2026 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! A module for the formats in which annotations can be emitted.
+
+
+/// An enumeration of the supported output formats.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OutputFormat {
+  /// The output of `git blame -s`, i.e., without author and date
+  /// information.
+  #[default]
+  Plain,
+  /// The output of `git blame --porcelain`, meant for consumption by
+  /// other programs.
+  Porcelain,
+  /// A JSON array of annotated hunks, as produced by
+  /// [`blame_to_json`][crate::blame_to_json].
+  #[cfg(feature = "json")]
+  Json,
+}
+
+impl OutputFormat {
+  /// Retrieve the argument instructing `git blame` to produce output
+  /// suitable for the format.
+  pub(crate) fn blame_arg(self) -> &'static str {
+    match self {
+      Self::Plain => "-s",
+      Self::Porcelain => "--porcelain",
+      // We need the full commit information for each line.
+      #[cfg(feature = "json")]
+      Self::Json => "--line-porcelain",
+    }
+  }
+}
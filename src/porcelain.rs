@@ -0,0 +1,217 @@
+// Copyright (C) 2026 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! A module for parsing the output of `git blame --line-porcelain`.
+
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+
+
+/// A single line as annotated by `git blame --line-porcelain`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PorcelainLine {
+  /// The SHA-1 of the commit that last modified the line.
+  pub commit: String,
+  /// The author of said commit.
+  pub author: String,
+  /// The time the commit was authored, in seconds since the Unix
+  /// epoch.
+  pub author_time: i64,
+  /// The offset of the author's time zone from UTC, in minutes.
+  pub author_tz: i32,
+  /// The number of the line in the annotated file.
+  pub line_number: usize,
+  /// The content of the line.
+  pub content: String,
+}
+
+impl PorcelainLine {
+  /// Format the date the commit was authored on, as `YYYY-MM-DD` in the
+  /// author's time zone.
+  pub fn author_date(&self) -> String {
+    let time = self.author_time + i64::from(self.author_tz) * 60;
+    let (year, month, day) = civil_from_days(time.div_euclid(86400));
+    format!("{year:04}-{month:02}-{day:02}")
+  }
+}
+
+
+/// Convert a number of days since the Unix epoch into a
+/// (year, month, day) triple in the proleptic Gregorian calendar.
+// See http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+  let days = days + 719468;
+  let era = days.div_euclid(146097);
+  let doe = days.rem_euclid(146097);
+  let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let day = doy - (153 * mp + 2) / 5 + 1;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 };
+  let year = yoe + era * 400 + i64::from(month <= 2);
+  (year, month as u32, day as u32)
+}
+
+
+/// Parse a time zone offset of the form `+HHMM` or `-HHMM` into the
+/// number of minutes it represents.
+fn parse_tz(tz: &str) -> Option<i32> {
+  let (sign, tz) = match tz.split_at_checked(1)? {
+    ("+", tz) => (1, tz),
+    ("-", tz) => (-1, tz),
+    _ => return None,
+  };
+  let hours = tz.get(0..2)?.parse::<i32>().ok()?;
+  let minutes = tz.get(2..4)?.parse::<i32>().ok()?;
+  Some(sign * (hours * 60 + minutes))
+}
+
+
+/// Parse the output of `git blame --line-porcelain` into a list of
+/// [`PorcelainLine`] objects.
+pub fn parse(output: &str) -> Result<Vec<PorcelainLine>> {
+  let mut lines = Vec::new();
+  // The commit and line number of the line currently being parsed, as
+  // well as its author. In line porcelain mode every line is preceded
+  // by a header containing this information.
+  let mut header = None;
+  let mut author = String::new();
+  let mut author_time = 0;
+  let mut author_tz = 0;
+
+  for line in output.lines() {
+    if let Some(content) = line.strip_prefix('\t') {
+      let (commit, number) = header.take().ok_or_else(|| {
+        Error::new(
+          ErrorKind::InvalidData,
+          format!(r#"encountered content line without header: "{line}""#),
+        )
+      })?;
+
+      let line = PorcelainLine {
+        commit,
+        author: author.clone(),
+        author_time,
+        author_tz,
+        line_number: number,
+        content: content.to_string(),
+      };
+      lines.push(line);
+    } else if header.is_none() {
+      // The header line has the format:
+      // <sha1> <source line> <result line> [<group line count>]
+      let mut parts = line.split(' ');
+      let commit = parts.next().unwrap_or_default();
+      let number = parts
+        .nth(1)
+        .and_then(|number| number.parse().ok())
+        .ok_or_else(|| {
+          Error::new(
+            ErrorKind::InvalidData,
+            format!(r#"encountered malformed header: "{line}""#),
+          )
+        })?;
+
+      header = Some((commit.to_string(), number));
+    } else if let Some(name) = line.strip_prefix("author ") {
+      author = name.to_string();
+    } else if let Some(time) = line.strip_prefix("author-time ") {
+      author_time = time.parse().map_err(|error| {
+        Error::new(
+          ErrorKind::InvalidData,
+          format!(r#"failed to parse time "{time}": {error}"#),
+        )
+      })?;
+    } else if let Some(tz) = line.strip_prefix("author-tz ") {
+      author_tz = parse_tz(tz).ok_or_else(|| {
+        Error::new(
+          ErrorKind::InvalidData,
+          format!(r#"failed to parse time zone "{tz}""#),
+        )
+      })?;
+    }
+  }
+  Ok(lines)
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+
+  /// Check that we can parse simple line porcelain output.
+  #[test]
+  fn parse_line_porcelain() {
+    let output = r#"1234567890123456789012345678901234567890 1 6 2
+author Alice
+author-mail <alice@example.com>
+author-time 1650000000
+author-tz +0000
+committer Alice
+committer-mail <alice@example.com>
+committer-time 1650000000
+committer-tz +0000
+summary Add main.c
+filename main.c
+	int main() {
+1234567890123456789012345678901234567890 2 7
+author Alice
+author-mail <alice@example.com>
+author-time 1650000000
+author-tz +0000
+committer Alice
+committer-mail <alice@example.com>
+committer-time 1650000000
+committer-tz +0000
+summary Add main.c
+filename main.c
+	  return 0;
+"#;
+
+    let lines = parse(output).unwrap();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0].commit, "1234567890123456789012345678901234567890");
+    assert_eq!(lines[0].author, "Alice");
+    assert_eq!(lines[0].author_time, 1650000000);
+    assert_eq!(lines[0].author_tz, 0);
+    assert_eq!(lines[0].line_number, 6);
+    assert_eq!(lines[0].content, "int main() {");
+    assert_eq!(lines[1].line_number, 7);
+    assert_eq!(lines[1].content, "  return 0;");
+  }
+
+  /// Check that we format author dates correctly.
+  #[test]
+  fn format_author_date() {
+    let mut line = PorcelainLine {
+      commit: String::new(),
+      author: String::new(),
+      author_time: 1650000000,
+      author_tz: 0,
+      line_number: 1,
+      content: String::new(),
+    };
+    assert_eq!(line.author_date(), "2022-04-15");
+
+    line.author_tz = parse_tz("-0600").unwrap();
+    assert_eq!(line.author_tz, -360);
+    assert_eq!(line.author_date(), "2022-04-14");
+
+    line.author_time = 0;
+    line.author_tz = parse_tz("+0130").unwrap();
+    assert_eq!(line.author_date(), "1970-01-01");
+
+    line.author_time = 951782400;
+    line.author_tz = 0;
+    assert_eq!(line.author_date(), "2000-02-29");
+  }
+
+  /// Make sure that we fail to parse malformed output.
+  #[test]
+  fn parse_malformed_porcelain() {
+    let output = "\tint main() {\n";
+    assert!(parse(output).is_err());
+  }
+}
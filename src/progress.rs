@@ -0,0 +1,63 @@
+// Copyright (C) 2026 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! A module for reporting the progress made in annotating diffs.
+
+use std::io::stderr;
+use std::io::Write as _;
+use std::sync::Mutex;
+
+use crate::Hunk;
+
+
+/// A reporter printing the progress made in annotating diffs as a
+/// single line to stderr, overwriting it in place.
+///
+/// The line is cleared once the object is dropped.
+pub(crate) struct Progress {
+  /// The total number of diffs to annotate.
+  total: usize,
+  /// The width of the line printed last.
+  width: Mutex<usize>,
+}
+
+impl Progress {
+  /// Create a new `Progress` object for annotating `total` diffs.
+  pub(crate) fn new(total: usize) -> Self {
+    Self {
+      total,
+      width: Mutex::new(0),
+    }
+  }
+
+  /// Report that `count` out of all diffs are being or have been
+  /// annotated, with `hunk` being the latest one.
+  ///
+  /// Progress is reported on a best-effort basis, so errors are
+  /// ignored.
+  pub(crate) fn report(&self, count: usize, hunk: &Hunk) {
+    let Hunk { src, dst } = hunk;
+    let file = if dst.file.as_str() != "/dev/null" {
+      &dst.file
+    } else {
+      &src.file
+    };
+    let line = format!("[{count}/{}] {file}", self.total);
+
+    let mut width = self.width.lock().unwrap_or_else(|err| err.into_inner());
+    // Pad the line to cover up any remnants of a longer previous one.
+    let mut stderr = stderr().lock();
+    let _result = write!(stderr, "\r{line:<width$}", width = *width);
+    let _result = stderr.flush();
+    *width = line.chars().count();
+  }
+}
+
+impl Drop for Progress {
+  fn drop(&mut self) {
+    let width = *self.width.get_mut().unwrap_or_else(|err| err.into_inner());
+    if width > 0 {
+      let _result = write!(stderr(), "\r{:width$}\r", "");
+    }
+  }
+}
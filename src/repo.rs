@@ -0,0 +1,109 @@
+// Copyright (C) 2026 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! A module providing meta data about a git repository.
+
+use std::io::Error;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::Stdio;
+
+use crate::capture;
+use crate::Result;
+
+
+/// Parse the output of `git --version` into a (major, minor, patch)
+/// triple.
+fn parse_version(output: &str) -> Option<(u32, u32, u32)> {
+  // The output has the form "git version 2.43.0", potentially followed
+  // by vendor specific suffixes, e.g., "2.39.3 (Apple Git-145)" or
+  // "2.45.1.windows.1".
+  let version = output.trim().strip_prefix("git version ")?;
+  let version = version.split(' ').next()?;
+  let mut parts = version.split('.').map(|part| part.parse::<u32>().ok());
+  let major = parts.next()??;
+  let minor = parts.next().flatten().unwrap_or(0);
+  let patch = parts.next().flatten().unwrap_or(0);
+  Some((major, minor, patch))
+}
+
+
+/// Meta data about a git repository, gathered once and cached.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RepoContext {
+  /// The version of `git`, as (major, minor, patch) triple.
+  pub git_version: (u32, u32, u32),
+  /// The root directory of the repository's working tree.
+  pub repo_root: PathBuf,
+  /// Whether the directory the context was detected for is inside the
+  /// repository's working tree.
+  pub is_inside_work_tree: bool,
+}
+
+impl RepoContext {
+  /// Detect the context of the repository containing `cwd`, using the
+  /// `git` binary at `git`.
+  pub fn detect(git: &Path, cwd: &Path) -> Result<Self> {
+    let run = |args: &[&str]| -> Result<String> {
+      let mut command = Command::new(git);
+      command
+        .current_dir(cwd)
+        .args(args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::piped());
+      let output = capture(command)?;
+      String::from_utf8(output).map_err(|error| Error::new(ErrorKind::InvalidData, error).into())
+    };
+
+    let output = run(&["--version"])?;
+    let git_version = parse_version(&output).ok_or_else(|| {
+      Error::new(
+        ErrorKind::InvalidData,
+        format!("failed to parse git version: {}", output.trim()),
+      )
+    })?;
+
+    let output = run(&["rev-parse", "--is-inside-work-tree", "--show-toplevel"])?;
+    let mut lines = output.lines();
+    let is_inside_work_tree = lines.next() == Some("true");
+    let repo_root = lines.next().map(PathBuf::from).ok_or_else(|| {
+      Error::new(
+        ErrorKind::InvalidData,
+        "failed to determine repository root",
+      )
+    })?;
+
+    let slf = Self {
+      git_version,
+      repo_root,
+      is_inside_work_tree,
+    };
+    Ok(slf)
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+
+  /// Check that we can parse the various forms of `git --version`
+  /// output.
+  #[test]
+  fn git_version_parsing() {
+    assert_eq!(parse_version("git version 2.43.0\n"), Some((2, 43, 0)));
+    assert_eq!(
+      parse_version("git version 2.39.3 (Apple Git-145)"),
+      Some((2, 39, 3))
+    );
+    assert_eq!(
+      parse_version("git version 2.45.1.windows.1"),
+      Some((2, 45, 1))
+    );
+    assert_eq!(parse_version("git version 3"), Some((3, 0, 0)));
+    assert_eq!(parse_version("hg version 6.1"), None);
+  }
+}
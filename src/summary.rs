@@ -0,0 +1,113 @@
+// Copyright (C) 2026 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! A module for summarizing blame information.
+
+use std::collections::BTreeSet;
+use std::ffi::OsStr;
+use std::ffi::OsString;
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::fmt::Result as FmtResult;
+use std::iter::once;
+
+use crate::blame_capture;
+use crate::porcelain;
+use crate::BlameConfig;
+use crate::Hunk;
+use crate::Result;
+
+
+/// A summary of the commits and authors that last touched the lines
+/// covered by a set of diffs.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BlameSummary {
+  /// The SHA-1s of all commits that last touched any of the lines.
+  pub commits: BTreeSet<String>,
+  /// The authors of said commits.
+  pub authors: BTreeSet<String>,
+  /// The total number of lines.
+  pub total_lines: usize,
+}
+
+impl Display for BlameSummary {
+  /// Format the summary, e.g., as `12 commits, 4 authors touched 87
+  /// lines`.
+  fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+    let plural = |count: usize| if count == 1 { "" } else { "s" };
+    let commits = self.commits.len();
+    let authors = self.authors.len();
+    let lines = self.total_lines;
+
+    write!(
+      f,
+      "{commits} commit{}, {authors} author{} touched {lines} line{}",
+      plural(commits),
+      plural(authors),
+      plural(lines),
+    )
+  }
+}
+
+
+/// Summarize the commits and authors that last touched the lines
+/// covered by the provided diffs.
+///
+/// `args` are passed to `git blame` as-is.
+pub fn blame_summary(diffs: &[Hunk], args: &[OsString]) -> Result<BlameSummary> {
+  blame_summary_with_config(diffs, args, &BlameConfig::default())
+}
+
+
+/// Summarize the commits and authors that last touched the lines
+/// covered by the provided diffs, as dictated by the provided
+/// configuration.
+pub fn blame_summary_with_config(
+  diffs: &[Hunk],
+  args: &[OsString],
+  config: &BlameConfig,
+) -> Result<BlameSummary> {
+  let mut summary = BlameSummary::default();
+
+  for Hunk { src, .. } in diffs {
+    // Newly added files have no lines to attribute.
+    if src.count == 0 {
+      continue
+    }
+
+    let args = args
+      .iter()
+      .map(OsString::as_os_str)
+      .chain(once(OsStr::new("--line-porcelain")));
+    let output = blame_capture(config, src, args)?;
+    let output = String::from_utf8_lossy(&output);
+
+    for line in porcelain::parse(&output)? {
+      summary.total_lines += 1;
+      let _new = summary.authors.insert(line.author);
+      let _new = summary.commits.insert(line.commit);
+    }
+  }
+  Ok(summary)
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+
+  /// Check that summaries are formatted as expected.
+  #[test]
+  fn summary_display() {
+    let summary = BlameSummary {
+      commits: BTreeSet::from(["a".to_string(), "b".to_string()]),
+      authors: BTreeSet::from(["alice".to_string()]),
+      total_lines: 3,
+    };
+    assert_eq!(summary.to_string(), "2 commits, 1 author touched 3 lines");
+
+    let summary = BlameSummary::default();
+    assert_eq!(summary.to_string(), "0 commits, 0 authors touched 0 lines");
+  }
+}
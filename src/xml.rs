@@ -0,0 +1,104 @@
+// Copyright (C) 2026 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! A module for emitting blame information in XML form.
+
+use std::io::Write;
+use std::ops::Deref as _;
+
+use crate::blame_capture;
+use crate::porcelain;
+use crate::BlameConfig;
+use crate::Hunk;
+use crate::Result;
+
+
+/// Escape a string for inclusion in XML character data or attribute
+/// values.
+///
+/// Characters not allowed in XML 1.0 documents are replaced with the
+/// Unicode replacement character.
+fn escape(s: &str) -> String {
+  let mut escaped = String::with_capacity(s.len());
+  for c in s.chars() {
+    match c {
+      '<' => escaped.push_str("&lt;"),
+      '>' => escaped.push_str("&gt;"),
+      '&' => escaped.push_str("&amp;"),
+      '"' => escaped.push_str("&quot;"),
+      '\'' => escaped.push_str("&apos;"),
+      '\t' | '\n' | '\r' => escaped.push(c),
+      c if c < ' ' || c == '\u{fffe}' || c == '\u{ffff}' => escaped.push('\u{fffd}'),
+      c => escaped.push(c),
+    }
+  }
+  escaped
+}
+
+
+/// Annotate all the diff hunks and write the result to `writer` as an
+/// XML document.
+///
+/// The document has the form:
+/// ```xml
+/// <blame><hunk src="file.c" start="6" count="6"><line commit="deadbeef" author="Alice" number="6">content</line></hunk></blame>
+/// ```
+pub fn blame_to_xml<W>(diffs: &[Hunk], config: &BlameConfig, writer: &mut W) -> Result<()>
+where
+  W: Write,
+{
+  write!(writer, "<blame>")?;
+
+  // `git blame` refuses to annotate an empty range of lines, as is the
+  // case for newly added files, so such diffs are skipped.
+  let diffs = diffs.iter().filter(|Hunk { src, .. }| src.count > 0);
+  for Hunk { src, .. } in diffs {
+    let output = blame_capture(config, src, ["--line-porcelain"])?;
+    let output = String::from_utf8_lossy(&output);
+    let lines = porcelain::parse(&output)?;
+
+    write!(
+      writer,
+      r#"<hunk src="{}" start="{}" count="{}">"#,
+      escape(src.file.deref()),
+      src.line,
+      src.count
+    )?;
+    for line in lines {
+      write!(
+        writer,
+        r#"<line commit="{}" author="{}" number="{}">{}</line>"#,
+        escape(&line.commit),
+        escape(&line.author),
+        line.line_number,
+        escape(&line.content),
+      )?;
+    }
+    write!(writer, "</hunk>")?;
+  }
+
+  writeln!(writer, "</blame>")?;
+  Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+
+  /// Check that special characters are escaped properly.
+  #[test]
+  fn escape_special_characters() {
+    assert_eq!(
+      escape("if (a < b && c > d)"),
+      "if (a &lt; b &amp;&amp; c &gt; d)"
+    );
+    assert_eq!(
+      escape(r#""quoted" 'text'"#),
+      "&quot;quoted&quot; &apos;text&apos;"
+    );
+    assert_eq!(escape("bell\u{7}"), "bell\u{fffd}");
+    assert_eq!(escape("\tindented"), "\tindented");
+  }
+}
@@ -1,15 +1,19 @@
 // Copyright (C) 2022 Daniel Mueller <deso@posteo.net>
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+use std::collections::BTreeSet;
+use std::env::current_exe;
+use std::env::var_os;
 use std::ffi::OsStr;
+use std::fs::create_dir;
 use std::fs::File;
-use std::io::Error;
+use std::io::stdin;
+use std::io::stdout;
+use std::io::Error as IoError;
 use std::io::ErrorKind;
-use std::io::Read as _;
 use std::io::Result;
 use std::io::Write as _;
 use std::path::Path;
-use std::process::ChildStdout;
 use std::process::Command;
 use std::process::Stdio;
 
@@ -17,18 +21,47 @@ use tempfile::tempdir;
 use tempfile::TempDir;
 
 use git_blamediff::await_child;
-use git_blamediff::GIT;
+use git_blamediff::blame;
+use git_blamediff::blame_batched_with_config;
+#[cfg(unix)]
+use git_blamediff::blame_cached_with_config;
+use git_blamediff::blame_collect_with_config;
+use git_blamediff::blame_incremental;
+#[cfg(feature = "interactive")]
+use git_blamediff::blame_interactive;
+use git_blamediff::blame_summary_with_config;
+use git_blamediff::blame_to_writer;
+#[cfg(feature = "xml")]
+use git_blamediff::blame_to_xml;
+use git_blamediff::blame_with_config;
+use git_blamediff::BlameConfig;
+use git_blamediff::Error;
+use git_blamediff::Hunk;
+use git_blamediff::OutputFormat;
+use git_blamediff::Parser;
+use git_blamediff::RepoContext;
+use git_blamediff::GIT_ENV;
 
 
 /// The number of digits to use for representing SHA-1 check sums.
 const GIT_SHA1_DIGITS: usize = 8;
 /// An empty array of arguments.
 const NO_ARGS: [String; 0] = [];
+/// The environment variable instructing `blame_to_stdout` to actually
+/// annotate a diff.
+const BLAME_TO_STDOUT_VAR: &str = "GIT_BLAMEDIFF_TEST_BLAME_TO_STDOUT";
+/// The marker separating the outputs emitted by `blame_to_stdout`.
+const BLAME_TO_STDOUT_MARKER: &str = "@@@ git-blamediff @@@\n";
 
 
 /// Create a `git` [`Command`].
 fn git_command(directory: &Path) -> Command {
-  let mut command = Command::new(GIT);
+  let config = BlameConfig {
+    directory: Some(directory.to_path_buf()),
+    clear_env: true,
+    ..Default::default()
+  };
+  let mut command = config.git_command();
   // Because we clear the entire environment Git does not have any
   // identity and will bail out. Provide some dummy values for testing
   // purposes.
@@ -39,18 +72,13 @@ fn git_command(directory: &Path) -> Command {
     "user.email=nobody@example.com",
   ];
 
-  command
-    .env_clear()
-    .stderr(Stdio::piped())
-    .arg("-C")
-    .arg(directory)
-    .args(args);
+  command.stderr(Stdio::piped()).args(args);
 
   command
 }
 
 /// Execute a `git` command and wait for it to finish.
-fn git<A, S>(stdout: Stdio, directory: &Path, args: A) -> Result<Option<ChildStdout>>
+fn git<A, S>(stdout: Stdio, directory: &Path, args: A) -> Result<Vec<u8>>
 where
   A: IntoIterator<Item = S>,
   S: AsRef<OsStr>,
@@ -62,7 +90,8 @@ where
     .stdout(stdout)
     .spawn()?;
 
-  await_child(command.get_program(), child)
+  let stdout = await_child(command.get_program(), child)?;
+  Ok(stdout)
 }
 
 
@@ -99,7 +128,7 @@ impl GitRepo {
     A: IntoIterator<Item = S>,
     S: AsRef<OsStr>,
   {
-    git(Stdio::null(), self.directory.path(), args).map(|_| ())
+    git(Stdio::null(), self.directory.path(), args).map(|_output| ())
   }
 
   /// Invoke a `git` command and capture and return its output.
@@ -108,12 +137,7 @@ impl GitRepo {
     A: IntoIterator<Item = S>,
     S: AsRef<OsStr>,
   {
-    let mut output = Vec::new();
-    // It is fine to unwrap here because we know that we captured stdout
-    // and so it will always be available.
-    let mut handle = git(Stdio::piped(), self.directory.path(), args).map(Option::unwrap)?;
-    let _ = handle.read_to_end(&mut output)?;
-    Ok(output)
+    git(Stdio::piped(), self.directory.path(), args)
   }
 
   /// Run `git init`.
@@ -177,7 +201,7 @@ impl GitRepo {
         .chain(args.into_iter().map(|s| s.to_string())),
     )?;
 
-    let sha1 = String::from_utf8(output).map_err(|error| Error::new(ErrorKind::Other, error))?;
+    let sha1 = String::from_utf8(output).map_err(IoError::other)?;
     let sha1 = sha1.trim();
     Ok(sha1.to_string())
   }
@@ -189,10 +213,10 @@ impl GitRepo {
   {
     let path = path.as_ref();
     if !path.is_relative() {
-      return Err(Error::new(
-        ErrorKind::Other,
-        format!("provided path {} is not relative", path.display()),
-      ))
+      return Err(IoError::other(format!(
+        "provided path {} is not relative",
+        path.display()
+      )));
     }
 
     let mut options = File::options();
@@ -209,6 +233,48 @@ impl GitRepo {
     Ok(())
   }
 
+  /// Run `git diff` in the form expected by `git-blamediff` and parse
+  /// the result.
+  fn diff<A, S>(&self, args: A) -> Result<Parser>
+  where
+    A: IntoIterator<Item = S>,
+    S: ToString,
+  {
+    let output = self.git_out(
+      ["diff", "--relative", "--no-prefix"]
+        .into_iter()
+        .map(ToString::to_string)
+        .chain(args.into_iter().map(|s| s.to_string())),
+    )?;
+
+    let mut parser = Parser::new();
+    let () = parser.parse(output.as_slice())?;
+    Ok(parser)
+  }
+
+  /// Annotate the output of `git diff` by means of the library.
+  fn blame<DA, DS, BA, BS>(&self, diff_args: DA, blame_args: BA) -> Result<Vec<u8>>
+  where
+    DA: IntoIterator<Item = DS>,
+    DS: ToString,
+    BA: IntoIterator<Item = BS>,
+    BS: AsRef<OsStr>,
+  {
+    let parser = self.diff(diff_args)?;
+    let config = BlameConfig {
+      directory: Some(self.directory.path().to_path_buf()),
+      ..Default::default()
+    };
+    let args = blame_args
+      .into_iter()
+      .map(|arg| arg.as_ref().to_os_string())
+      .collect::<Vec<_>>();
+
+    let mut output = Vec::new();
+    let () = blame_with_config(parser.diffs(), &args, &config, &mut output)?;
+    Ok(output)
+  }
+
   /// Invoke `git-blamediff`.
   fn blamediff<DA, DS, BA, BS>(&self, diff_args: DA, blame_args: BA) -> Result<Vec<u8>>
   where
@@ -235,13 +301,8 @@ impl GitRepo {
       .args(blame_args)
       .spawn()?;
 
-    let _ = await_child(diff_cmd.get_program(), diff_child)?;
-    // It is fine to unwrap here because we know that we captured
-    // stdout and so it will always be available.
-    let mut stdout = await_child(blamediff_cmd.get_program(), blamediff_child)?.unwrap();
-    let mut output = Vec::new();
-    let _ = stdout.read_to_end(&mut output)?;
-
+    let _output = await_child(diff_cmd.get_program(), diff_child)?;
+    let output = await_child(blamediff_cmd.get_program(), blamediff_child)?;
     Ok(output)
   }
 }
@@ -269,7 +330,7 @@ fn blame_single_file_single_line() {
   // Contrary to `git-rev-parse`, `git-blame` adds one to the provided
   // length of the SHA-1.
   let abbrev = format!("--abbrev={}", GIT_SHA1_DIGITS - 1);
-  let out = repo.blamediff(NO_ARGS, [abbrev]).unwrap();
+  let out = repo.blame(NO_ARGS, [abbrev]).unwrap();
   let expected = format!(
     r#"--- main.py
 +++ main.py
@@ -281,6 +342,172 @@ fn blame_single_file_single_line() {
 }
 
 
+/// Check that we can retrieve blame information in structured form.
+#[test]
+fn blame_collect() {
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+
+  repo
+    .write("main.py", "# main.py\n# foo\n", WriteMode::Overwrite)
+    .unwrap();
+  repo.add(["main.py"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+
+  repo
+    .write("main.py", "# main.py\n# bar\n", WriteMode::Overwrite)
+    .unwrap();
+  let short = format!("--short={GIT_SHA1_DIGITS}");
+  let sha1 = repo.rev_parse([&short, "HEAD"]).unwrap();
+  let abbrev = format!("--abbrev={}", GIT_SHA1_DIGITS - 1);
+
+  let parser = repo.diff(["-U1"]).unwrap();
+  let config = BlameConfig {
+    directory: Some(repo.directory.path().to_path_buf()),
+    ..Default::default()
+  };
+  let hunks = blame_collect_with_config(parser.diffs(), &[abbrev.into()], &config).unwrap();
+  assert_eq!(hunks.len(), 1);
+
+  let hunk = &hunks[0];
+  assert_eq!(hunk.src.file.as_str(), "main.py");
+  assert_eq!(hunk.dst.file.as_str(), "main.py");
+  let lines = hunk
+    .lines
+    .iter()
+    .map(|line| {
+      (
+        line.commit.as_str(),
+        line.line_number,
+        line.content.as_str(),
+      )
+    })
+    .collect::<Vec<_>>();
+  assert_eq!(
+    lines,
+    [(sha1.as_str(), 1, "# main.py"), (sha1.as_str(), 2, "# foo")]
+  );
+}
+
+
+/// Check that `blame_incremental` reports hunks one by one and stops
+/// once the callback fails.
+#[test]
+fn blame_incremental_callback() {
+  // Pure additions do not require invoking `git`.
+  let diff = concat!(
+    "--- /dev/null\n",
+    "+++ a.c\n",
+    "@@ -0,0 +1 @@\n",
+    "+int a;\n",
+    "--- /dev/null\n",
+    "+++ b.c\n",
+    "@@ -0,0 +1 @@\n",
+    "+int b;\n",
+  );
+  let mut parser = Parser::new();
+  let () = parser.parse(diff.as_bytes()).unwrap();
+
+  let mut files = Vec::new();
+  let () = blame_incremental(parser.diffs().iter().map(Ok), &[], |src, dst, blame| {
+    assert_eq!(src.file.as_str(), "/dev/null");
+    assert!(blame.is_empty());
+    let () = files.push(dst.file.to_string());
+    Ok(())
+  })
+  .unwrap();
+  assert_eq!(files, ["a.c", "b.c"]);
+
+  let mut files = Vec::new();
+  let error = blame_incremental(parser.diffs().iter().map(Ok), &[], |_src, dst, _blame| {
+    let () = files.push(dst.file.to_string());
+    Err(Error::Io(IoError::other("stop")))
+  })
+  .unwrap_err();
+  assert_eq!(error.to_string(), "stop");
+  assert_eq!(files, ["a.c"]);
+}
+
+
+/// Annotate the diff provided on stdin using [`blame`] as well as
+/// [`blame_to_writer`], printing both outputs separated by markers.
+///
+/// `blame` prints to stdout and runs `git` in the current working
+/// directory, so this test only does anything when run in a child
+/// process, as done by `blame_matches_blame_to_writer`.
+#[test]
+fn blame_to_stdout() {
+  if var_os(BLAME_TO_STDOUT_VAR).is_none() {
+    return
+  }
+
+  let mut parser = Parser::new();
+  let () = parser.parse(stdin().lock()).unwrap();
+  let diffs = parser.diffs();
+
+  let mut output = Vec::new();
+  let () = blame_to_writer(diffs.iter().map(Ok), &[], OutputFormat::Plain, &mut output).unwrap();
+
+  let () = stdout()
+    .write_all(BLAME_TO_STDOUT_MARKER.as_bytes())
+    .unwrap();
+  let () = blame(diffs.iter().map(Ok), &[], OutputFormat::Plain).unwrap();
+  let mut stdout = stdout().lock();
+  let () = stdout.write_all(BLAME_TO_STDOUT_MARKER.as_bytes()).unwrap();
+  let () = stdout.write_all(&output).unwrap();
+  let () = stdout.write_all(BLAME_TO_STDOUT_MARKER.as_bytes()).unwrap();
+  let () = stdout.flush().unwrap();
+}
+
+
+/// Check that `blame` produces the same output as `blame_to_writer`
+/// for plain output.
+#[test]
+fn blame_matches_blame_to_writer() {
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+
+  repo
+    .write("main.c", "int x;\nint y;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo.add(["main.c"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+  let short = format!("--short={GIT_SHA1_DIGITS}");
+  let sha1 = repo.rev_parse([short.as_str(), "HEAD"]).unwrap();
+
+  repo
+    .write("main.c", "int x;\nint z;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo
+    .write("new.c", "int a;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo.git(["add", "--intent-to-add", "new.c"]).unwrap();
+  let diff = repo.git_out(["diff", "--relative", "--no-prefix"]).unwrap();
+
+  let mut child = Command::new(current_exe().unwrap())
+    .args(["--exact", "blame_to_stdout", "--test-threads=1"])
+    .env(BLAME_TO_STDOUT_VAR, "1")
+    .current_dir(repo.directory.path())
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .spawn()
+    .unwrap();
+  let () = child.stdin.take().unwrap().write_all(&diff).unwrap();
+  let output = child.wait_with_output().unwrap();
+  assert!(output.status.success());
+
+  let out = String::from_utf8(output.stdout).unwrap();
+  let parts = out.split(BLAME_TO_STDOUT_MARKER).collect::<Vec<_>>();
+  let [_, blame, writer, _] = parts.as_slice() else {
+    panic!("{out}")
+  };
+  assert_eq!(blame, writer);
+  assert!(blame.starts_with("--- main.c\n+++ main.c\n"), "{blame}");
+  assert!(blame.contains(&format!("{sha1} 2) int y;")), "{blame}");
+  assert!(blame.ends_with("--- /dev/null\n+++ new.c\n"), "{blame}");
+}
+
+
 /// Check that `git-blamediff` works properly on a removed file.
 #[test]
 fn blame_removed_file() {
@@ -298,7 +525,7 @@ fn blame_removed_file() {
   let sha1 = repo.rev_parse([&short, "HEAD"]).unwrap();
 
   let abbrev = format!("--abbrev={}", GIT_SHA1_DIGITS - 1);
-  let out = repo.blamediff(["--staged"], [abbrev]).unwrap();
+  let out = repo.blame(["--staged"], [abbrev]).unwrap();
   let expected = format!(
     r#"--- main.py
 +++ /dev/null
@@ -328,7 +555,7 @@ fn blame_with_additional_arguments() {
   let sha1 = repo.rev_parse(["HEAD"]).unwrap();
 
   // Tell git-blame to use the long format for SHA-1 checksums.
-  let out = repo.blamediff(NO_ARGS, ["-l"]).unwrap();
+  let out = repo.blame(NO_ARGS, ["-l"]).unwrap();
   let expected = format!(
     r#"--- main.py
 +++ main.py
@@ -397,7 +624,7 @@ int main(int argc, char const* argv[])
     .write("foobar.c", foobar, WriteMode::Overwrite)
     .unwrap();
 
-  let out = repo.blamediff(NO_ARGS, ["-l"]).unwrap();
+  let out = repo.blame(NO_ARGS, ["-l"]).unwrap();
   let expected = format!(
     r#"--- foobar.c
 +++ foobar.c
@@ -422,3 +649,2091 @@ int main(int argc, char const* argv[])
 
   assert_eq!(String::from_utf8(out).unwrap(), expected)
 }
+
+
+/// Parse the provided string as an XML document, returning the names
+/// of all elements encountered, in order.
+#[cfg(feature = "xml")]
+fn parse_xml(xml: &str) -> Vec<String> {
+  let document = roxmltree::Document::parse(xml).unwrap();
+  document
+    .descendants()
+    .filter(|node| node.is_element())
+    .map(|node| node.tag_name().name().to_string())
+    .collect()
+}
+
+
+/// Check that we can emit blame information as well-formed XML.
+#[cfg(feature = "xml")]
+#[test]
+fn blame_to_xml_document() {
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+
+  repo
+    .write("main.c", "if (a < b && c > d) {\n", WriteMode::Overwrite)
+    .unwrap();
+  repo.add(["main.c"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+  let sha1 = repo.rev_parse(["HEAD"]).unwrap();
+
+  repo
+    .write("main.c", "if (a < b) {\n", WriteMode::Overwrite)
+    .unwrap();
+
+  let parser = repo.diff(NO_ARGS).unwrap();
+  let config = BlameConfig {
+    directory: Some(repo.directory.path().to_path_buf()),
+    ..Default::default()
+  };
+  let mut out = Vec::new();
+  let () = blame_to_xml(parser.diffs(), &config, &mut out).unwrap();
+  let out = String::from_utf8(out).unwrap();
+
+  let expected = format!(
+    r#"<blame><hunk src="main.c" start="1" count="1"><line commit="{sha1}" author="nobody" number="1">if (a &lt; b &amp;&amp; c &gt; d) {{</line></hunk></blame>
+"#
+  );
+  assert_eq!(out, expected);
+  assert_eq!(parse_xml(&out), ["blame", "hunk", "line"]);
+
+  let document = roxmltree::Document::parse(&out).unwrap();
+  let line = document
+    .descendants()
+    .find(|node| node.has_tag_name("line"))
+    .unwrap();
+  assert_eq!(line.text(), Some("if (a < b && c > d) {"));
+}
+
+
+/// Check that newly added files, which have no lines to annotate, are
+/// skipped when emitting XML.
+#[cfg(feature = "xml")]
+#[test]
+fn blame_to_xml_with_new_file() {
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+
+  repo
+    .write("main.c", "int x;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo.add(["main.c"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+
+  repo
+    .write("main.c", "int y;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo
+    .write("new.c", "int z;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo.git(["add", "--intent-to-add", "new.c"]).unwrap();
+
+  let parser = repo.diff(NO_ARGS).unwrap();
+  let config = BlameConfig {
+    directory: Some(repo.directory.path().to_path_buf()),
+    ..Default::default()
+  };
+  let mut out = Vec::new();
+  let () = blame_to_xml(parser.diffs(), &config, &mut out).unwrap();
+  let out = String::from_utf8(out).unwrap();
+  assert_eq!(parse_xml(&out), ["blame", "hunk", "line"]);
+}
+
+
+/// Check that only the hunks the user agreed to are annotated in
+/// interactive mode.
+#[cfg(feature = "interactive")]
+#[test]
+fn blame_interactively() {
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+
+  repo
+    .write("first.c", "int first;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo
+    .write("second.c", "int second;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo.add(["first.c", "second.c"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+
+  repo
+    .write("first.c", "int first_;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo
+    .write("second.c", "int second_;\n", WriteMode::Overwrite)
+    .unwrap();
+
+  let parser = repo.diff(NO_ARGS).unwrap();
+  assert_eq!(parser.diffs().len(), 2);
+
+  let config = BlameConfig {
+    directory: Some(repo.directory.path().to_path_buf()),
+    ..Default::default()
+  };
+  let input = b"y\nq\n";
+  let mut out = Vec::new();
+  let () = blame_interactive(parser.diffs(), &[], &config, &input[..], &mut out).unwrap();
+  let out = String::from_utf8(out).unwrap();
+
+  assert!(out.contains("1) int first;"), "{out}");
+  assert!(out.contains("+++ second.c"), "{out}");
+  assert!(!out.contains("1) int second;"), "{out}");
+}
+
+
+/// Check that newly added files are not offered for annotation in
+/// interactive mode.
+#[cfg(feature = "interactive")]
+#[test]
+fn blame_interactively_with_new_file() {
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+
+  repo
+    .write("main.c", "int x;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo.add(["main.c"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+
+  repo
+    .write("main.c", "int y;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo
+    .write("new.c", "int z;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo.git(["add", "--intent-to-add", "new.c"]).unwrap();
+
+  let parser = repo.diff(NO_ARGS).unwrap();
+  assert_eq!(parser.diffs().len(), 2);
+
+  let config = BlameConfig {
+    directory: Some(repo.directory.path().to_path_buf()),
+    ..Default::default()
+  };
+  let input = b"a\n";
+  let mut out = Vec::new();
+  let () = blame_interactive(parser.diffs(), &[], &config, &input[..], &mut out).unwrap();
+  let out = String::from_utf8(out).unwrap();
+
+  assert!(out.contains("1) int x;"), "{out}");
+  assert!(!out.contains("new.c"), "{out}");
+}
+
+
+/// Check that we can emit blame information as Markdown.
+#[test]
+fn blame_as_markdown() {
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+
+  repo
+    .write("main.py", "# main.py", WriteMode::Overwrite)
+    .unwrap();
+  repo.add(["main.py"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+
+  repo
+    .write("main.py", "# Hello, World!", WriteMode::Append)
+    .unwrap();
+  let sha1 = repo.rev_parse(["HEAD"]).unwrap();
+
+  let out = repo.blamediff(NO_ARGS, ["--format=markdown"]).unwrap();
+  let out = String::from_utf8(out).unwrap();
+  let mut lines = out.lines();
+
+  assert_eq!(lines.next(), Some("```blame"));
+  assert_eq!(lines.next(), Some("# src: main.py L1"));
+  assert_eq!(lines.next(), Some("# dst: main.py L1"));
+  let line = lines.next().unwrap();
+  assert!(line.starts_with(&format!("{sha1} nobody ")), "{line}");
+  assert!(line.ends_with(" 1) # main.py"), "{line}");
+  assert_eq!(lines.next(), Some("```"));
+  assert_eq!(lines.next(), None);
+}
+
+
+/// Check that newly added files, which have no lines to annotate, are
+/// skipped when emitting Markdown.
+#[test]
+fn blame_as_markdown_with_new_file() {
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+
+  repo
+    .write("main.py", "# main.py\n", WriteMode::Overwrite)
+    .unwrap();
+  repo.add(["main.py"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+  let sha1 = repo.rev_parse(["HEAD"]).unwrap();
+
+  repo
+    .write("main.py", "# Hello, World!\n", WriteMode::Overwrite)
+    .unwrap();
+  repo
+    .write("new.py", "# new.py\n", WriteMode::Overwrite)
+    .unwrap();
+  repo.git(["add", "--intent-to-add", "new.py"]).unwrap();
+
+  let out = repo.blamediff(NO_ARGS, ["--format=markdown"]).unwrap();
+  let out = String::from_utf8(out).unwrap();
+  let mut lines = out.lines();
+
+  assert_eq!(lines.next(), Some("```blame"));
+  assert_eq!(lines.next(), Some("# src: main.py L1"));
+  assert_eq!(lines.next(), Some("# dst: main.py L1"));
+  let line = lines.next().unwrap();
+  assert!(line.starts_with(&format!("{sha1} nobody ")), "{line}");
+  assert_eq!(lines.next(), Some("```"));
+  assert_eq!(lines.next(), None);
+}
+
+
+/// Verify that diffs with varying amounts of context lines are
+/// annotated correctly.
+#[test]
+fn blame_with_varying_context() {
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+
+  let content = (1..=30).map(|i| format!("line {i}\n")).collect::<String>();
+  repo
+    .write("lines.txt", &content, WriteMode::Overwrite)
+    .unwrap();
+  repo.add(["lines.txt"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+
+  let content = content.replace("line 15\n", "line fifteen\n");
+  repo
+    .write("lines.txt", &content, WriteMode::Overwrite)
+    .unwrap();
+
+  for context in [0, 1, 3, 10] {
+    let unified = format!("-U{context}");
+    let parser = repo.diff([&unified]).unwrap();
+    let diffs = parser.diffs();
+    assert_eq!(diffs.len(), 1, "{unified}");
+
+    let Hunk { src, dst } = &diffs[0];
+    assert_eq!(src.line, 15 - context, "{unified}");
+    assert_eq!(src.count, 2 * context + 1, "{unified}");
+    assert_eq!(dst.line, 15 - context, "{unified}");
+    assert_eq!(dst.count, 2 * context + 1, "{unified}");
+
+    let range = format!("-L{},+{}", src.line, src.count);
+    let blame = repo
+      .git_out(["blame", "-s", &range, "HEAD", "--", "lines.txt"])
+      .unwrap();
+    let blame = String::from_utf8(blame).unwrap();
+    let expected = format!("--- lines.txt\n+++ lines.txt\n{blame}");
+
+    let out = repo.blame([&unified], NO_ARGS).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), expected, "{unified}");
+  }
+}
+
+
+/// Check that context lines are annotated separately when requested.
+#[test]
+fn blame_with_annotated_context() {
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+
+  repo
+    .write(
+      "lines.txt",
+      "line 1\nline 2\nline 3\n",
+      WriteMode::Overwrite,
+    )
+    .unwrap();
+  repo.add(["lines.txt"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+  let sha1_first = repo.rev_parse(["HEAD"]).unwrap();
+
+  repo
+    .write(
+      "lines.txt",
+      "line 1\nline two\nline 3\n",
+      WriteMode::Overwrite,
+    )
+    .unwrap();
+  repo.add(["lines.txt"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+  let sha1_second = repo.rev_parse(["HEAD"]).unwrap();
+
+  repo
+    .write(
+      "lines.txt",
+      "line 1\nline 2\nline 3\n",
+      WriteMode::Overwrite,
+    )
+    .unwrap();
+
+  let out = repo
+    .blamediff(NO_ARGS, ["-l", "--annotate-context"])
+    .unwrap();
+  let expected = format!(
+    r#"--- lines.txt
++++ lines.txt
+    {sha1_first} 1) line 1
+{sha1_second} 2) line two
+    {sha1_first} 3) line 3
+"#
+  );
+  assert_eq!(String::from_utf8(out).unwrap(), expected);
+}
+
+
+/// Check that additional context lines are annotated when requested.
+#[test]
+fn blame_with_additional_context() {
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+
+  let content = (1..=6).map(|i| format!("line {i}\n")).collect::<String>();
+  repo
+    .write("lines.txt", &content, WriteMode::Overwrite)
+    .unwrap();
+  repo.add(["lines.txt"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+  let sha1 = repo.rev_parse(["HEAD"]).unwrap();
+
+  let line = |i: usize| format!("{sha1} {i}) line {i}\n");
+  let context = |i: usize| format!("    {}", line(i));
+
+  // Change the last line, so that the expanded range extends past the
+  // end of the file.
+  let changed = content.replace("line 6\n", "line six\n");
+  repo
+    .write("lines.txt", &changed, WriteMode::Overwrite)
+    .unwrap();
+
+  let out = repo.blamediff(["-U0"], ["-l", "--context=2"]).unwrap();
+  let expected = format!(
+    "--- lines.txt\n+++ lines.txt\n{}{}{}",
+    line(4),
+    line(5),
+    line(6)
+  );
+  assert_eq!(String::from_utf8(out).unwrap(), expected);
+
+  let out = repo
+    .blamediff(["-U0"], ["-l", "--context=2", "--annotate-context"])
+    .unwrap();
+  let expected = format!(
+    "--- lines.txt\n+++ lines.txt\n{}{}{}",
+    context(4),
+    context(5),
+    line(6)
+  );
+  assert_eq!(String::from_utf8(out).unwrap(), expected);
+
+  // Now change a line in the middle of the file.
+  let changed = content.replace("line 3\n", "line three\n");
+  repo
+    .write("lines.txt", &changed, WriteMode::Overwrite)
+    .unwrap();
+
+  let out = repo
+    .blamediff(["-U0"], ["-l", "--context=1", "--annotate-context"])
+    .unwrap();
+  let expected = format!(
+    "--- lines.txt\n+++ lines.txt\n{}{}{}",
+    context(2),
+    line(3),
+    context(4)
+  );
+  assert_eq!(String::from_utf8(out).unwrap(), expected);
+
+  // Without additional context lines, only the changed one is
+  // annotated.
+  let out = repo.blamediff(["-U0"], ["-l", "--context=0"]).unwrap();
+  let expected = format!("--- lines.txt\n+++ lines.txt\n{}", line(3));
+  assert_eq!(String::from_utf8(out).unwrap(), expected);
+}
+
+
+/// Check that lines from the root commit are attributed to it when
+/// `--blame-root` is provided.
+#[test]
+fn blame_root_commit() {
+  let repo = GitRepo::new().unwrap();
+  repo
+    .write("main.py", "# main.py", WriteMode::Overwrite)
+    .unwrap();
+  repo.add(["main.py"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+
+  repo
+    .write("main.py", "# Hello, World!", WriteMode::Append)
+    .unwrap();
+  let sha1 = repo.rev_parse(["HEAD"]).unwrap();
+
+  // By default, git-blame treats the root commit as a boundary and
+  // marks lines originating in it accordingly.
+  let out = repo.blamediff(NO_ARGS, ["-l"]).unwrap();
+  let out = String::from_utf8(out).unwrap();
+  assert!(out.contains(&format!("^{}", &sha1[..39])), "{out}");
+
+  let out = repo.blamediff(NO_ARGS, ["-l", "--blame-root"]).unwrap();
+  let expected = format!(
+    r#"--- main.py
++++ main.py
+{sha1} 1) # main.py
+"#
+  );
+  assert_eq!(String::from_utf8(out).unwrap(), expected);
+}
+
+
+/// Check that `--follow-history` traces the history of lines across
+/// file renames.
+#[test]
+fn blame_following_history() {
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+
+  repo
+    .write("old.txt", "line 1\nline 2\nline 3\n", WriteMode::Overwrite)
+    .unwrap();
+  repo.add(["old.txt"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+  let sha1_add = repo.rev_parse(["HEAD"]).unwrap();
+
+  repo.git(["mv", "old.txt", "new.txt"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+  let sha1_mv = repo.rev_parse(["--short", "HEAD"]).unwrap();
+
+  repo
+    .write("new.txt", "line 4\n", WriteMode::Append)
+    .unwrap();
+  repo.add(["new.txt"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+  let sha1_append = repo.rev_parse(["HEAD"]).unwrap();
+
+  repo
+    .write(
+      "new.txt",
+      "line 1\nline 2\nline three\nline four\n",
+      WriteMode::Overwrite,
+    )
+    .unwrap();
+
+  let out = repo.blamediff(NO_ARGS, ["--follow-history"]).unwrap();
+  let out = String::from_utf8(out).unwrap();
+  let mut lines = out.lines();
+
+  assert_eq!(lines.next(), Some("--- new.txt"));
+  assert_eq!(lines.next(), Some("+++ new.txt"));
+  assert_eq!(
+    lines.next().unwrap(),
+    format!("# renamed old.txt -> new.txt in {sha1_mv}")
+  );
+  for number in 1..=3 {
+    let line = lines.next().unwrap();
+    assert!(line.starts_with(&sha1_add[..7]), "{line}");
+    assert!(
+      line.contains(&format!(" old.txt {number}) line {number}")),
+      "{line}"
+    );
+  }
+  let line = lines.next().unwrap();
+  assert!(line.starts_with(&sha1_append[..7]), "{line}");
+  assert!(line.contains(" new.txt 4) line 4"), "{line}");
+  assert_eq!(lines.next(), None);
+
+  // The output for each diff is formatted as per the template.
+  let args = ["--follow-history", r"--template=== {src_file} ==\n{blame}"];
+  let out = repo.blamediff(NO_ARGS, args).unwrap();
+  let out = String::from_utf8(out).unwrap();
+  let mut lines = out.lines();
+  assert_eq!(lines.next(), Some("== new.txt =="));
+  assert_eq!(
+    lines.next().unwrap(),
+    format!("# renamed old.txt -> new.txt in {sha1_mv}")
+  );
+  assert_eq!(lines.count(), 4);
+}
+
+
+/// Check that we can detect the context of a repository.
+#[test]
+fn detect_repo_context() {
+  let repo = GitRepo::new().unwrap();
+  let root = repo.directory.path().canonicalize().unwrap();
+  let subdir = root.join("subdir");
+  let () = std::fs::create_dir(&subdir).unwrap();
+  let config = BlameConfig::default();
+
+  for cwd in [&root, &subdir] {
+    let context = RepoContext::detect(&config.git_path, cwd).unwrap();
+    assert_eq!(context.repo_root, root);
+    assert!(context.is_inside_work_tree);
+    assert!(
+      context.git_version >= (2, 0, 0),
+      "{:?}",
+      context.git_version
+    );
+  }
+
+  let dir = tempdir().unwrap();
+  let result = RepoContext::detect(&config.git_path, dir.path());
+  assert!(result.is_err());
+}
+
+
+/// Check that the path to the `git` binary to use can be configured.
+#[test]
+fn blame_with_configured_git_path() {
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+  repo
+    .write("main.c", "int main;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo.add(["main.c"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+  repo
+    .write("main.c", "int main();\n", WriteMode::Overwrite)
+    .unwrap();
+
+  let parser = repo.diff(NO_ARGS).unwrap();
+  let config = BlameConfig {
+    git_path: repo.directory.path().join("does-not-exist"),
+    directory: Some(repo.directory.path().to_path_buf()),
+    ..Default::default()
+  };
+  let result = blame_with_config(parser.diffs(), &[], &config, &mut Vec::new());
+  assert!(
+    matches!(&result, Err(Error::Io(error)) if error.kind() == ErrorKind::NotFound),
+    "{result:?}"
+  );
+
+  let mut diff = git_command(repo.directory.path());
+  let diff = diff
+    .args(["diff", "--relative", "--no-prefix"])
+    .stdin(Stdio::null())
+    .output()
+    .unwrap();
+
+  let mut blamediff = Command::new(env!("CARGO_BIN_EXE_git-blamediff"));
+  let mut child = blamediff
+    .current_dir(repo.directory.path())
+    .env(GIT_ENV, repo.directory.path().join("does-not-exist"))
+    .stdin(Stdio::piped())
+    .stdout(Stdio::null())
+    .stderr(Stdio::null())
+    .spawn()
+    .unwrap();
+  let () = child.stdin.take().unwrap().write_all(&diff.stdout).unwrap();
+  let status = child.wait().unwrap();
+  assert!(!status.success());
+}
+
+
+/// Check that the complete error output of a failed process is
+/// reported.
+#[test]
+fn report_process_error_output() {
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+
+  let mut command = git_command(repo.directory.path());
+  let child = command
+    .args(["log", "does-not-exist"])
+    .stdin(Stdio::null())
+    .stdout(Stdio::null())
+    .spawn()
+    .unwrap();
+  let result = await_child(command.get_program(), child);
+  let Err(Error::Process { stderr, .. }) = result else {
+    panic!("unexpected result: {result:?}")
+  };
+  assert!(stderr.starts_with("fatal: ambiguous argument"), "{stderr}");
+  assert!(stderr.lines().count() > 1, "{stderr}");
+  assert!(!stderr.ends_with('\n'), "{stderr}");
+}
+
+
+/// Check that annotating diffs concurrently produces the same output as
+/// doing so sequentially.
+#[test]
+fn blame_concurrently() {
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+
+  let files = (0..8).map(|i| format!("file{i}.txt")).collect::<Vec<_>>();
+  for file in &files {
+    repo
+      .write(file, &format!("{file}\n"), WriteMode::Overwrite)
+      .unwrap();
+  }
+  repo.add(&files).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+
+  for file in &files {
+    repo
+      .write(file, &format!("{file} changed\n"), WriteMode::Overwrite)
+      .unwrap();
+  }
+
+  let parser = repo.diff(NO_ARGS).unwrap();
+  assert_eq!(parser.diffs().len(), files.len());
+
+  let blame = |jobs| {
+    let config = BlameConfig {
+      directory: Some(repo.directory.path().to_path_buf()),
+      jobs,
+      ..Default::default()
+    };
+    let mut out = Vec::new();
+    let result = blame_with_config(parser.diffs(), &[], &config, &mut out);
+    result.map(|()| String::from_utf8(out).unwrap())
+  };
+
+  let expected = blame(1).unwrap();
+  for jobs in [2, 3, 8, 16] {
+    assert_eq!(blame(jobs).unwrap(), expected, "{jobs}");
+  }
+
+  // Remove one of the files from the repository, causing its
+  // annotation to fail.
+  repo.remove(["--force", "file5.txt"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+  let result = blame(4);
+  assert!(
+    matches!(&result, Err(Error::Process { stderr, .. }) if stderr.contains("file5.txt")),
+    "{result:?}"
+  );
+}
+
+
+/// Check that annotating diffs in batches produces the same output as
+/// doing so one hunk at a time.
+#[test]
+fn blame_batched() {
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+
+  let content = (1..=12).map(|i| format!("line {i}\n")).collect::<String>();
+  repo.write("a.txt", &content, WriteMode::Overwrite).unwrap();
+  repo.write("b.txt", &content, WriteMode::Overwrite).unwrap();
+  repo.add(["a.txt", "b.txt"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+
+  let changed = content
+    .replace("line 2\n", "line two\n")
+    .replace("line 6\n", "line six\n")
+    .replace("line 11\n", "line eleven\n");
+  repo.write("a.txt", &changed, WriteMode::Overwrite).unwrap();
+  repo.write("b.txt", &changed, WriteMode::Overwrite).unwrap();
+
+  let diff = |file| {
+    repo
+      .git_out(["diff", "--relative", "--no-prefix", "-U0", "--", file])
+      .unwrap()
+  };
+  // Have hunks for `a.txt` appear in two separate runs.
+  let diffs = [diff("a.txt"), diff("b.txt"), diff("a.txt")].concat();
+  let mut parser = Parser::new();
+  let () = parser.parse(diffs.as_slice()).unwrap();
+  assert_eq!(parser.diffs().len(), 9);
+
+  let config = BlameConfig {
+    directory: Some(repo.directory.path().to_path_buf()),
+    ..Default::default()
+  };
+  let mut expected = Vec::new();
+  let () = blame_with_config(parser.diffs(), &[], &config, &mut expected).unwrap();
+  let mut out = Vec::new();
+  let () = blame_batched_with_config(parser.diffs(), &[], &config, &mut out).unwrap();
+
+  // Columns are aligned across all lines annotated by a single `git
+  // blame` invocation, so we ignore differences in padding.
+  let expected = String::from_utf8(expected).unwrap();
+  let out = String::from_utf8(out).unwrap();
+  assert_eq!(
+    out.split_whitespace().collect::<Vec<_>>(),
+    expected.split_whitespace().collect::<Vec<_>>()
+  );
+}
+
+
+/// Check that the annotations of identical hunks are reused.
+#[cfg(unix)]
+#[test]
+fn blame_cached() {
+  use std::fs::read_to_string;
+  use std::fs::set_permissions;
+  use std::fs::Permissions;
+  use std::os::unix::fs::PermissionsExt as _;
+
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+
+  repo
+    .write("main.c", "int a;\nint b;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo.add(["main.c"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+  repo
+    .write("main.c", "int a;\nint c;\n", WriteMode::Overwrite)
+    .unwrap();
+
+  let diff = repo
+    .git_out(["diff", "--relative", "--no-prefix", "-U0"])
+    .unwrap();
+  let diffs = [diff.as_slice(), diff.as_slice()].concat();
+  let mut parser = Parser::new();
+  let () = parser.parse(diffs.as_slice()).unwrap();
+  assert_eq!(parser.diffs().len(), 2);
+
+  // Use a wrapper around `git` recording each of its invocations.
+  let bin = tempdir().unwrap();
+  let log = bin.path().join("invocations");
+  let git = bin.path().join("git");
+  let script = format!(
+    "#!/bin/sh\necho \"$@\" >> '{}'\nexec '{}' \"$@\"\n",
+    log.display(),
+    BlameConfig::default().git_path.display()
+  );
+  let () = File::create(&git)
+    .unwrap()
+    .write_all(script.as_bytes())
+    .unwrap();
+  let () = set_permissions(&git, Permissions::from_mode(0o755)).unwrap();
+
+  let config = BlameConfig {
+    git_path: git,
+    directory: Some(repo.directory.path().to_path_buf()),
+    ..Default::default()
+  };
+  let mut out = Vec::new();
+  let () = blame_cached_with_config(parser.diffs(), &[], &config, &mut out).unwrap();
+  assert_eq!(read_to_string(&log).unwrap().lines().count(), 1);
+
+  let mut expected = Vec::new();
+  let () = blame_with_config(parser.diffs(), &[], &config, &mut expected).unwrap();
+  assert_eq!(read_to_string(&log).unwrap().lines().count(), 3);
+  assert_eq!(
+    String::from_utf8(out).unwrap(),
+    String::from_utf8(expected).unwrap()
+  );
+}
+
+
+/// Check that `git blame` invocations not finishing in time are
+/// killed.
+#[cfg(unix)]
+#[test]
+fn blame_timeout() {
+  use std::fs::set_permissions;
+  use std::fs::Permissions;
+  use std::os::unix::fs::PermissionsExt as _;
+  use std::time::Duration;
+  use std::time::Instant;
+
+  // Use a `git` that hangs indefinitely.
+  let bin = tempdir().unwrap();
+  let git = bin.path().join("git");
+  let () = File::create(&git)
+    .unwrap()
+    .write_all(b"#!/bin/sh\nexec sleep 3600\n")
+    .unwrap();
+  let () = set_permissions(&git, Permissions::from_mode(0o755)).unwrap();
+
+  let diff = "--- main.c\n+++ main.c\n@@ -3,2 +3,2 @@\n-a\n-b\n+c\n+d\n";
+  let mut parser = Parser::new();
+  let () = parser.parse(diff.as_bytes()).unwrap();
+
+  let timeout = Duration::from_millis(500);
+  let config = BlameConfig {
+    git_path: git,
+    directory: Some(bin.path().to_path_buf()),
+    timeout: Some(timeout),
+    ..Default::default()
+  };
+  let start = Instant::now();
+  let result = blame_with_config(parser.diffs(), &[], &config, &mut Vec::new());
+  let elapsed = start.elapsed();
+
+  match result {
+    Err(Error::ProcessTimeout { file, line, count }) => {
+      assert_eq!(file, "main.c");
+      assert_eq!(line, 3);
+      assert_eq!(count, 2);
+    },
+    result => panic!("unexpected result: {result:?}"),
+  }
+  assert!(elapsed >= timeout, "{elapsed:?}");
+  assert!(elapsed < timeout + Duration::from_secs(10), "{elapsed:?}");
+
+  // Invocations finishing in time are not affected.
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+  repo
+    .write("main.c", "int a;\nint b;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo.add(["main.c"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+  repo
+    .write("main.c", "int a;\nint c;\n", WriteMode::Overwrite)
+    .unwrap();
+
+  let parser = repo.diff(NO_ARGS).unwrap();
+  let mut config = BlameConfig {
+    directory: Some(repo.directory.path().to_path_buf()),
+    ..Default::default()
+  };
+  let mut expected = Vec::new();
+  let () = blame_with_config(parser.diffs(), &[], &config, &mut expected).unwrap();
+
+  config.timeout = Some(Duration::from_secs(60));
+  let mut out = Vec::new();
+  let () = blame_with_config(parser.diffs(), &[], &config, &mut out).unwrap();
+  assert_eq!(out, expected);
+}
+
+
+/// Check that `git` processes are run with the configured environment.
+#[cfg(unix)]
+#[test]
+fn blame_with_env_vars() {
+  use std::fs::set_permissions;
+  use std::fs::Permissions;
+  use std::os::unix::fs::PermissionsExt as _;
+
+  // Use a `git` that reports parts of its environment and fails.
+  let bin = tempdir().unwrap();
+  let git = bin.path().join("git");
+  let () = File::create(&git)
+    .unwrap()
+    .write_all(b"#!/bin/sh\necho \"foo=$FOO dir=${CARGO_MANIFEST_DIR:+set}\" >&2\nexit 1\n")
+    .unwrap();
+  let () = set_permissions(&git, Permissions::from_mode(0o755)).unwrap();
+
+  let diff = "--- main.c\n+++ main.c\n@@ -3,2 +3,2 @@\n-a\n-b\n+c\n+d\n";
+  let mut parser = Parser::new();
+  let () = parser.parse(diff.as_bytes()).unwrap();
+
+  let mut config = BlameConfig {
+    git_path: git,
+    directory: Some(bin.path().to_path_buf()),
+    env_vars: [("FOO".into(), "bar".into())].into_iter().collect(),
+    ..Default::default()
+  };
+  let error = blame_with_config(parser.diffs(), &[], &config, &mut Vec::new()).unwrap_err();
+  assert!(error.to_string().contains("foo=bar dir=set"), "{error}");
+
+  config.clear_env = true;
+  let error = blame_with_config(parser.diffs(), &[], &config, &mut Vec::new()).unwrap_err();
+  assert!(
+    error.to_string().trim_end().ends_with("foo=bar dir="),
+    "{error}"
+  );
+}
+
+
+/// Check that annotations are colored by commit age if requested.
+#[cfg(feature = "color")]
+#[test]
+fn blame_colored() {
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+
+  repo
+    .write("main.c", "int main;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo.add(["main.c"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+  repo
+    .write("main.c", "int main();\n", WriteMode::Overwrite)
+    .unwrap();
+  let short = format!("--short={GIT_SHA1_DIGITS}");
+  let sha1 = repo.rev_parse([&short, "HEAD"]).unwrap();
+
+  let out = repo.blamediff(NO_ARGS, ["--color=always"]).unwrap();
+  let expected = format!("--- main.c\n+++ main.c\n\x1b[31m{sha1}\x1b[0m 1) int main;\n");
+  assert_eq!(String::from_utf8(out).unwrap(), expected);
+
+  let out = repo.blamediff(NO_ARGS, ["--color=never"]).unwrap();
+  let out = String::from_utf8(out).unwrap();
+  assert!(!out.contains('\x1b'), "{out}");
+
+  // Output is captured and so stdout is not a terminal.
+  let out = repo.blamediff(NO_ARGS, ["--color=auto"]).unwrap();
+  let out = String::from_utf8(out).unwrap();
+  assert!(!out.contains('\x1b'), "{out}");
+}
+
+
+/// Check that renamed files with content changes are annotated using
+/// their original name.
+#[test]
+fn blame_renamed_file() {
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+
+  let content = (1..=10).map(|i| format!("line {i}\n")).collect::<String>();
+  repo
+    .write("old.txt", &content, WriteMode::Overwrite)
+    .unwrap();
+  repo.add(["old.txt"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+  let short = format!("--short={GIT_SHA1_DIGITS}");
+  let sha1 = repo.rev_parse([&short, "HEAD"]).unwrap();
+
+  repo.git(["mv", "old.txt", "new.txt"]).unwrap();
+  repo
+    .write("new.txt", "line 11\n", WriteMode::Append)
+    .unwrap();
+  repo.add(["new.txt"]).unwrap();
+
+  let parser = repo.diff(["--staged", "-M"]).unwrap();
+  let Hunk { src, dst } = &parser.diffs()[0];
+  assert_eq!(src.blame_file(), "old.txt");
+  assert_eq!(dst.file.as_str(), "new.txt");
+
+  let abbrev = format!("--abbrev={}", GIT_SHA1_DIGITS - 1);
+  let out = repo.blame(["--staged", "-M"], [abbrev]).unwrap();
+  let out = String::from_utf8(out).unwrap();
+  let mut lines = out.lines();
+  assert_eq!(lines.next(), Some("--- old.txt"));
+  assert_eq!(lines.next(), Some("+++ new.txt"));
+  assert_eq!(lines.next(), Some(format!("{sha1}  8) line 8").as_str()));
+}
+
+
+/// Check that we can annotate the destination side of diffs, or both
+/// sides.
+#[test]
+fn blame_target_sides() {
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+
+  repo
+    .write("main.c", "int a;\nint b;\nint c;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo.add(["main.c"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+  let short = format!("--short={GIT_SHA1_DIGITS}");
+  let sha1 = repo.rev_parse([&short, "HEAD"]).unwrap();
+
+  repo
+    .write(
+      "main.c",
+      "int a;\nint x;\nint y;\nint c;\n",
+      WriteMode::Overwrite,
+    )
+    .unwrap();
+
+  let abbrev = format!("--abbrev={}", GIT_SHA1_DIGITS - 1);
+  let out = repo
+    .blamediff(["-U0"], [abbrev.as_str(), "--blame-target=src"])
+    .unwrap();
+  let expected = format!("--- main.c\n+++ main.c\n{sha1} 2) int b;\n");
+  assert_eq!(String::from_utf8(out).unwrap(), expected);
+
+  let out = repo
+    .blamediff(["-U0"], [abbrev.as_str(), "--blame-target=dst"])
+    .unwrap();
+  let expected = "--- main.c\n+++ main.c\n<root> 2) int x;\n<root> 3) int y;\n";
+  assert_eq!(String::from_utf8(out).unwrap(), expected);
+
+  let out = repo
+    .blamediff(["-U0"], [abbrev.as_str(), "--blame-target=both"])
+    .unwrap();
+  let expected =
+    format!("--- main.c\n+++ main.c\n{sha1} 2) int b;\n--\n<root> 2) int x;\n<root> 3) int y;\n");
+  assert_eq!(String::from_utf8(out).unwrap(), expected);
+}
+
+
+/// Check that the all-zeros commit hash is replaced with the configured
+/// label.
+#[test]
+fn blame_root_label() {
+  // A repository with a single commit, without any parents.
+  let repo = GitRepo::new().unwrap();
+  repo
+    .write("main.c", "int a;\nint b;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo.add(["main.c"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+  let short = format!("--short={}", GIT_SHA1_DIGITS - 1);
+  let sha1 = repo.rev_parse([&short, "HEAD"]).unwrap();
+  let zeros = "0".repeat(GIT_SHA1_DIGITS);
+
+  repo
+    .write("main.c", "int a;\nint c;\n", WriteMode::Overwrite)
+    .unwrap();
+
+  let abbrev = format!("--abbrev={}", GIT_SHA1_DIGITS - 1);
+  let args = [abbrev.as_str(), "--blame-target=both"];
+  let out = repo.blamediff(["-U0"], args).unwrap();
+  let expected = format!("--- main.c\n+++ main.c\n^{sha1} 2) int b;\n--\n<root> 2) int c;\n");
+  assert_eq!(String::from_utf8(out).unwrap(), expected);
+
+  let args = [abbrev.as_str(), "--blame-target=both", "--root-label=new"];
+  let out = repo.blamediff(["-U0"], args).unwrap();
+  let expected = format!("--- main.c\n+++ main.c\n^{sha1} 2) int b;\n--\nnew 2) int c;\n");
+  assert_eq!(String::from_utf8(out).unwrap(), expected);
+
+  // An empty label leaves the hash untouched.
+  let args = [abbrev.as_str(), "--blame-target=both", "--root-label="];
+  let out = repo.blamediff(["-U0"], args).unwrap();
+  let expected = format!("--- main.c\n+++ main.c\n^{sha1} 2) int b;\n--\n{zeros} 2) int c;\n");
+  assert_eq!(String::from_utf8(out).unwrap(), expected);
+}
+
+
+/// Check that the output for each diff can be formatted with a
+/// template.
+#[test]
+fn blame_with_template() {
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+
+  repo
+    .write("main.c", "int main;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo.add(["main.c"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+  repo
+    .write("main.c", "int main();\n", WriteMode::Overwrite)
+    .unwrap();
+  let short = format!("--short={GIT_SHA1_DIGITS}");
+  let sha1 = repo.rev_parse([&short, "HEAD"]).unwrap();
+  let abbrev = format!("--abbrev={}", GIT_SHA1_DIGITS - 1);
+
+  let out = repo.blamediff(NO_ARGS, [abbrev.as_str()]).unwrap();
+  let expected = format!("--- main.c\n+++ main.c\n{sha1} 1) int main;\n");
+  assert_eq!(String::from_utf8(out).unwrap(), expected);
+
+  let out = repo
+    .blamediff(NO_ARGS, [abbrev.as_str(), "--template={blame}"])
+    .unwrap();
+  let expected = format!("{sha1} 1) int main;\n");
+  assert_eq!(String::from_utf8(out).unwrap(), expected);
+
+  let out = repo
+    .blamediff(
+      NO_ARGS,
+      [abbrev.as_str(), r"--template=== {src_file} ==\n{blame}"],
+    )
+    .unwrap();
+  let expected = format!("== main.c ==\n{sha1} 1) int main;\n");
+  assert_eq!(String::from_utf8(out).unwrap(), expected);
+
+  let result = repo.blamediff(NO_ARGS, ["--template={function}"]);
+  assert!(result.is_err());
+}
+
+
+/// Check that the function containing a hunk can be included in the
+/// output.
+#[test]
+fn blame_with_func_context() {
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+
+  let content = "int main()\n{\n  a();\n  b();\n  c();\n  d();\n}\n";
+  repo.write("main.c", content, WriteMode::Overwrite).unwrap();
+  repo.add(["main.c"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+  let short = format!("--short={GIT_SHA1_DIGITS}");
+  let sha1 = repo.rev_parse([&short, "HEAD"]).unwrap();
+
+  let content = content.replace("d();", "e();");
+  repo
+    .write("main.c", &content, WriteMode::Overwrite)
+    .unwrap();
+
+  let parser = repo.diff(["-U0"]).unwrap();
+  let Hunk { src, dst } = &parser.diffs()[0];
+  assert_eq!(src.func_context.as_deref(), Some("int main()"));
+  assert_eq!(dst.func_context.as_deref(), Some("int main()"));
+
+  let abbrev = format!("--abbrev={}", GIT_SHA1_DIGITS - 1);
+  let out = repo
+    .blamediff(
+      ["-U0"],
+      [
+        abbrev.as_str(),
+        r"--template=--- {src_file} [{func}]\n{blame}",
+      ],
+    )
+    .unwrap();
+  let expected = format!("--- main.c [int main()]\n{sha1} 6)   d();\n");
+  assert_eq!(String::from_utf8(out).unwrap(), expected);
+}
+
+
+/// Check that we can emit blame information as JSON.
+#[cfg(feature = "json")]
+#[test]
+fn blame_as_json() {
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+
+  repo
+    .write("main.c", "puts(\"hi\");\n", WriteMode::Overwrite)
+    .unwrap();
+  repo
+    .write("util.c", "int util;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo.add(["main.c", "util.c"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+  let sha1 = repo.rev_parse(["HEAD"]).unwrap();
+
+  repo
+    .write("main.c", "puts(\"hello\");\n", WriteMode::Overwrite)
+    .unwrap();
+  repo
+    .write("util.c", "int util();\n", WriteMode::Overwrite)
+    .unwrap();
+
+  let out = repo.blamediff(NO_ARGS, ["--format=json"]).unwrap();
+  let out = String::from_utf8(out).unwrap();
+  let expected = format!(
+    r#"[
+{{"src_file":"main.c","dst_file":"main.c","lines":[{{"commit":"{sha1}","line_no":1,"content":"puts(\"hi\");"}}]}},
+{{"src_file":"util.c","dst_file":"util.c","lines":[{{"commit":"{sha1}","line_no":1,"content":"int util;"}}]}}
+]
+"#
+  );
+  assert_eq!(out, expected);
+
+  let parser = repo.diff(NO_ARGS).unwrap();
+  let config = BlameConfig {
+    directory: Some(repo.directory.path().to_path_buf()),
+    format: OutputFormat::Json,
+    ..Default::default()
+  };
+  let mut out = Vec::new();
+  let () = blame_with_config(parser.diffs(), &[], &config, &mut out).unwrap();
+  assert_eq!(String::from_utf8(out).unwrap(), expected);
+}
+
+
+/// Check that newly added files are emitted with an empty list of lines
+/// when emitting JSON.
+#[cfg(feature = "json")]
+#[test]
+fn blame_as_json_with_new_file() {
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+
+  repo
+    .write("main.c", "int x;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo.add(["main.c"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+  let sha1 = repo.rev_parse(["HEAD"]).unwrap();
+
+  repo
+    .write("main.c", "int y;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo
+    .write("new.c", "int z;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo.git(["add", "--intent-to-add", "new.c"]).unwrap();
+
+  let out = repo.blamediff(NO_ARGS, ["--format=json"]).unwrap();
+  let out = String::from_utf8(out).unwrap();
+  let expected = format!(
+    r#"[
+{{"src_file":"main.c","dst_file":"main.c","lines":[{{"commit":"{sha1}","line_no":1,"content":"int x;"}}]}},
+{{"src_file":"/dev/null","dst_file":"new.c","lines":[]}}
+]
+"#
+  );
+  assert_eq!(out, expected);
+}
+
+
+/// Check that we can emit blame information in porcelain format.
+#[test]
+fn blame_as_porcelain() {
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+
+  repo
+    .write("main.c", "int a;\nint b;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo.add(["main.c"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+  let sha1 = repo.rev_parse(["HEAD"]).unwrap();
+
+  repo
+    .write("main.c", "int a;\nint c;\n", WriteMode::Overwrite)
+    .unwrap();
+
+  let out = repo.blamediff(["-U0"], ["--format=porcelain"]).unwrap();
+  let out = String::from_utf8(out).unwrap();
+  let mut lines = out.lines();
+  assert_eq!(lines.next(), Some("--- main.c"));
+  assert_eq!(lines.next(), Some("+++ main.c"));
+  assert_eq!(lines.next(), Some(format!("{sha1} 2 2 1").as_str()));
+  assert_eq!(lines.next(), Some("author nobody"));
+  assert_eq!(lines.next_back(), Some("\tint b;"));
+}
+
+
+/// Check that we can summarize the commits and authors touching the
+/// lines of a diff.
+#[test]
+fn blame_summary() {
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+
+  repo
+    .write("main.c", "int a;\nint b;\nint c;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo.add(["main.c"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+  let sha1 = repo.rev_parse(["HEAD"]).unwrap();
+
+  repo
+    .write("main.c", "int a;\nint x;\nint c;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo.add(["main.c"]).unwrap();
+  repo
+    .commit(["--author=somebody <somebody@example.com>"])
+    .unwrap();
+  let sha2 = repo.rev_parse(["HEAD"]).unwrap();
+
+  repo
+    .write("main.c", "int d;\nint e;\nint f;\n", WriteMode::Overwrite)
+    .unwrap();
+
+  let parser = repo.diff(["-U0"]).unwrap();
+  let config = BlameConfig {
+    directory: Some(repo.directory.path().to_path_buf()),
+    ..Default::default()
+  };
+  let summary = blame_summary_with_config(parser.diffs(), &[], &config).unwrap();
+  assert_eq!(summary.commits, BTreeSet::from([sha1, sha2]));
+  assert_eq!(
+    summary.authors,
+    BTreeSet::from(["nobody".to_string(), "somebody".to_string()])
+  );
+  assert_eq!(summary.total_lines, 3);
+
+  let out = repo.blamediff(["-U0"], ["--summary-only"]).unwrap();
+  assert_eq!(
+    String::from_utf8(out).unwrap(),
+    "2 commits, 2 authors touched 3 lines\n"
+  );
+
+  let out = repo.blamediff(["-U0"], ["--summary"]).unwrap();
+  let out = String::from_utf8(out).unwrap();
+  assert!(out.starts_with("--- main.c\n+++ main.c\n"), "{out}");
+  assert!(
+    out.ends_with("\n2 commits, 2 authors touched 3 lines\n"),
+    "{out}"
+  );
+}
+
+
+/// Check that we can find the last commit still containing removed
+/// lines.
+#[test]
+fn blame_reverse() {
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+
+  repo
+    .write("main.c", "int a;\nint b;\nint c;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo
+    .write("util.c", "int util;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo.add(["main.c", "util.c"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+
+  repo
+    .write(
+      "main.c",
+      "int a;\nint b;\nint c;\nint d;\n",
+      WriteMode::Overwrite,
+    )
+    .unwrap();
+  repo
+    .write("new.c", "int new;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo.add(["main.c", "new.c"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+  let short = format!("--short={GIT_SHA1_DIGITS}");
+  let sha1 = repo.rev_parse([&short, "HEAD"]).unwrap();
+
+  repo
+    .write("main.c", "int a;\nint c;\nint d;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo
+    .write("util.c", "long util;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo.add(["main.c", "util.c"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+
+  // `new.c` was purely added, so it does not show up at all. Note that
+  // `--contents` can't be used in conjunction with `--reverse` and is
+  // filtered out.
+  let abbrev = format!("--abbrev={}", GIT_SHA1_DIGITS - 1);
+  let out = repo
+    .blamediff(
+      ["-U0", "HEAD~2", "HEAD"],
+      [
+        abbrev.as_str(),
+        "--reverse=HEAD~2..HEAD",
+        "--contents",
+        "main.c",
+      ],
+    )
+    .unwrap();
+  let expected = format!(
+    "--- main.c\n+++ main.c\n{sha1} 2) int b;\n--- util.c\n+++ util.c\n{sha1} 1) int util;\n"
+  );
+  assert_eq!(String::from_utf8(out).unwrap(), expected);
+}
+
+
+/// Check that we can annotate files with names containing spaces or
+/// characters that git quotes.
+#[test]
+fn blame_special_file_names() {
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+
+  let files = ["my component.rs", "q\"x.c", "with spaces/\"quoted\".c"];
+  let () = create_dir(repo.directory.path().join("with spaces")).unwrap();
+  for file in files {
+    repo.write(file, "int a;\n", WriteMode::Overwrite).unwrap();
+  }
+  repo.add(files).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+  let short = format!("--short={GIT_SHA1_DIGITS}");
+  let sha1 = repo.rev_parse([&short, "HEAD"]).unwrap();
+
+  for file in files {
+    repo.write(file, "int b;\n", WriteMode::Overwrite).unwrap();
+  }
+
+  let abbrev = format!("--abbrev={}", GIT_SHA1_DIGITS - 1);
+  let out = repo.blame(["-U0"], [abbrev]).unwrap();
+  let expected = files
+    .iter()
+    .map(|file| format!("--- {file}\n+++ {file}\n{sha1} 1) int a;\n"))
+    .collect::<String>();
+  assert_eq!(String::from_utf8(out).unwrap(), expected);
+}
+
+
+/// Check that we can annotate lines as of a revision other than
+/// `HEAD`.
+#[test]
+fn blame_at_revision() {
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+
+  repo
+    .write("main.c", "int a;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo.add(["main.c"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+  let short = format!("--short={GIT_SHA1_DIGITS}");
+  let sha1 = repo.rev_parse([&short, "HEAD"]).unwrap();
+
+  for content in ["int b;\n", "int c;\n"] {
+    repo.write("main.c", content, WriteMode::Overwrite).unwrap();
+    repo.add(["main.c"]).unwrap();
+    repo.commit(NO_ARGS).unwrap();
+  }
+  let sha3 = repo.rev_parse([&short, "HEAD"]).unwrap();
+
+  let abbrev = format!("--abbrev={}", GIT_SHA1_DIGITS - 1);
+  let out = repo
+    .blamediff(["HEAD~2", "HEAD~1"], [abbrev.as_str(), "--at=HEAD~2"])
+    .unwrap();
+  let expected = format!("--- main.c\n+++ main.c\n{sha1} 1) int a;\n");
+  assert_eq!(String::from_utf8(out).unwrap(), expected);
+
+  let out = repo
+    .blamediff(
+      ["HEAD~2", "HEAD~1"],
+      [abbrev.as_str(), &format!("--at={sha1}")],
+    )
+    .unwrap();
+  assert_eq!(String::from_utf8(out).unwrap(), expected);
+
+  let out = repo
+    .blamediff(
+      ["HEAD~2", "HEAD~1"],
+      [abbrev.as_str(), "--base-commit=HEAD~2"],
+    )
+    .unwrap();
+  assert_eq!(String::from_utf8(out).unwrap(), expected);
+
+  // The last revision provided takes precedence.
+  let out = repo
+    .blamediff(
+      ["HEAD~2", "HEAD~1"],
+      [abbrev.as_str(), "--at=HEAD", "--base-commit=HEAD~2"],
+    )
+    .unwrap();
+  assert_eq!(String::from_utf8(out).unwrap(), expected);
+
+  // By default the lines are annotated as of `HEAD`.
+  let out = repo.blamediff(["HEAD~2", "HEAD~1"], [abbrev]).unwrap();
+  let expected = format!("--- main.c\n+++ main.c\n{sha3} 1) int c;\n");
+  assert_eq!(String::from_utf8(out).unwrap(), expected);
+}
+
+
+/// Check that staged changes are annotated as present in the index.
+#[test]
+fn blame_staged() {
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+
+  repo
+    .write("main.c", "int a;\nint b;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo.add(["main.c"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+
+  repo
+    .write("main.c", "int a;\nint c;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo
+    .write("new.c", "int new;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo.add(["main.c", "new.c"]).unwrap();
+  // The working tree content should not be considered.
+  repo
+    .write("main.c", "int a;\nint d;\n", WriteMode::Overwrite)
+    .unwrap();
+
+  let zeros = "0".repeat(40);
+  let out = repo
+    .blamediff(
+      ["--staged", "-U0"],
+      ["-l", "--staged", "--blame-target=dst", "--root-label="],
+    )
+    .unwrap();
+  let expected = format!("--- main.c\n+++ main.c\n{zeros} 2) int c;\n");
+  assert_eq!(String::from_utf8(out).unwrap(), expected);
+}
+
+
+/// Check that revisions can be ignored when assigning blame.
+#[test]
+fn blame_ignoring_revs() {
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+
+  repo
+    .write("main.c", "int a;\nint b;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo.add(["main.c"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+  let short = format!("--short={GIT_SHA1_DIGITS}");
+  let sha1 = repo.rev_parse([&short, "HEAD"]).unwrap();
+
+  // Reformat the file.
+  repo
+    .write("main.c", "int a;\nint  b;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo.add(["main.c"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+  let reformat = repo.rev_parse([&short, "HEAD"]).unwrap();
+
+  repo
+    .write("main.c", "int a;\nint c;\n", WriteMode::Overwrite)
+    .unwrap();
+
+  let abbrev = format!("--abbrev={}", GIT_SHA1_DIGITS - 1);
+  let out = repo.blamediff(["-U0"], [abbrev.as_str()]).unwrap();
+  let expected = format!("--- main.c\n+++ main.c\n{reformat} 2) int  b;\n");
+  assert_eq!(String::from_utf8(out).unwrap(), expected);
+
+  let ignore = format!("--ignore-rev={reformat}");
+  let out = repo.blamediff(["-U0"], [abbrev.as_str(), &ignore]).unwrap();
+  let expected = format!("--- main.c\n+++ main.c\n{sha1} 2) int  b;\n");
+  assert_eq!(String::from_utf8(out).unwrap(), expected);
+
+  // Unknown revisions are reported before any output is produced.
+  let parser = repo.diff(["-U0"]).unwrap();
+  let config = BlameConfig {
+    directory: Some(repo.directory.path().to_path_buf()),
+    ignore_revs: vec![reformat, "deadbeef".to_string()],
+    ..Default::default()
+  };
+  let mut out = Vec::new();
+  let result = blame_with_config(parser.diffs(), &[], &config, &mut out);
+  let error = result.unwrap_err();
+  assert_eq!(error.to_string(), "unknown revision 'deadbeef'");
+  assert!(out.is_empty());
+}
+
+
+/// Check that hunks merely changing whitespace can be skipped.
+#[test]
+fn blame_ignoring_whitespace_hunks() {
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+
+  let content = "int a;\n\n\n\n\nint b;\n";
+  repo.write("main.c", content, WriteMode::Overwrite).unwrap();
+  repo.add(["main.c"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+  let short = format!("--short={GIT_SHA1_DIGITS}");
+  let sha1 = repo.rev_parse([&short, "HEAD"]).unwrap();
+
+  repo
+    .write(
+      "main.c",
+      "  int a; \n\n\n\n\nint c;\n",
+      WriteMode::Overwrite,
+    )
+    .unwrap();
+
+  let abbrev = format!("--abbrev={}", GIT_SHA1_DIGITS - 1);
+  let out = repo.blamediff(["-U0"], [abbrev.as_str()]).unwrap();
+  let expected =
+    format!("--- main.c\n+++ main.c\n{sha1} 1) int a;\n--- main.c\n+++ main.c\n{sha1} 6) int b;\n");
+  assert_eq!(String::from_utf8(out).unwrap(), expected);
+
+  let args = [abbrev.as_str(), "--ignore-whitespace-hunks"];
+  let out = repo.blamediff(["-U0"], args).unwrap();
+  let expected = format!("--- main.c\n+++ main.c\n{sha1} 6) int b;\n");
+  assert_eq!(String::from_utf8(out).unwrap(), expected);
+}
+
+
+/// Check that we can annotate the combined diff `git show` produces
+/// for a merge commit.
+#[test]
+fn blame_merge_commit() {
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+
+  repo
+    .write("main.c", "int a;\nint b;\nint c;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo.add(["main.c"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+  let short = format!("--short={GIT_SHA1_DIGITS}");
+  let base = repo.rev_parse([&short, "HEAD"]).unwrap();
+
+  repo.git(["checkout", "--quiet", "-b", "side"]).unwrap();
+  repo
+    .write(
+      "main.c",
+      "int a;\nint side;\nint c;\n",
+      WriteMode::Overwrite,
+    )
+    .unwrap();
+  repo.commit(["--all"]).unwrap();
+  repo.git(["checkout", "--quiet", "-"]).unwrap();
+  repo
+    .write(
+      "main.c",
+      "int a;\nint main;\nint c;\n",
+      WriteMode::Overwrite,
+    )
+    .unwrap();
+  repo.commit(["--all"]).unwrap();
+
+  // The merge conflicts, and we resolve it by choosing a different
+  // line altogether, so that the combined diff covers it.
+  let _error = repo.git(["merge", "side"]).unwrap_err();
+  repo
+    .write(
+      "main.c",
+      "int a;\nint merged;\nint c;\n",
+      WriteMode::Overwrite,
+    )
+    .unwrap();
+  repo.add(["main.c"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+  let merge = repo.rev_parse([&short, "HEAD"]).unwrap();
+
+  let show = repo.git_out(["show"]).unwrap();
+  let patches = tempdir().unwrap();
+  let path = patches.path().join("merge.patch");
+  let () = File::create(&path).unwrap().write_all(&show).unwrap();
+
+  let abbrev = format!("--abbrev={}", GIT_SHA1_DIGITS - 1);
+  let output = Command::new(env!("CARGO_BIN_EXE_git-blamediff"))
+    .current_dir(repo.directory.path())
+    .arg(abbrev)
+    .arg(&path)
+    .stdin(Stdio::null())
+    .output()
+    .unwrap();
+  assert!(output.status.success(), "{output:?}");
+
+  let expected = format!(
+    r#"--- main.c
++++ main.c
+{base} 1) int a;
+{merge} 2) int merged;
+{base} 3) int c;
+"#
+  );
+  assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+}
+
+
+/// Check that progress is not reported when stderr is not a terminal,
+/// even if requested.
+#[test]
+fn blame_progress_not_on_terminal() {
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+
+  let files = (0..12).map(|i| format!("file{i}.c")).collect::<Vec<_>>();
+  for file in &files {
+    repo.write(file, "int a;\n", WriteMode::Overwrite).unwrap();
+  }
+  repo.add(&files).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+  for file in &files {
+    repo.write(file, "int b;\n", WriteMode::Overwrite).unwrap();
+  }
+
+  let diff = repo.git_out(["diff", "--no-prefix"]).unwrap();
+  let patches = tempdir().unwrap();
+  let path = patches.path().join("files.patch");
+  let () = File::create(&path).unwrap().write_all(&diff).unwrap();
+
+  let blamediff = |args: &[&str]| {
+    Command::new(env!("CARGO_BIN_EXE_git-blamediff"))
+      .current_dir(repo.directory.path())
+      .args(args)
+      .arg(&path)
+      .stdin(Stdio::null())
+      .output()
+      .unwrap()
+  };
+
+  let expected = blamediff(&[]);
+  assert!(expected.status.success(), "{expected:?}");
+  assert!(expected.stderr.is_empty(), "{expected:?}");
+
+  let output = blamediff(&["--progress"]);
+  assert!(output.status.success(), "{output:?}");
+  assert!(output.stderr.is_empty(), "{output:?}");
+  assert_eq!(output.stdout, expected.stdout);
+}
+
+
+/// Check that we can read diffs from files whose paths are not valid
+/// Unicode.
+#[cfg(unix)]
+#[test]
+fn blame_non_unicode_path() {
+  use std::os::unix::ffi::OsStrExt as _;
+
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+
+  repo
+    .write("main.c", "int a;\nint b;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo.add(["main.c"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+  let short = format!("--short={GIT_SHA1_DIGITS}");
+  let commit = repo.rev_parse([&short, "HEAD"]).unwrap();
+
+  repo
+    .write("main.c", "int a;\nint c;\n", WriteMode::Overwrite)
+    .unwrap();
+
+  let diff = repo.git_out(["diff", "--no-prefix", "-U0"]).unwrap();
+  let patches = tempdir().unwrap();
+  let path = patches.path().join(OsStr::from_bytes(b"p\xff.diff"));
+  let () = std::fs::write(&path, diff).unwrap();
+
+  let abbrev = format!("--abbrev={}", GIT_SHA1_DIGITS - 1);
+  let output = Command::new(env!("CARGO_BIN_EXE_git-blamediff"))
+    .current_dir(repo.directory.path())
+    .arg(&abbrev)
+    .arg(&path)
+    .stdin(Stdio::null())
+    .output()
+    .unwrap();
+  assert!(output.status.success(), "{output:?}");
+
+  let expected = format!("--- main.c\n+++ main.c\n{commit} 2) int b;\n");
+  assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+}
+
+
+/// Check that errors are reported in human readable form.
+#[test]
+fn report_error_readably() {
+  let repo = GitRepo::new().unwrap();
+  let patches = tempdir().unwrap();
+  let path = patches.path().join("garbage.diff");
+  let () = std::fs::write(&path, "--- main.c\ngarbage\n").unwrap();
+
+  let output = Command::new(env!("CARGO_BIN_EXE_git-blamediff"))
+    .current_dir(repo.directory.path())
+    .arg(&path)
+    .stdin(Stdio::null())
+    .output()
+    .unwrap();
+  assert_eq!(output.status.code(), Some(1), "{output:?}");
+  assert!(output.stdout.is_empty(), "{output:?}");
+
+  let stderr = String::from_utf8(output.stderr).unwrap();
+  assert!(
+    stderr.starts_with(r#"encountered unexpected line 2: "garbage""#),
+    "{stderr}"
+  );
+  assert_eq!(stderr.lines().count(), 1, "{stderr}");
+}
+
+
+/// Check that the `--lenient` option causes unexpected lines in the
+/// input to be skipped over.
+#[test]
+fn blame_lenient() {
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+
+  repo
+    .write("main.c", "int a;\nint b;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo.add(["main.c"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+  let short = format!("--short={GIT_SHA1_DIGITS}");
+  let commit = repo.rev_parse([&short, "HEAD"]).unwrap();
+
+  repo
+    .write("main.c", "int a;\nint c;\n", WriteMode::Overwrite)
+    .unwrap();
+
+  let diff = repo.git_out(["diff", "--no-prefix"]).unwrap();
+  let patches = tempdir().unwrap();
+  let path = patches.path().join("review.patch");
+  let mut file = File::create(&path).unwrap();
+  let () = file.write_all(b"--- foo.c\nReviewed-by: nobody\n").unwrap();
+  let () = file.write_all(&diff).unwrap();
+
+  let abbrev = format!("--abbrev={}", GIT_SHA1_DIGITS - 1);
+  let blamediff = |lenient: bool| {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_git-blamediff"));
+    command
+      .current_dir(repo.directory.path())
+      .arg(&abbrev)
+      .arg(&path)
+      .stdin(Stdio::null());
+    if lenient {
+      command.arg("--lenient");
+    }
+    command.output().unwrap()
+  };
+
+  let output = blamediff(false);
+  assert!(!output.status.success(), "{output:?}");
+
+  let output = blamediff(true);
+  assert!(output.status.success(), "{output:?}");
+  let stderr = String::from_utf8(output.stderr).unwrap();
+  assert!(stderr.contains("Reviewed-by: nobody"), "{stderr}");
+
+  let expected = format!(
+    r#"--- main.c
++++ main.c
+{commit} 1) int a;
+{commit} 2) int b;
+"#
+  );
+  assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+}
+
+
+/// Check that with the `--lenient` option the file following a
+/// complete hunk is recognized as such.
+#[test]
+fn blame_lenient_multiple_files() {
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+
+  repo.write("m.c", "a\nb\n", WriteMode::Overwrite).unwrap();
+  repo.write("u.c", "x\n", WriteMode::Overwrite).unwrap();
+  repo.add(["m.c", "u.c"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+  let short = format!("--short={GIT_SHA1_DIGITS}");
+  let commit = repo.rev_parse([&short, "HEAD"]).unwrap();
+
+  repo.write("m.c", "a\nc\n", WriteMode::Overwrite).unwrap();
+  repo.write("u.c", "z\n", WriteMode::Overwrite).unwrap();
+
+  // Without `diff --git` lines separating them, the second file's
+  // header directly follows the complete hunk of the first one.
+  let diff = repo.git_out(["diff", "--no-prefix", "-U0"]).unwrap();
+  let diff = String::from_utf8(diff)
+    .unwrap()
+    .lines()
+    .filter(|line| !line.starts_with("diff ") && !line.starts_with("index "))
+    .map(|line| format!("{line}\n"))
+    .collect::<String>();
+  let patches = tempdir().unwrap();
+  let path = patches.path().join("review.patch");
+  let () = std::fs::write(&path, diff).unwrap();
+
+  let abbrev = format!("--abbrev={}", GIT_SHA1_DIGITS - 1);
+  let output = Command::new(env!("CARGO_BIN_EXE_git-blamediff"))
+    .current_dir(repo.directory.path())
+    .arg(&abbrev)
+    .arg("--lenient")
+    .arg(&path)
+    .stdin(Stdio::null())
+    .output()
+    .unwrap();
+  assert!(output.status.success(), "{output:?}");
+  assert!(output.stderr.is_empty(), "{output:?}");
+
+  let expected = format!(
+    r#"--- m.c
++++ m.c
+{commit} 2) b
+--- u.c
++++ u.c
+{commit} 1) x
+"#
+  );
+  assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+}
+
+/// Check that the hunks of patches that differ between the series
+/// compared by `git range-diff` get annotated.
+#[test]
+fn blame_range_diff() {
+  let lines = |changed: &str| {
+    (1..=10)
+      .map(|i| match i {
+        8 => format!("int {changed};\n"),
+        _ => format!("int l{i};\n"),
+      })
+      .collect::<String>()
+  };
+
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+
+  repo
+    .write("main.c", &lines("l8"), WriteMode::Overwrite)
+    .unwrap();
+  repo
+    .write("util.c", "int x;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo.add(["main.c", "util.c"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+  repo.git(["tag", "base"]).unwrap();
+  let short = format!("--short={GIT_SHA1_DIGITS}");
+  let base = repo.rev_parse([&short, "HEAD"]).unwrap();
+
+  // Both series change `util.c` the same way, but `main.c` differently.
+  for (branch, changed) in [("old", "c"), ("new", "d")] {
+    repo
+      .git(["checkout", "--quiet", "-b", branch, "base"])
+      .unwrap();
+    repo
+      .write("main.c", &lines(changed), WriteMode::Overwrite)
+      .unwrap();
+    repo
+      .write("util.c", "int y;\n", WriteMode::Overwrite)
+      .unwrap();
+    repo.commit(["--all"]).unwrap();
+  }
+
+  let range_diff = repo
+    .git_out(["range-diff", "--no-color", "base..old", "base..new"])
+    .unwrap();
+  let range_diff = String::from_utf8(range_diff).unwrap();
+  let patches = tempdir().unwrap();
+  let path = patches.path().join("range.diff");
+  let () = std::fs::write(&path, &range_diff).unwrap();
+
+  let abbrev = format!("--abbrev={}", GIT_SHA1_DIGITS - 1);
+  let blamediff = |side: &str| {
+    Command::new(env!("CARGO_BIN_EXE_git-blamediff"))
+      .current_dir(repo.directory.path())
+      .arg(&abbrev)
+      .arg("--range-diff")
+      .arg(side)
+      .arg(&path)
+      .stdin(Stdio::null())
+      .output()
+      .unwrap()
+  };
+
+  // Only the hunk of `main.c` differs, and only its lines shown by
+  // `git range-diff` get annotated.
+  let expected = format!(
+    r#"--- main.c
++++ main.c
+{base}  6) int l6;
+{base}  7) int l7;
+{base}  8) int l8;
+{base}  9) int l9;
+{base} 10) int l10;
+"#
+  );
+  let output = blamediff("--range-diff-side=new");
+  assert!(output.status.success(), "{output:?}");
+  assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+
+  let output = blamediff("--range-diff-side=both");
+  assert!(output.status.success(), "{output:?}");
+  assert_eq!(
+    String::from_utf8(output.stdout).unwrap(),
+    expected.repeat(2)
+  );
+
+  // Commits that are not available locally get annotated as of the
+  // configured revision.
+  let (_pair, body) = range_diff.split_once('\n').unwrap();
+  let range_diff = format!("1:  0000000 ! 1:  1111111 change\n{body}");
+  let () = std::fs::write(&path, range_diff).unwrap();
+  repo.git(["checkout", "--quiet", "base"]).unwrap();
+
+  let output = blamediff("--range-diff-side=new");
+  assert!(output.status.success(), "{output:?}");
+  assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+}
+
+
+/// Check that we exit gracefully if our output gets closed early.
+#[test]
+fn blame_into_closed_pipe() {
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+
+  repo
+    .write("main.c", "int a;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo.add(["main.c"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+  repo
+    .write("main.c", "int b;\n", WriteMode::Overwrite)
+    .unwrap();
+
+  let diff = repo.git_out(["diff", "--relative", "--no-prefix"]).unwrap();
+
+  let mut child = Command::new(env!("CARGO_BIN_EXE_git-blamediff"))
+    .current_dir(repo.directory.path())
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()
+    .unwrap();
+
+  // Close the reading end of the program's output before it gets to
+  // write anything, which it only does once it consumed all input.
+  drop(child.stdout.take());
+  let () = child.stdin.take().unwrap().write_all(&diff).unwrap();
+
+  let output = child.wait_with_output().unwrap();
+  assert!(output.status.success(), "{:?}", output.status);
+  assert_eq!(String::from_utf8(output.stderr).unwrap(), "");
+}
+
+
+/// Check that diffs can be read from files provided as arguments.
+#[test]
+fn blame_diff_files() {
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+
+  for file in ["main.c", "util.c"] {
+    repo
+      .write(file, "int a;\nint b;\n", WriteMode::Overwrite)
+      .unwrap();
+  }
+  repo.add(["main.c", "util.c"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+  for file in ["main.c", "util.c"] {
+    repo
+      .write(file, "int a;\nint c;\n", WriteMode::Overwrite)
+      .unwrap();
+  }
+
+  let expected = repo.blamediff(NO_ARGS, ["-l"]).unwrap();
+
+  let patches = tempdir().unwrap();
+  let mut paths = Vec::new();
+  for file in ["main.c", "util.c"] {
+    let diff = repo
+      .git_out(["diff", "--relative", "--no-prefix", "--", file])
+      .unwrap();
+    let path = patches.path().join(format!("{file}.patch"));
+    let () = File::create(&path).unwrap().write_all(&diff).unwrap();
+    let () = paths.push(path);
+  }
+
+  let blamediff = |args: &[&OsStr]| {
+    let output = Command::new(env!("CARGO_BIN_EXE_git-blamediff"))
+      .current_dir(repo.directory.path())
+      .args(args)
+      .stdin(Stdio::null())
+      .output()
+      .unwrap();
+    assert!(output.status.success(), "{output:?}");
+    output.stdout
+  };
+
+  let out = blamediff(&[OsStr::new("-l"), paths[0].as_os_str(), paths[1].as_os_str()]);
+  assert_eq!(out, expected);
+
+  let out = blamediff(&[
+    OsStr::new("-l"),
+    OsStr::new("--"),
+    paths[0].as_os_str(),
+    paths[1].as_os_str(),
+  ]);
+  assert_eq!(out, expected);
+}
+
+
+/// Check that we can annotate a diff with CRLF line endings.
+#[test]
+fn blame_crlf_diff() {
+  let repo = GitRepo::new().unwrap();
+  repo.commit(["--allow-empty"]).unwrap();
+
+  repo
+    .write("main.c", "int a;\nint b;\n", WriteMode::Overwrite)
+    .unwrap();
+  repo.add(["main.c"]).unwrap();
+  repo.commit(NO_ARGS).unwrap();
+  let short = format!("--short={GIT_SHA1_DIGITS}");
+  let sha1 = repo.rev_parse([&short, "HEAD"]).unwrap();
+
+  repo
+    .write("main.c", "int a;\nint c;\n", WriteMode::Overwrite)
+    .unwrap();
+
+  let diff = repo
+    .git_out(["diff", "--relative", "--no-prefix", "-U0"])
+    .unwrap();
+  let diff = String::from_utf8(diff).unwrap().replace('\n', "\r\n");
+
+  let mut parser = Parser::new();
+  let () = parser.parse(diff.as_bytes()).unwrap();
+  let config = BlameConfig {
+    directory: Some(repo.directory.path().to_path_buf()),
+    ..Default::default()
+  };
+  let abbrev = format!("--abbrev={}", GIT_SHA1_DIGITS - 1);
+
+  let mut out = Vec::new();
+  let () = blame_with_config(parser.diffs(), &[abbrev.into()], &config, &mut out).unwrap();
+  let expected = format!("--- main.c\n+++ main.c\n{sha1} 2) int b;\n");
+  assert_eq!(String::from_utf8(out).unwrap(), expected);
+}